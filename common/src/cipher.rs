@@ -0,0 +1,202 @@
+//! The AEADs jump data table entries are encrypted with. Each backend is behind a cargo feature,
+//! so builds for targets without AES-NI can leave it out:
+//!
+//! - `aesni`: AES-256-GCM using AES-NI. It's only used when the CPU supports it.
+//! - `aes-soft`: AES-256-GCM in pure Rust.
+//! - `chacha`: ChaCha20-Poly1305 in pure Rust.
+//!
+//! Both AES backends produce the same ciphertext, so a table encrypted with one can be decrypted
+//! with the other.
+
+use std::marker::PhantomData;
+
+use aead::generic_array::GenericArray;
+use aead::{Aead, NewAead, Payload};
+
+use crate::RekkEncKey;
+
+/// Identifies a backend in the container header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CipherKind {
+    AesNi = 1,
+    AesSoft = 2,
+    ChaCha20 = 3,
+}
+
+impl CipherKind {
+    pub const ALL: [CipherKind; 3] = [CipherKind::AesNi, CipherKind::AesSoft, CipherKind::ChaCha20];
+
+    pub fn from_id(id: u8) -> Option<CipherKind> {
+        CipherKind::ALL.iter().copied().find(|x| *x as u8 == id)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CipherKind::AesNi => "aesni",
+            CipherKind::AesSoft => "aes-soft",
+            CipherKind::ChaCha20 => "chacha20",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CipherKind> {
+        CipherKind::ALL.iter().copied().find(|x| x.name() == name)
+    }
+
+    /// Returns the fastest backend this build and CPU can use.
+    pub fn best() -> Option<CipherKind> {
+        CipherKind::ALL.iter().copied().find(|x| x.is_available())
+    }
+
+    /// Returns true if this build has the backend, and the CPU supports it.
+    pub fn is_available(&self) -> bool {
+        match self {
+            CipherKind::AesNi => cfg!(feature = "aesni") && has_aesni(),
+            CipherKind::AesSoft => cfg!(feature = "aes-soft"),
+            CipherKind::ChaCha20 => cfg!(feature = "chacha"),
+        }
+    }
+
+    /// Returns a cipher that can decrypt what this backend encrypted, if this build has one. AES
+    /// tables are decrypted with whichever AES backend is available.
+    pub fn decryptor(&self) -> Option<Box<dyn Cipher>> {
+        match self {
+            CipherKind::AesNi | CipherKind::AesSoft => CipherKind::AesNi
+                .encryptor()
+                .or_else(|| CipherKind::AesSoft.encryptor()),
+            CipherKind::ChaCha20 => self.encryptor(),
+        }
+    }
+
+    /// Returns this backend, if this build has it and the CPU supports it.
+    pub fn encryptor(&self) -> Option<Box<dyn Cipher>> {
+        if !self.is_available() {
+            return None;
+        }
+
+        match self {
+            #[cfg(feature = "aesni")]
+            CipherKind::AesNi => Some(Box::new(Backend::<AesNiGcm>::new(*self))),
+            #[cfg(feature = "aes-soft")]
+            CipherKind::AesSoft => Some(Box::new(Backend::<AesSoftGcm>::new(*self))),
+            #[cfg(feature = "chacha")]
+            CipherKind::ChaCha20 => Some(Box::new(
+                Backend::<chacha20poly1305::ChaCha20Poly1305>::new(*self),
+            )),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
+
+/// An AEAD with 256 bit keys and 96 bit nonces.
+pub trait Cipher {
+    fn kind(&self) -> CipherKind;
+
+    fn encrypt(&self, key: &RekkEncKey, nonce: &[u8; 12], aad: &[u8], msg: &[u8]) -> Vec<u8>;
+
+    /// Returns `None` if the data fails authentication.
+    fn decrypt(
+        &self,
+        key: &RekkEncKey,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        data: &[u8],
+    ) -> Option<Vec<u8>>;
+}
+
+#[cfg(feature = "aesni")]
+type AesNiGcm = aes_gcm::AesGcm<aesni::Aes256, aead::consts::U12>;
+
+#[cfg(feature = "aes-soft")]
+type AesSoftGcm = aes_gcm::AesGcm<aes_soft::Aes256, aead::consts::U12>;
+
+/// Implements `Cipher` for a RustCrypto AEAD.
+#[allow(dead_code)]
+struct Backend<A> {
+    kind: CipherKind,
+    aead: PhantomData<A>,
+}
+
+#[allow(dead_code)]
+impl<A> Backend<A> {
+    fn new(kind: CipherKind) -> Self {
+        Backend {
+            kind,
+            aead: PhantomData,
+        }
+    }
+}
+
+impl<A: NewAead + Aead> Cipher for Backend<A> {
+    fn kind(&self) -> CipherKind {
+        self.kind
+    }
+
+    fn encrypt(&self, key: &RekkEncKey, nonce: &[u8; 12], aad: &[u8], msg: &[u8]) -> Vec<u8> {
+        let aead = A::new(GenericArray::from_slice(&key.0));
+        aead.encrypt(GenericArray::from_slice(nonce), Payload { msg, aad })
+            .unwrap()
+    }
+
+    fn decrypt(
+        &self,
+        key: &RekkEncKey,
+        nonce: &[u8; 12],
+        aad: &[u8],
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        let aead = A::new(GenericArray::from_slice(&key.0));
+        aead.decrypt(GenericArray::from_slice(nonce), Payload { msg: data, aad })
+            .ok()
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn has_aesni() -> bool {
+    is_x86_feature_detected!("aes") && is_x86_feature_detected!("sse2")
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn has_aesni() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cipher::CipherKind;
+    use crate::RekkEncKey;
+
+    #[test]
+    fn backends() {
+        let key = RekkEncKey([4; 32]);
+        let nonce = [2; 12];
+
+        for kind in CipherKind::ALL.iter() {
+            let cipher = match kind.encryptor() {
+                Some(cipher) => cipher,
+                None => continue,
+            };
+
+            let data = cipher.encrypt(&key, &nonce, b"aad", b"jump data");
+            let decryptor = kind.decryptor().unwrap();
+
+            assert_eq!(cipher.kind(), *kind);
+            assert_eq!(
+                decryptor.decrypt(&key, &nonce, b"aad", &data).unwrap(),
+                b"jump data"
+            );
+            assert!(decryptor.decrypt(&key, &nonce, b"other", &data).is_none());
+        }
+
+        // Both AES backends produce the same ciphertext.
+        if let (Some(aesni), Some(soft)) = (
+            CipherKind::AesNi.encryptor(),
+            CipherKind::AesSoft.encryptor(),
+        ) {
+            assert_eq!(
+                aesni.encrypt(&key, &nonce, b"", b"jump data"),
+                soft.encrypt(&key, &nonce, b"", b"jump data")
+            );
+        }
+    }
+}
@@ -0,0 +1,383 @@
+//! The container `jdt.bin` and `nanomite.bin` are stored in, so the runtime can tell they belong
+//! together, and that it knows how to run them.
+//!
+//! The layout is a fixed 72 byte header followed by the payload, with every field little endian:
+//!
+//! | offset | size | field                                                 |
+//! |--------|------|-------------------------------------------------------|
+//! | 0      | 4    | magic, `REKK`                                         |
+//! | 4      | 2    | format version                                        |
+//! | 6      | 1    | what the payload is                                   |
+//! | 7      | 1    | architecture of the protected binary                  |
+//! | 8      | 4    | features used by the infection                        |
+//! | 12     | 8    | infection id, shared by the two files of an infection |
+//! | 20     | 32   | SHA-256 of the original binary                        |
+//! | 52     | 8    | payload length                                        |
+//! | 60     | 1    | cipher backend the jump data table was encrypted with |
+//! | 61     | 7    | reserved, zero                                        |
+//! | 68     | 4    | CRC-32 of the rest of the header, and the payload     |
+
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::cipher::CipherKind;
+
+pub const MAGIC: [u8; 4] = *b"REKK";
+
+/// The version of the format written by this build, and the only one the runtime reads. The first
+/// infector wrote its files without a header, `infector upgrade` converts them to this version.
+pub const VERSION: u16 = 1;
+
+pub const HEADER_SIZE: usize = 72;
+
+/// Where the CRC is stored. Everything before it is covered by it.
+const CRC_OFFSET: usize = 68;
+
+/// What a container holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Content {
+    /// The compressed nanomite'd binary, encrypted as described in `payload`.
+    Binary = 1,
+
+    /// The compressed jump data table.
+    JumpDataTable = 2,
+}
+
+/// The architecture of the protected binary.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arch {
+    X86 = 1,
+    X86_64 = 2,
+}
+
+impl Arch {
+    pub fn from_bitness(bitness: u32) -> Option<Arch> {
+        match bitness {
+            32 => Some(Arch::X86),
+            64 => Some(Arch::X86_64),
+            _ => None,
+        }
+    }
+}
+
+/// The features an infection used. A runtime refuses to run an infection that uses features it
+/// doesn't know about.
+#[derive(Debug, Clone, Copy)]
+pub enum Feature {
+    /// Calls and returns are replaced with nanomites, so the runtime has to emulate the stack.
+    CallReturn = 0x1,
+
+    /// The jump data table has passthrough entries for the program's own breakpoints.
+    Passthrough = 0x2,
+
+    /// The jump data table has decoy entries.
+    Decoys = 0x4,
+}
+
+impl Feature {
+    /// Every feature this build knows about.
+    pub const ALL: u32 =
+        Feature::CallReturn as u32 | Feature::Passthrough as u32 | Feature::Decoys as u32;
+
+    pub fn get_flag(&self, features: u32) -> bool {
+        features & *self as u32 != 0
+    }
+}
+
+/// Describes the payload of a container, and the infection it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub content: Content,
+    pub arch: Arch,
+
+    /// The `Feature`s used, as a bit set.
+    pub features: u32,
+
+    /// Identifies the infection. The binary and jump data table of one infection share it.
+    pub infection: u64,
+
+    /// The SHA-256 of the binary before it was infected.
+    pub binary_hash: [u8; 32],
+
+    /// The backend the jump data table and the binary were encrypted with.
+    pub cipher: CipherKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ContainerError {
+    /// The data doesn't start with the magic.
+    NotAContainer,
+
+    /// The container was written in a format version this build can't read.
+    UnsupportedVersion(u16),
+
+    /// The container holds something other than what was expected.
+    WrongContent {
+        expected: Content,
+    },
+
+    UnknownArch(u8),
+
+    UnknownCipher(u8),
+
+    /// The container uses features this build doesn't know about, as a bit set.
+    UnsupportedFeatures(u32),
+
+    /// The header or payload was cut short.
+    Truncated,
+
+    /// The CRC doesn't match, so the header or payload is corrupt.
+    Corrupt,
+
+    /// The binary and jump data table came from different infections.
+    Mismatch,
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerError::NotAContainer => write!(f, "not a rekk container"),
+            ContainerError::UnsupportedVersion(version) => write!(
+                f,
+                "container format version {} isn't supported, expected version {}",
+                version, VERSION
+            ),
+            ContainerError::WrongContent { expected } => {
+                write!(f, "expected a container holding the {}", expected)
+            }
+            ContainerError::UnknownArch(arch) => write!(f, "unknown architecture {}", arch),
+            ContainerError::UnknownCipher(cipher) => write!(f, "unknown cipher {}", cipher),
+            ContainerError::UnsupportedFeatures(features) => write!(
+                f,
+                "the infection uses features this build doesn't support (0x{:X})",
+                features
+            ),
+            ContainerError::Truncated => write!(f, "the container is truncated"),
+            ContainerError::Corrupt => write!(f, "the container is corrupt, its CRC doesn't match"),
+            ContainerError::Mismatch => write!(
+                f,
+                "the binary and jump data table come from different infections"
+            ),
+        }
+    }
+}
+
+impl Error for ContainerError {}
+
+impl fmt::Display for Content {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Content::Binary => write!(f, "nanomite'd binary"),
+            Content::JumpDataTable => write!(f, "jump data table"),
+        }
+    }
+}
+
+/// Returns the SHA-256 of a binary, for `Header::binary_hash`.
+pub fn hash_binary(binary: &[u8]) -> [u8; 32] {
+    Sha256::digest(binary).into()
+}
+
+/// Prepends the header to the payload.
+pub fn wrap(header: &Header, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_SIZE + payload.len());
+
+    data.extend_from_slice(&MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.push(header.content as u8);
+    data.push(header.arch as u8);
+    data.extend_from_slice(&header.features.to_le_bytes());
+    data.extend_from_slice(&header.infection.to_le_bytes());
+    data.extend_from_slice(&header.binary_hash);
+    data.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    data.push(header.cipher as u8);
+    data.resize(CRC_OFFSET, 0);
+
+    let crc = crc(&data, payload);
+    data.extend_from_slice(&crc.to_le_bytes());
+    data.extend_from_slice(payload);
+
+    data
+}
+
+/// Checks the container holds `content` this build can handle, and isn't corrupt. Returns the
+/// header and the payload.
+pub fn unwrap(data: &[u8], content: Content) -> Result<(Header, &[u8]), ContainerError> {
+    if !data.starts_with(&MAGIC) {
+        return Err(ContainerError::NotAContainer);
+    }
+
+    if data.len() < HEADER_SIZE {
+        return Err(ContainerError::Truncated);
+    }
+
+    let version = u16::from_le_bytes(data[4..6].try_into().unwrap());
+
+    if version != VERSION {
+        return Err(ContainerError::UnsupportedVersion(version));
+    }
+
+    let length = u64::from_le_bytes(data[52..60].try_into().unwrap());
+    let payload = (length as usize)
+        .checked_add(HEADER_SIZE)
+        .and_then(|end| data.get(HEADER_SIZE..end))
+        .ok_or(ContainerError::Truncated)?;
+
+    let stored_crc = u32::from_le_bytes(data[CRC_OFFSET..HEADER_SIZE].try_into().unwrap());
+
+    if crc(&data[..CRC_OFFSET], payload) != stored_crc {
+        return Err(ContainerError::Corrupt);
+    }
+
+    let stored_content = match data[6] {
+        1 => Content::Binary,
+        2 => Content::JumpDataTable,
+        _ => return Err(ContainerError::WrongContent { expected: content }),
+    };
+
+    if stored_content != content {
+        return Err(ContainerError::WrongContent { expected: content });
+    }
+
+    let arch = match data[7] {
+        1 => Arch::X86,
+        2 => Arch::X86_64,
+        arch => return Err(ContainerError::UnknownArch(arch)),
+    };
+
+    let features = u32::from_le_bytes(data[8..12].try_into().unwrap());
+
+    if features & !Feature::ALL != 0 {
+        return Err(ContainerError::UnsupportedFeatures(
+            features & !Feature::ALL,
+        ));
+    }
+
+    let cipher = CipherKind::from_id(data[60]).ok_or(ContainerError::UnknownCipher(data[60]))?;
+
+    let header = Header {
+        content,
+        arch,
+        features,
+        infection: u64::from_le_bytes(data[12..20].try_into().unwrap()),
+        binary_hash: data[20..52].try_into().unwrap(),
+        cipher,
+    };
+
+    Ok((header, payload))
+}
+
+/// Checks a binary and jump data table come from the same infection.
+pub fn check_pair(binary: &Header, jdt: &Header) -> Result<(), ContainerError> {
+    if binary.infection != jdt.infection
+        || binary.binary_hash != jdt.binary_hash
+        || binary.arch != jdt.arch
+        || binary.features != jdt.features
+        || binary.cipher != jdt.cipher
+    {
+        return Err(ContainerError::Mismatch);
+    }
+
+    Ok(())
+}
+
+/// Unwraps the binary and jump data table of an infection, and checks they belong together.
+/// Returns the header of the binary, and both payloads.
+pub fn unwrap_pair<'a>(
+    binary: &'a [u8],
+    jdt: &'a [u8],
+) -> Result<(Header, &'a [u8], &'a [u8]), ContainerError> {
+    let (binary_header, binary) = unwrap(binary, Content::Binary)?;
+    let (jdt_header, jdt) = unwrap(jdt, Content::JumpDataTable)?;
+    check_pair(&binary_header, &jdt_header)?;
+
+    Ok((binary_header, binary, jdt))
+}
+
+fn crc(header: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cipher::CipherKind;
+    use crate::container::{
+        check_pair, hash_binary, unwrap, wrap, Arch, ContainerError, Content, Feature, Header,
+        VERSION,
+    };
+
+    fn header(content: Content) -> Header {
+        Header {
+            content,
+            arch: Arch::X86_64,
+            features: Feature::Decoys as u32,
+            infection: 0x1234,
+            binary_hash: hash_binary(b"\x7fELF"),
+            cipher: CipherKind::ChaCha20,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = wrap(&header(Content::JumpDataTable), b"payload");
+        let (unwrapped, payload) = unwrap(&data, Content::JumpDataTable).unwrap();
+
+        assert_eq!(unwrapped, header(Content::JumpDataTable));
+        assert_eq!(payload, b"payload");
+        assert_eq!(
+            unwrap(&data, Content::Binary).unwrap_err(),
+            ContainerError::WrongContent {
+                expected: Content::Binary
+            }
+        );
+        assert!(check_pair(&header(Content::Binary), &unwrapped).is_ok());
+
+        let mut other = header(Content::Binary);
+        other.infection += 1;
+        assert_eq!(
+            check_pair(&other, &unwrapped),
+            Err(ContainerError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_containers() {
+        let data = wrap(&header(Content::Binary), b"payload");
+
+        assert_eq!(
+            unwrap(b"\x00\x00payload", Content::Binary).unwrap_err(),
+            ContainerError::NotAContainer
+        );
+        assert_eq!(
+            unwrap(&data[..data.len() - 1], Content::Binary).unwrap_err(),
+            ContainerError::Truncated
+        );
+
+        let mut corrupt = data.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            unwrap(&corrupt, Content::Binary).unwrap_err(),
+            ContainerError::Corrupt
+        );
+
+        let mut newer = data.clone();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            unwrap(&newer, Content::Binary).unwrap_err(),
+            ContainerError::UnsupportedVersion(VERSION + 1)
+        );
+
+        let mut features = header(Content::Binary);
+        features.features |= 0x8000_0000;
+        assert_eq!(
+            unwrap(&wrap(&features, b""), Content::Binary).unwrap_err(),
+            ContainerError::UnsupportedFeatures(0x8000_0000)
+        );
+    }
+}
@@ -1,11 +1,31 @@
-use block_modes::BlockMode;
+use std::error::Error;
+
 use serde::{Deserialize, Serialize};
 
+use crate::cipher::Cipher;
 use crate::flags::Flags;
-use crate::{Aes256Cbc, EncryptedJumpData, JumpType, RekkEncKey};
+use crate::jump_data_table::JDTError;
+use crate::thread_context::ThreadContext;
+use crate::{EncryptedJumpData, JumpType, RekkEncKey};
+
+impl EncryptedJumpData {
+    /// Decrypts and authenticates the entry with the given JDT key.
+    pub fn decrypt(
+        &self,
+        cipher: &dyn Cipher,
+        key: &RekkEncKey,
+        addr: u64,
+    ) -> Result<JumpData, JDTError> {
+        let data = cipher
+            .decrypt(key, &self.nonce, &addr.to_le_bytes(), &self.data)
+            .ok_or(JDTError::Tampered(addr))?;
+
+        bincode::deserialize(&data).map_err(|_| JDTError::Malformed(addr))
+    }
+}
 
 /// Contains the necessary information to emulate the jump.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct JumpData {
     /// The type of jump.
     jump_type: JumpType,
@@ -26,16 +46,105 @@ impl JumpData {
         }
     }
 
-    pub fn encrypt(&self, key: RekkEncKey, iv: &[u8; 16]) -> EncryptedJumpData {
+    pub fn jump_type(&self) -> JumpType {
+        self.jump_type
+    }
+
+    pub fn j_true(&self) -> isize {
+        self.j_true
+    }
+
+    pub fn j_false(&self) -> usize {
+        self.j_false
+    }
+
+    /// Encrypts the jump data for the entry with the given JDT key. Every entry needs its own
+    /// nonce.
+    pub fn encrypt(
+        &self,
+        cipher: &dyn Cipher,
+        key: &RekkEncKey,
+        nonce: [u8; 12],
+        addr: u64,
+    ) -> EncryptedJumpData {
         // serialize the object.
         let cereal = bincode::serialize(self).unwrap();
 
-        // encrypt it.
-        let aes = Aes256Cbc::new_var(key.0.as_ref(), iv).unwrap();
+        // encrypt it, binding it to its key in the table.
+        let enc = cipher.encrypt(key, &nonce, &addr.to_le_bytes(), &cereal);
 
-        let enc = aes.encrypt_vec(cereal.as_slice());
+        EncryptedJumpData { nonce, data: enc }
+    }
 
-        EncryptedJumpData { key, data: enc }
+    /// Emulates the jump on the thread that hit the nanomite, updating its instruction pointer,
+    /// and its stack for calls and returns.
+    pub fn emulate(&self, context: &mut dyn ThreadContext) -> Result<(), Box<dyn Error>> {
+        let ip = context.ip();
+
+        match self.jump_type {
+            JumpType::Passthrough => {
+                return Err("passthrough entries are delivered to the program, not emulated".into())
+            }
+            JumpType::Call => {
+                context.push(ip.wrapping_add(self.j_false as u64))?;
+                context.set_ip(ip.wrapping_add(self.j_true as u64));
+            }
+            JumpType::Return => {
+                let return_address = context.pop()?;
+                context.set_sp(context.sp().wrapping_add(self.j_true as u64));
+                context.set_ip(return_address);
+            }
+            _ => {
+                let offset = match self.jump_type.counter_size() {
+                    Some(size) => self.get_counter_ip_offset(context, size),
+                    None => self.get_ip_offset(context.flags()),
+                };
+
+                context.set_ip(ip.wrapping_add(offset as u64));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the offset for `loop`, `loope`, `loopne` and `jcxz`, decrementing the counter
+    /// register for loops.
+    fn get_counter_ip_offset(&self, context: &mut dyn ThreadContext, size: u32) -> isize {
+        let mask = u64::MAX >> (64 - size);
+        let counter = context.cx() & mask;
+
+        let jump = match self.jump_type {
+            JumpType::JumpCxZero | JumpType::JumpEcxZero | JumpType::JumpRcxZero => counter == 0,
+            _ => {
+                let counter = counter.wrapping_sub(1) & mask;
+
+                // Writing CX leaves the rest of RCX alone, but writing ECX zeroes the upper half.
+                if size == 16 {
+                    context.set_cx((context.cx() & !mask) | counter);
+                } else {
+                    context.set_cx(counter);
+                }
+
+                let zero_flag = Flags::ZeroFlag.get_flag(context.flags());
+
+                counter != 0
+                    && match self.jump_type {
+                        JumpType::LoopEqual16 | JumpType::LoopEqual32 | JumpType::LoopEqual64 => {
+                            zero_flag
+                        }
+                        JumpType::LoopNotEqual16
+                        | JumpType::LoopNotEqual32
+                        | JumpType::LoopNotEqual64 => !zero_flag,
+                        _ => true,
+                    }
+            }
+        };
+
+        if jump {
+            self.j_true
+        } else {
+            self.j_false as isize
+        }
     }
 
     pub fn get_ip_offset(&self, eflags: u64) -> isize {
@@ -45,6 +154,29 @@ impl JumpData {
             JumpType::None => {
                 panic!("unknown err")
             }
+            JumpType::Return => {
+                panic!("ret has no fixed offset")
+            }
+            JumpType::Passthrough => {
+                panic!("passthrough entries aren't jumps")
+            }
+            JumpType::Loop16
+            | JumpType::Loop32
+            | JumpType::Loop64
+            | JumpType::LoopEqual16
+            | JumpType::LoopEqual32
+            | JumpType::LoopEqual64
+            | JumpType::LoopNotEqual16
+            | JumpType::LoopNotEqual32
+            | JumpType::LoopNotEqual64
+            | JumpType::JumpCxZero
+            | JumpType::JumpEcxZero
+            | JumpType::JumpRcxZero => {
+                panic!("the counter register is needed to emulate loop and jcxz")
+            }
+            JumpType::Jump | JumpType::Call => {
+                return self.j_true;
+            }
             JumpType::JumpOverflow => {
                 flag_to_check = (Flags::OverflowFlag, true);
             }
@@ -132,3 +264,244 @@ impl JumpData {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::error::Error;
+
+    use crate::flags::Flags;
+    use crate::jump_data::JumpData;
+    use crate::thread_context::ThreadContext;
+    use crate::JumpType;
+
+    const IP: u64 = 0x1000;
+    const J_TRUE: isize = -0x20;
+    const J_FALSE: usize = 2;
+
+    #[derive(Default)]
+    struct MockContext {
+        ip: u64,
+        flags: u64,
+        sp: u64,
+        cx: u64,
+        memory: HashMap<u64, u8>,
+    }
+
+    impl ThreadContext for MockContext {
+        fn ip(&self) -> u64 {
+            self.ip
+        }
+
+        fn set_ip(&mut self, ip: u64) {
+            self.ip = ip;
+        }
+
+        fn flags(&self) -> u64 {
+            self.flags
+        }
+
+        fn sp(&self) -> u64 {
+            self.sp
+        }
+
+        fn set_sp(&mut self, sp: u64) {
+            self.sp = sp;
+        }
+
+        fn cx(&self) -> u64 {
+            self.cx
+        }
+
+        fn set_cx(&mut self, cx: u64) {
+            self.cx = cx;
+        }
+
+        fn pointer_size(&self) -> u64 {
+            8
+        }
+
+        fn read_memory(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Box<dyn Error>> {
+            for (i, b) in buf.iter_mut().enumerate() {
+                *b = self.memory[&(addr + i as u64)];
+            }
+
+            Ok(())
+        }
+
+        fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<(), Box<dyn Error>> {
+            for (i, b) in data.iter().enumerate() {
+                self.memory.insert(addr + i as u64, *b);
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Emulates a jump at `IP`, returning the new counter and whether the jump was taken.
+    fn emulate(jump_type: JumpType, cx: u64, zero_flag: bool) -> (u64, bool) {
+        let mut context = MockContext {
+            ip: IP,
+            cx,
+            flags: if zero_flag { Flags::ZeroFlag as u64 } else { 0 },
+            ..Default::default()
+        };
+
+        JumpData::new(jump_type, J_TRUE, J_FALSE)
+            .emulate(&mut context)
+            .unwrap();
+
+        let taken = context.ip == (IP as i64 + J_TRUE as i64) as u64;
+        assert!(taken || context.ip == IP + J_FALSE as u64);
+
+        (context.cx, taken)
+    }
+
+    #[test]
+    fn loop16() {
+        assert_eq!(
+            emulate(JumpType::Loop16, 0xAAAA_0005, false),
+            (0xAAAA_0004, true)
+        );
+        assert_eq!(
+            emulate(JumpType::Loop16, 0xAAAA_0001, false),
+            (0xAAAA_0000, false)
+        );
+        assert_eq!(
+            emulate(JumpType::Loop16, 0xAAAA_0000, false),
+            (0xAAAA_FFFF, true)
+        );
+    }
+
+    #[test]
+    fn loop32() {
+        assert_eq!(
+            emulate(JumpType::Loop32, 0xAAAA_0000_0005, false),
+            (4, true)
+        );
+        assert_eq!(
+            emulate(JumpType::Loop32, 0xAAAA_0000_0001, false),
+            (0, false)
+        );
+        assert_eq!(
+            emulate(JumpType::Loop32, 0xAAAA_0000_0000, false),
+            (0xFFFF_FFFF, true)
+        );
+    }
+
+    #[test]
+    fn loop64() {
+        assert_eq!(
+            emulate(JumpType::Loop64, 0x1_0000_0000, false),
+            (0xFFFF_FFFF, true)
+        );
+        assert_eq!(emulate(JumpType::Loop64, 1, false), (0, false));
+        assert_eq!(emulate(JumpType::Loop64, 0, false), (u64::MAX, true));
+    }
+
+    #[test]
+    fn loop_equal() {
+        assert_eq!(
+            emulate(JumpType::LoopEqual16, 0xAAAA_0005, true),
+            (0xAAAA_0004, true)
+        );
+        assert_eq!(
+            emulate(JumpType::LoopEqual16, 0xAAAA_0005, false),
+            (0xAAAA_0004, false)
+        );
+        assert_eq!(
+            emulate(JumpType::LoopEqual16, 0xAAAA_0001, true),
+            (0xAAAA_0000, false)
+        );
+        assert_eq!(
+            emulate(JumpType::LoopEqual32, 0xAAAA_0000_0005, true),
+            (4, true)
+        );
+        assert_eq!(
+            emulate(JumpType::LoopEqual32, 0xAAAA_0000_0005, false),
+            (4, false)
+        );
+        assert_eq!(
+            emulate(JumpType::LoopEqual32, 0xAAAA_0000_0001, true),
+            (0, false)
+        );
+        assert_eq!(emulate(JumpType::LoopEqual64, 5, true), (4, true));
+        assert_eq!(emulate(JumpType::LoopEqual64, 5, false), (4, false));
+        assert_eq!(emulate(JumpType::LoopEqual64, 1, true), (0, false));
+    }
+
+    #[test]
+    fn loop_not_equal() {
+        assert_eq!(
+            emulate(JumpType::LoopNotEqual16, 0xAAAA_0005, false),
+            (0xAAAA_0004, true)
+        );
+        assert_eq!(
+            emulate(JumpType::LoopNotEqual16, 0xAAAA_0005, true),
+            (0xAAAA_0004, false)
+        );
+        assert_eq!(
+            emulate(JumpType::LoopNotEqual16, 0xAAAA_0001, false),
+            (0xAAAA_0000, false)
+        );
+        assert_eq!(
+            emulate(JumpType::LoopNotEqual32, 0xAAAA_0000_0005, false),
+            (4, true)
+        );
+        assert_eq!(
+            emulate(JumpType::LoopNotEqual32, 0xAAAA_0000_0005, true),
+            (4, false)
+        );
+        assert_eq!(
+            emulate(JumpType::LoopNotEqual32, 0xAAAA_0000_0001, false),
+            (0, false)
+        );
+        assert_eq!(emulate(JumpType::LoopNotEqual64, 5, false), (4, true));
+        assert_eq!(emulate(JumpType::LoopNotEqual64, 5, true), (4, false));
+        assert_eq!(emulate(JumpType::LoopNotEqual64, 1, false), (0, false));
+    }
+
+    #[test]
+    fn jump_cx_zero() {
+        assert_eq!(
+            emulate(JumpType::JumpCxZero, 0x1_0000, false),
+            (0x1_0000, true)
+        );
+        assert_eq!(
+            emulate(JumpType::JumpCxZero, 0x1_0001, false),
+            (0x1_0001, false)
+        );
+        assert_eq!(
+            emulate(JumpType::JumpEcxZero, 0x1_0000_0000, false),
+            (0x1_0000_0000, true)
+        );
+        assert_eq!(
+            emulate(JumpType::JumpEcxZero, 0x1_0000, false),
+            (0x1_0000, false)
+        );
+        assert_eq!(emulate(JumpType::JumpRcxZero, 0, false), (0, true));
+        assert_eq!(
+            emulate(JumpType::JumpRcxZero, 0x1_0000_0000, false),
+            (0x1_0000_0000, false)
+        );
+    }
+
+    #[test]
+    fn call_and_return() {
+        let mut context = MockContext {
+            ip: IP,
+            sp: 0x8000,
+            ..Default::default()
+        };
+
+        JumpData::new(JumpType::Call, 0x100, 5)
+            .emulate(&mut context)
+            .unwrap();
+        assert_eq!((context.ip, context.sp), (IP + 0x100, 0x8000 - 8));
+
+        JumpData::new(JumpType::Return, 0x10, 3)
+            .emulate(&mut context)
+            .unwrap();
+        assert_eq!((context.ip, context.sp), (IP + 5, 0x8000 + 0x10));
+    }
+}
@@ -1,49 +1,359 @@
 use error::Error;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fmt::Formatter;
+use std::ops::Range;
 use std::{error, fmt};
 
-use block_modes::BlockMode;
 use serde::{Deserialize, Serialize};
 
+use crate::cipher::Cipher;
 use crate::jump_data::JumpData;
-use crate::{Aes256Cbc, EncryptedJumpData};
+use crate::keys::{self, MasterKey};
+use crate::thread_context::ThreadContext;
+use crate::EncryptedJumpData;
+
+/// How far the code an entry key is bound to reaches on either side of its nanomite.
+pub const WINDOW_RADIUS: u64 = 32;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JumpDataTable {
-    pub table: HashMap<u64, EncryptedJumpData>,
-    pub iv: [u8; 16],
+    pub table: BTreeMap<u64, EncryptedJumpData>,
+    /// The half of the master key stored in the table. The other half is the nanomite'd binary.
+    pub salt: [u8; 32],
+
+    pub layout: ImageLayout,
+
+    /// The infected sections of the image. Entry keys are bound to the code around their
+    /// nanomite, which never reaches past the section it's in.
+    pub regions: Vec<CodeRegion>,
+}
+
+/// Where the nanomite'd image expects to be loaded. The runtime uses this to translate the address
+/// of a nanomite into its key in the table.
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone)]
+pub struct ImageLayout {
+    /// The address the keys are relative to. This is the `p_vaddr` of the executable `PT_LOAD`
+    /// segment for ELF images, and the image base for PE images.
+    pub base: u64,
+
+    /// The link-time address of the entry point.
+    pub entry: u64,
+
+    /// Whether the image is 32 or 64 bit.
+    pub bitness: u32,
+}
+
+impl ImageLayout {
+    /// Returns how far the image was moved from its link-time addresses, given the address its
+    /// entry point was actually loaded at.
+    pub fn load_bias(&self, loaded_entry: u64) -> u64 {
+        loaded_entry.wrapping_sub(self.entry)
+    }
+
+    /// Returns the key of the nanomite at `addr`.
+    pub fn key(&self, addr: u64, load_bias: u64) -> u64 {
+        addr.wrapping_sub(load_bias).wrapping_sub(self.base)
+    }
+
+    /// Returns the address of the nanomite with the given key. This is the inverse of `key`.
+    pub fn address(&self, key: u64, load_bias: u64) -> u64 {
+        key.wrapping_add(self.base).wrapping_add(load_bias)
+    }
 }
 
-#[derive(Debug)]
-pub struct JDTError;
+/// An infected section of the nanomite'd image.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CodeRegion {
+    /// The JDT key of the first byte of the section.
+    pub start: u64,
+    pub size: u64,
+
+    /// Where the section is stored in the nanomite'd binary.
+    pub file_offset: u64,
+
+    /// The JDT keys of the bytes the loader patches when the image isn't loaded at its preferred
+    /// base, sorted. They're zeroed before the code around a nanomite is hashed. Only PE images
+    /// have any.
+    pub relocations: Vec<Range<u64>>,
+}
+
+impl CodeRegion {
+    pub fn contains(&self, key: u64) -> bool {
+        key.wrapping_sub(self.start) < self.size
+    }
+
+    /// Returns the JDT keys of the code the nanomite at `key` is bound to. It's `WINDOW_RADIUS`
+    /// bytes on either side of the nanomite, cut off at the ends of the section.
+    pub fn window(&self, key: u64) -> Range<u64> {
+        let start = key.saturating_sub(WINDOW_RADIUS).max(self.start);
+        let end = key
+            .saturating_add(WINDOW_RADIUS)
+            .min(self.start + self.size);
+
+        start..end
+    }
+
+    /// Returns the code the nanomite at `key` is bound to, given the content of the section.
+    pub fn window_in<'a>(&self, code: &'a [u8], key: u64) -> Option<&'a [u8]> {
+        let window = self.window(key);
+        code.get((window.start - self.start) as usize..(window.end - self.start) as usize)
+    }
+
+    /// Returns the hash of `code`, the code at JDT key `start` of the section, ignoring the bytes
+    /// the loader relocates.
+    pub fn code_hash(&self, start: u64, mut code: Vec<u8>) -> [u8; 32] {
+        let end = start + code.len() as u64;
+        let first = self.relocations.partition_point(|x| x.end <= start);
+
+        for relocation in self.relocations[first..]
+            .iter()
+            .take_while(|x| x.start < end)
+        {
+            let from = relocation.start.max(start) - start;
+            let to = relocation.end.min(end) - start;
+            code[from as usize..to as usize].fill(0);
+        }
+
+        keys::code_hash(&code)
+    }
+
+    /// Returns the hash of the code the nanomite at `key` is bound to, given the content of the
+    /// section.
+    pub fn window_hash(&self, code: &[u8], key: u64) -> Option<[u8; 32]> {
+        let window = self.window_in(code, key)?;
+        Some(self.code_hash(self.window(key).start, window.to_vec()))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JDTError {
+    /// There's no entry for the address. The `int3` there is the program's own.
+    NotFound,
+
+    /// The entry at the given JDT key failed authentication. Either the table was modified, or
+    /// the nanomite'd binary its keys are derived from was.
+    Tampered(u64),
+
+    /// The entry at the given JDT key decrypted, but doesn't hold valid jump data.
+    Malformed(u64),
+}
 
 impl fmt::Display for JDTError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "unknown err")
+        match self {
+            JDTError::NotFound => write!(f, "no jump data table entry for the address"),
+            JDTError::Tampered(addr) => write!(
+                f,
+                "the jump data table entry at offset 0x{:X} failed authentication, the protected \
+                 program has been tampered with",
+                addr
+            ),
+            JDTError::Malformed(addr) => write!(
+                f,
+                "the jump data table entry at offset 0x{:X} is malformed",
+                addr
+            ),
+        }
     }
 }
 
 impl Error for JDTError {}
 
 impl JumpDataTable {
-    fn new() -> JumpDataTable {
-        JumpDataTable {
-            table: HashMap::new(),
-            iv: [0; 16],
+    /// Returns the master key of the table, given the nanomite'd binary it belongs to.
+    pub fn master_key(&self, binary: &[u8]) -> MasterKey {
+        MasterKey::new(&self.salt, binary)
+    }
+
+    /// Returns the infected section the given JDT key is in.
+    pub fn region(&self, key: u64) -> Option<&CodeRegion> {
+        self.regions.iter().find(|x| x.contains(key))
+    }
+
+    /// Returns the JDT keys of the code the entry at `key` is bound to, or `None` if there's no
+    /// entry for it.
+    pub fn window(&self, key: u64) -> Option<Range<u64>> {
+        if !self.table.contains_key(&key) {
+            return None;
         }
+
+        self.region(key).map(|x| x.window(key))
     }
 
-    pub fn get_jump_data(&self, addr: u64) -> Result<JumpData, Box<dyn Error>> {
+    /// Reads the code the entry at `key` is bound to from the thread that hit its nanomite, and
+    /// returns its hash. Returns `None` if there's no entry for the key.
+    pub fn read_code_hash(
+        &self,
+        key: u64,
+        load_bias: u64,
+        context: &mut dyn ThreadContext,
+    ) -> Result<Option<[u8; 32]>, Box<dyn Error>> {
+        let region = match self.region(key) {
+            Some(region) if self.table.contains_key(&key) => region,
+            _ => return Ok(None),
+        };
+
+        let window = region.window(key);
+        let mut code = vec![0; (window.end - window.start) as usize];
+        context.read_memory(self.layout.address(window.start, load_bias), &mut code)?;
+
+        Ok(Some(region.code_hash(window.start, code)))
+    }
+
+    /// Decrypts the entry at `addr`, given the hash of the code around its nanomite.
+    pub fn get_jump_data(
+        &self,
+        addr: u64,
+        code_hash: &[u8; 32],
+        master: &MasterKey,
+        cipher: &dyn Cipher,
+    ) -> Result<JumpData, JDTError> {
         // Look up the EncryptedJumpData from the hashmap.
-        // Then, decrypt the data, deserialize it, and give it to the user.
-        let enc_data = self.table.get(&addr).ok_or(JDTError)?;
+        // Then, derive its key, decrypt and authenticate the data, deserialize it, and give it to
+        // the user.
+        let enc_data = self.table.get(&addr).ok_or(JDTError::NotFound)?;
+        let key = master.entry_key(addr, code_hash);
+
+        enc_data.decrypt(cipher, &key, addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::cipher::{Cipher, CipherKind};
+    use crate::jump_data::JumpData;
+    use crate::jump_data_table::{CodeRegion, ImageLayout, JDTError, JumpDataTable};
+    use crate::JumpType;
+
+    const BINARY: &[u8] = b"nanomite'd binary";
+    const CODE: &[u8] = &[0x90; 0x30];
+
+    fn cipher() -> Box<dyn Cipher> {
+        CipherKind::best().unwrap().encryptor().unwrap()
+    }
+
+    fn table() -> JumpDataTable {
+        let mut jdt = JumpDataTable {
+            table: BTreeMap::new(),
+            salt: [7; 32],
+            layout: ImageLayout::default(),
+            regions: vec![CodeRegion {
+                start: 0x8,
+                size: CODE.len() as u64,
+                file_offset: 0x100,
+                relocations: Vec::new(),
+            }],
+        };
+        let master = jdt.master_key(BINARY);
+        let cipher = cipher();
+
+        for (i, addr) in [0x10u64, 0x20].iter().enumerate() {
+            let jump_data = JumpData::new(JumpType::JumpEqual, 0x40, 2 + i);
+            let code_hash = jdt.regions[0].window_hash(CODE, *addr).unwrap();
+            let enc = jump_data.encrypt(
+                cipher.as_ref(),
+                &master.entry_key(*addr, &code_hash),
+                [i as u8; 12],
+                *addr,
+            );
+            jdt.table.insert(*addr, enc);
+        }
+
+        jdt
+    }
+
+    #[test]
+    fn windows() {
+        let jdt = table();
+
+        // Cut off at the start and the end of the region.
+        assert_eq!(jdt.window(0x10), Some(0x8..0x30));
+        assert_eq!(jdt.window(0x20), Some(0x8..0x38));
+        assert_eq!(jdt.regions[0].window(0x30), 0x10..0x38);
+        assert_eq!(jdt.window(0x30), None);
+
+        let region = jdt.region(0x20).unwrap();
+        assert_eq!(region.window_in(CODE, 0x20).unwrap().len(), 0x30);
+        assert_eq!(region.window_in(&CODE[..0x10], 0x20), None);
+        assert!(jdt.region(0x38).is_none());
+    }
+
+    #[test]
+    fn authenticates_entries() {
+        let jdt = table();
+        let master = jdt.master_key(BINARY);
+        let cipher = cipher();
+        let hash = |key| jdt.regions[0].window_hash(CODE, key).unwrap();
+
+        assert_eq!(
+            jdt.get_jump_data(0x10, &hash(0x10), &master, cipher.as_ref())
+                .unwrap()
+                .j_false(),
+            2
+        );
+        assert_eq!(
+            jdt.get_jump_data(0x20, &hash(0x20), &master, cipher.as_ref())
+                .unwrap()
+                .j_false(),
+            3
+        );
+        assert_eq!(
+            jdt.get_jump_data(0x30, &hash(0x30), &master, cipher.as_ref())
+                .unwrap_err(),
+            JDTError::NotFound
+        );
+
+        // A different binary derives different keys.
+        let other = jdt.master_key(b"patched binary");
+        assert_eq!(
+            jdt.get_jump_data(0x10, &hash(0x10), &other, cipher.as_ref())
+                .unwrap_err(),
+            JDTError::Tampered(0x10)
+        );
+
+        // So does patched code around the nanomite.
+        let mut patched = CODE.to_vec();
+        patched[0x20] = 0xCC;
+        let patched_hash = jdt.regions[0].window_hash(&patched, 0x10).unwrap();
+        assert_eq!(
+            jdt.get_jump_data(0x10, &patched_hash, &master, cipher.as_ref())
+                .unwrap_err(),
+            JDTError::Tampered(0x10)
+        );
+
+        // Entries are bound to their key, even though the ciphertext is untouched.
+        let mut moved = table();
+        let entry = moved.table[&0x10].clone();
+        moved.table.insert(0x20, entry);
+        assert_eq!(
+            moved
+                .get_jump_data(0x20, &hash(0x20), &master, cipher.as_ref())
+                .unwrap_err(),
+            JDTError::Tampered(0x20)
+        );
+    }
 
-        let aes = Aes256Cbc::new_var(enc_data.key.0.as_ref(), self.iv.as_ref()).unwrap();
-        let data = aes.decrypt_vec(enc_data.data.as_ref())?;
+    #[test]
+    fn ignores_relocations() {
+        let mut region = table().regions[0].clone();
+        region.relocations = vec![0x4..0xC, 0x18..0x1C, 0x34..0x3C];
+        let hash = region.window_hash(CODE, 0x10).unwrap();
 
-        let jump_data: JumpData = bincode::deserialize(data.as_ref())?;
+        // Relocated bytes, even ones that only partly overlap the window or the region.
+        let mut relocated = CODE.to_vec();
+        relocated[0x0..0x4].copy_from_slice(&[1, 2, 3, 4]);
+        relocated[0x10..0x14].copy_from_slice(&[5, 6, 7, 8]);
+        relocated[0x2C..0x30].copy_from_slice(&[9, 10, 11, 12]);
+        assert_eq!(region.window_hash(&relocated, 0x10), Some(hash));
+        assert_eq!(
+            region.window_hash(&relocated, 0x30),
+            region.window_hash(CODE, 0x30)
+        );
 
-        Ok(jump_data)
+        let mut patched = CODE.to_vec();
+        patched[0x14] = 0xCC;
+        assert_ne!(region.window_hash(&patched, 0x10), Some(hash));
     }
 }
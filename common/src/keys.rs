@@ -0,0 +1,117 @@
+//! Derives the key of each jump data table entry, so none of them are stored in the table, and the
+//! key of the nanomite'd binary.
+//!
+//! The master key is split between the two files of an infection: it's extracted from the salt in
+//! the jump data table, and the SHA-256 of the nanomite'd binary. Each entry key is then expanded
+//! from the master key and the entry's JDT key, so every nanomite has its own. Neither file is
+//! enough to decrypt the table on its own, but both are packed into the protected executable, so
+//! whoever has it has both halves, and can derive every key just like the runtime does. What the
+//! split does guarantee is that patching the nanomite'd binary breaks every entry.
+//!
+//! Each entry key is also bound to the code around its nanomite, which the runtime hashes in the
+//! protected process when the nanomite is hit. Code patched in memory, after the runtime has
+//! checked the binary, still breaks the nanomites around it.
+
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+use crate::container::Header;
+use crate::RekkEncKey;
+
+/// Separates entry keys from anything else that might be derived from the master key later.
+const ENTRY_INFO: &[u8] = b"rekk jdt entry";
+
+/// Separates the payload key from the entry keys.
+const PAYLOAD_INFO: &[u8] = b"rekk payload";
+
+/// The secret every entry key of an infection is derived from.
+#[derive(Clone)]
+pub struct MasterKey {
+    hkdf: Hkdf<Sha256>,
+}
+
+impl MasterKey {
+    /// Combines the salt of a jump data table with the nanomite'd binary it belongs to.
+    pub fn new(salt: &[u8; 32], binary: &[u8]) -> MasterKey {
+        let binary_hash = Sha256::digest(binary);
+
+        MasterKey {
+            hkdf: Hkdf::new(Some(salt), &binary_hash),
+        }
+    }
+
+    /// Returns the key of the entry with the given JDT key, given the `code_hash` of the code
+    /// around its nanomite.
+    pub fn entry_key(&self, key: u64, code_hash: &[u8; 32]) -> RekkEncKey {
+        let mut info = ENTRY_INFO.to_vec();
+        info.extend_from_slice(&key.to_le_bytes());
+        info.extend_from_slice(code_hash);
+
+        let mut entry_key = [0u8; 32];
+        // 32 bytes is well within what HKDF-SHA256 can expand to.
+        self.hkdf.expand(&info, &mut entry_key).unwrap();
+
+        RekkEncKey(entry_key)
+    }
+}
+
+/// Returns the hash of the code around a nanomite, as found by `CodeRegion::window`. Use
+/// `CodeRegion::code_hash` instead, which leaves out the bytes the loader relocates.
+pub fn code_hash(code: &[u8]) -> [u8; 32] {
+    Sha256::digest(code).into()
+}
+
+/// Returns the key the nanomite'd binary is encrypted with. It's derived from the salt of the jump
+/// data table, and the infection id and binary hash in the header, which are all stored in
+/// plaintext in the protected executable. It only keeps the binary from being recovered with
+/// `strings` or by decompressing it, not from anyone who reads those fields.
+pub fn payload_key(salt: &[u8; 32], header: &Header) -> RekkEncKey {
+    let mut ikm = header.infection.to_le_bytes().to_vec();
+    ikm.extend_from_slice(&header.binary_hash);
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(salt), &ikm)
+        .expand(PAYLOAD_INFO, &mut key)
+        .unwrap();
+
+    RekkEncKey(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keys::{code_hash, MasterKey};
+
+    #[test]
+    fn entry_keys() {
+        let master = MasterKey::new(&[1; 32], b"nanomite'd binary");
+        let code = code_hash(b"\x74\x05\xcc\x12");
+
+        assert_eq!(
+            master.entry_key(0x10, &code).0,
+            master.entry_key(0x10, &code).0
+        );
+        assert_ne!(
+            master.entry_key(0x10, &code).0,
+            master.entry_key(0x11, &code).0
+        );
+
+        // Both halves of the master key matter.
+        let other_salt = MasterKey::new(&[2; 32], b"nanomite'd binary");
+        let other_binary = MasterKey::new(&[1; 32], b"nanomite'd binary!");
+        assert_ne!(
+            master.entry_key(0x10, &code).0,
+            other_salt.entry_key(0x10, &code).0
+        );
+        assert_ne!(
+            master.entry_key(0x10, &code).0,
+            other_binary.entry_key(0x10, &code).0
+        );
+
+        // So does the code around the nanomite.
+        let patched = code_hash(b"\x74\x05\xcc\x13");
+        assert_ne!(
+            master.entry_key(0x10, &code).0,
+            master.entry_key(0x10, &patched).0
+        );
+    }
+}
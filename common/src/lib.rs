@@ -1,28 +1,34 @@
-use aesni::Aes256;
-use block_modes::block_padding::Pkcs7;
-use block_modes::Cbc;
 use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
 
-pub type Aes256Cbc = Cbc<Aes256, Pkcs7>;
-
+pub mod cipher;
+pub mod container;
 pub mod flags;
 pub mod jump_data;
 pub mod jump_data_table;
+pub mod keys;
+pub mod packed;
+pub mod payload;
+pub mod profile;
+pub mod thread_context;
 
 /// A 32 byte encryption key.
-#[derive(Serialize, Deserialize, Debug)]
 pub struct RekkEncKey(pub [u8; 32]);
 
-/// An encrypted jump data struct.
-#[derive(Serialize, Deserialize, Debug)]
+/// An encrypted jump data struct. Its key isn't stored, it's derived with `keys::MasterKey`.
+///
+/// Entries are encrypted with AES-256-GCM, with the JDT key of the entry as associated data, so an
+/// entry that's modified, or moved to another key, fails to decrypt.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EncryptedJumpData {
-    key: RekkEncKey,
+    nonce: [u8; 12],
+
+    /// The ciphertext, followed by the tag.
     data: Vec<u8>,
 }
 
 /// The type of jump to be emulated.
-#[derive(Serialize, Deserialize, FromPrimitive, Debug)]
+#[derive(Serialize, Deserialize, FromPrimitive, Debug, Clone, Copy, PartialEq)]
 pub enum JumpType {
     /// The instruction doesn't have a condition code
     None = 0,
@@ -74,4 +80,75 @@ pub enum JumpType {
 
     /// Greater (signed) (`ZF=0 and SF=OF`)
     JumpGreater = 16,
+
+    /// Unconditional near `jmp`.
+    Jump = 17,
+
+    /// Near `call`. The return address is pushed on to the stack.
+    Call = 18,
+
+    /// Near `ret`. `j_true` holds the number of extra bytes `ret imm16` releases from the stack.
+    Return = 19,
+
+    /// `loop` with a 16 bit counter (`CX-=1; CX!=0`)
+    Loop16 = 20,
+
+    /// `loop` with a 32 bit counter (`ECX-=1; ECX!=0`)
+    Loop32 = 21,
+
+    /// `loop` with a 64 bit counter (`RCX-=1; RCX!=0`)
+    Loop64 = 22,
+
+    /// `loope` with a 16 bit counter (`CX-=1; CX!=0 and ZF=1`)
+    LoopEqual16 = 23,
+
+    /// `loope` with a 32 bit counter (`ECX-=1; ECX!=0 and ZF=1`)
+    LoopEqual32 = 24,
+
+    /// `loope` with a 64 bit counter (`RCX-=1; RCX!=0 and ZF=1`)
+    LoopEqual64 = 25,
+
+    /// `loopne` with a 16 bit counter (`CX-=1; CX!=0 and ZF=0`)
+    LoopNotEqual16 = 26,
+
+    /// `loopne` with a 32 bit counter (`ECX-=1; ECX!=0 and ZF=0`)
+    LoopNotEqual32 = 27,
+
+    /// `loopne` with a 64 bit counter (`RCX-=1; RCX!=0 and ZF=0`)
+    LoopNotEqual64 = 28,
+
+    /// `jcxz` (`CX=0`)
+    JumpCxZero = 29,
+
+    /// `jecxz` (`ECX=0`)
+    JumpEcxZero = 30,
+
+    /// `jrcxz` (`RCX=0`)
+    JumpRcxZero = 31,
+
+    /// A genuine `int3` of the program, rather than a nanomite. The runtime delivers the
+    /// breakpoint to the program instead of emulating a jump, just like it does for `int3`s that
+    /// aren't in the table.
+    Passthrough = 32,
+}
+
+impl JumpType {
+    /// Returns the size of the counter register, in bits, for jumps that use `CX`, `ECX` or `RCX`.
+    pub fn counter_size(&self) -> Option<u32> {
+        match self {
+            JumpType::Loop16
+            | JumpType::LoopEqual16
+            | JumpType::LoopNotEqual16
+            | JumpType::JumpCxZero => Some(16),
+            JumpType::Loop32
+            | JumpType::LoopEqual32
+            | JumpType::LoopNotEqual32
+            | JumpType::JumpEcxZero => Some(32),
+            JumpType::Loop64
+            | JumpType::LoopEqual64
+            | JumpType::LoopNotEqual64
+            | JumpType::JumpRcxZero => Some(64),
+            _ => None,
+        }
+    }
 }
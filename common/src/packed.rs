@@ -0,0 +1,97 @@
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+
+/// Magic placed at the very end of a packed runtime.
+pub const TRAILER_MAGIC: [u8; 8] = *b"REKKPACK";
+
+/// Size of the trailer: the binary length, the jdt length, and the magic.
+const TRAILER_SIZE: usize = 8 + 8 + TRAILER_MAGIC.len();
+
+#[derive(Debug)]
+pub struct PackError;
+
+impl fmt::Display for PackError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "no packed binary or jdt found")
+    }
+}
+
+impl Error for PackError {}
+
+/// Appends the compressed binary and JDT to a copy of the runtime stub.
+///
+/// The layout is `[stub][binary][jdt][binary len][jdt len][magic]`, with both lengths stored as
+/// little endian `u64`s. Neither ELF nor PE loaders care about trailing data, so the stub still
+/// runs as normal.
+pub fn pack(stub: &[u8], binary: &[u8], jdt: &[u8]) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(stub.len() + binary.len() + jdt.len() + TRAILER_SIZE);
+
+    packed.extend_from_slice(stub);
+    packed.extend_from_slice(binary);
+    packed.extend_from_slice(jdt);
+    packed.extend_from_slice(&(binary.len() as u64).to_le_bytes());
+    packed.extend_from_slice(&(jdt.len() as u64).to_le_bytes());
+    packed.extend_from_slice(&TRAILER_MAGIC);
+
+    packed
+}
+
+/// Locates the compressed binary and JDT in a packed runtime image, returning them in that order.
+pub fn unpack(image: &[u8]) -> Result<(&[u8], &[u8]), PackError> {
+    if image.len() < TRAILER_SIZE || !image.ends_with(&TRAILER_MAGIC) {
+        return Err(PackError);
+    }
+
+    let trailer = &image[image.len() - TRAILER_SIZE..];
+    let binary_len = u64::from_le_bytes(trailer[0..8].try_into().unwrap()) as usize;
+    let jdt_len = u64::from_le_bytes(trailer[8..16].try_into().unwrap()) as usize;
+
+    let jdt_end = image.len() - TRAILER_SIZE;
+    let jdt_start = jdt_end.checked_sub(jdt_len).ok_or(PackError)?;
+    let binary_start = jdt_start.checked_sub(binary_len).ok_or(PackError)?;
+
+    Ok((&image[binary_start..jdt_start], &image[jdt_start..jdt_end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packed::{pack, unpack, TRAILER_SIZE};
+
+    #[test]
+    fn round_trip() {
+        let packed = pack(b"stub", b"binary", b"jdt");
+        assert!(packed.starts_with(b"stub"));
+        assert_eq!(unpack(&packed).unwrap(), (&b"binary"[..], &b"jdt"[..]));
+
+        // Empty parts are still found.
+        let packed = pack(b"", b"", b"");
+        assert_eq!(packed.len(), TRAILER_SIZE);
+        assert_eq!(unpack(&packed).unwrap(), (&b""[..], &b""[..]));
+    }
+
+    #[test]
+    fn rejects_bad_trailers() {
+        let packed = pack(b"stub", b"binary", b"jdt");
+
+        // A bare stub, or one cut short anywhere in the trailer.
+        assert!(unpack(b"stub").is_err());
+        assert!(unpack(&packed[..packed.len() - 1]).is_err());
+        assert!(unpack(&packed[packed.len() - TRAILER_SIZE + 1..]).is_err());
+
+        let mut magic = packed.clone();
+        *magic.last_mut().unwrap() ^= 1;
+        assert!(unpack(&magic).is_err());
+
+        // Lengths that run past the start of the image.
+        let trailer = packed.len() - TRAILER_SIZE;
+        let mut long_jdt = packed.clone();
+        long_jdt[trailer + 8..trailer + 16].copy_from_slice(&100u64.to_le_bytes());
+        assert!(unpack(&long_jdt).is_err());
+
+        let mut long_binary = packed.clone();
+        long_binary[trailer..trailer + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(unpack(&long_binary).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! Encrypts the compressed nanomite'd binary, so it can't be recovered from `nanomite.bin`, or the
+//! packed runtime, by just decompressing it.
+//!
+//! The payload is a 12 byte nonce, followed by the ciphertext and tag. The key is derived with
+//! `keys::payload_key`, and the infection id and hash of the original binary are the associated
+//! data, so a payload only decrypts with the jump data table of its own infection.
+
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+
+use crate::cipher::Cipher;
+use crate::container::Header;
+use crate::keys::payload_key;
+
+pub const NONCE_SIZE: usize = 12;
+
+#[derive(Debug, PartialEq)]
+pub enum PayloadError {
+    /// The payload is too short to hold a nonce and tag.
+    Truncated,
+
+    /// The payload failed authentication, it was modified or belongs to another infection.
+    Tampered,
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::Truncated => write!(f, "the nanomite'd binary is truncated"),
+            PayloadError::Tampered => write!(
+                f,
+                "the nanomite'd binary failed authentication, it has been tampered with"
+            ),
+        }
+    }
+}
+
+impl Error for PayloadError {}
+
+/// Encrypts the compressed binary, with the salt of the infection's jump data table.
+pub fn encrypt(
+    cipher: &dyn Cipher,
+    salt: &[u8; 32],
+    header: &Header,
+    compressed: &[u8],
+    nonce: [u8; NONCE_SIZE],
+) -> Vec<u8> {
+    let key = payload_key(salt, header);
+    let mut payload = nonce.to_vec();
+
+    payload.extend(cipher.encrypt(&key, &nonce, &associated_data(header), compressed));
+    payload
+}
+
+/// Authenticates and decrypts the payload, returning the compressed binary. Nothing is returned
+/// unless the whole payload is authentic.
+pub fn decrypt(
+    cipher: &dyn Cipher,
+    salt: &[u8; 32],
+    header: &Header,
+    payload: &[u8],
+) -> Result<Vec<u8>, PayloadError> {
+    if payload.len() < NONCE_SIZE {
+        return Err(PayloadError::Truncated);
+    }
+
+    let (nonce, data) = payload.split_at(NONCE_SIZE);
+    let key = payload_key(salt, header);
+
+    cipher
+        .decrypt(
+            &key,
+            nonce.try_into().unwrap(),
+            &associated_data(header),
+            data,
+        )
+        .ok_or(PayloadError::Tampered)
+}
+
+fn associated_data(header: &Header) -> Vec<u8> {
+    let mut data = header.infection.to_le_bytes().to_vec();
+    data.extend_from_slice(&header.binary_hash);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cipher::CipherKind;
+    use crate::container::{hash_binary, Arch, Content, Header};
+    use crate::payload::{decrypt, encrypt, PayloadError};
+
+    fn header() -> Header {
+        Header {
+            content: Content::Binary,
+            arch: Arch::X86_64,
+            features: 0,
+            infection: 0x5678,
+            binary_hash: hash_binary(b"\x7fELF"),
+            cipher: CipherKind::best().unwrap(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let cipher = CipherKind::best().unwrap().encryptor().unwrap();
+        let cipher = cipher.as_ref();
+        let payload = encrypt(cipher, &[1; 32], &header(), b"compressed", [9; 12]);
+
+        assert!(!payload.windows(10).any(|x| x == b"compressed"));
+        assert_eq!(
+            decrypt(cipher, &[1; 32], &header(), &payload).unwrap(),
+            b"compressed"
+        );
+
+        // The salt of another table, or the header of another infection, don't decrypt it.
+        let mut other = header();
+        other.infection += 1;
+        assert_eq!(
+            decrypt(cipher, &[2; 32], &header(), &payload),
+            Err(PayloadError::Tampered)
+        );
+        assert_eq!(
+            decrypt(cipher, &[1; 32], &other, &payload),
+            Err(PayloadError::Tampered)
+        );
+
+        let mut tampered = payload.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            decrypt(cipher, &[1; 32], &header(), &tampered),
+            Err(PayloadError::Tampered)
+        );
+        assert_eq!(
+            decrypt(cipher, &[1; 32], &header(), &payload[..4]),
+            Err(PayloadError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The environment variable that makes the runtime record a profile, to the file it names.
+pub const PROFILE_ENV: &str = "REKK_PROFILE";
+
+/// How many times each nanomite was hit, by JDT key.
+///
+/// Profiles are stored as text, one `<key in hex> <hits>` line per nanomite. Recording to an
+/// existing profile adds to its counts, so several runs can be combined.
+#[derive(Debug, Default, PartialEq)]
+pub struct Profile {
+    pub hits: BTreeMap<u64, u64>,
+}
+
+#[derive(Debug)]
+pub enum ProfileError {
+    Io(io::Error),
+
+    /// The given line of the profile is malformed.
+    Parse(usize),
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileError::Io(e) => write!(f, "couldn't access the profile: {}", e),
+            ProfileError::Parse(line) => write!(f, "malformed profile on line {}", line),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+impl From<io::Error> for ProfileError {
+    fn from(e: io::Error) -> Self {
+        ProfileError::Io(e)
+    }
+}
+
+impl Profile {
+    pub fn record(&mut self, key: u64) {
+        *self.hits.entry(key).or_insert(0) += 1;
+    }
+
+    pub fn hits(&self, key: u64) -> u64 {
+        self.hits.get(&key).copied().unwrap_or(0)
+    }
+
+    pub fn parse(text: &str) -> Result<Profile, ProfileError> {
+        let mut profile = Profile::default();
+
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let key = fields.next().and_then(|x| u64::from_str_radix(x, 16).ok());
+            let hits = fields.next().and_then(|x| x.parse::<u64>().ok());
+
+            match (key, hits, fields.next()) {
+                (Some(key), Some(hits), None) => *profile.hits.entry(key).or_insert(0) += hits,
+                _ => return Err(ProfileError::Parse(i + 1)),
+            }
+        }
+
+        Ok(profile)
+    }
+
+    pub fn read(path: &Path) -> Result<Profile, ProfileError> {
+        Profile::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Adds the hits to the profile at `path`, creating it if it doesn't exist.
+    pub fn merge_into(&self, path: &Path) -> Result<(), ProfileError> {
+        let mut merged = match Profile::read(path) {
+            Ok(profile) => profile,
+            Err(ProfileError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Profile::default(),
+            Err(e) => return Err(e),
+        };
+
+        for (key, hits) in self.hits.iter() {
+            *merged.hits.entry(*key).or_insert(0) += hits;
+        }
+
+        fs::write(path, merged.to_string())?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, hits) in self.hits.iter() {
+            writeln!(f, "{:x} {}", key, hits)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::profile::{Profile, ProfileError};
+
+    #[test]
+    fn round_trip() {
+        let mut profile = Profile::default();
+        profile.record(0x1130);
+        profile.record(0x1130);
+        profile.record(0x10a4);
+
+        assert_eq!(profile.to_string(), "10a4 1\n1130 2\n");
+        assert_eq!(Profile::parse(&profile.to_string()).unwrap(), profile);
+        assert_eq!(profile.hits(0x1130), 2);
+        assert_eq!(profile.hits(0x2000), 0);
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(matches!(
+            Profile::parse("10a4 1\n\n1130\n"),
+            Err(ProfileError::Parse(3))
+        ));
+        assert!(matches!(
+            Profile::parse("10a4 1 2\n"),
+            Err(ProfileError::Parse(1))
+        ));
+    }
+}
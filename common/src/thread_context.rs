@@ -0,0 +1,46 @@
+use std::error::Error;
+
+/// The registers and memory of the thread that hit a nanomite. Each runtime implements this for
+/// its debugging API, so jumps can be emulated without knowing how the thread is accessed.
+pub trait ThreadContext {
+    /// The address of the nanomite that was hit.
+    fn ip(&self) -> u64;
+    fn set_ip(&mut self, ip: u64);
+
+    fn flags(&self) -> u64;
+
+    fn sp(&self) -> u64;
+    fn set_sp(&mut self, sp: u64);
+
+    /// The counter register used by `loop` and `jrcxz`.
+    fn cx(&self) -> u64;
+    fn set_cx(&mut self, cx: u64);
+
+    /// Size of a return address on the stack, in bytes.
+    fn pointer_size(&self) -> u64;
+
+    fn read_memory(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Box<dyn Error>>;
+    fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<(), Box<dyn Error>>;
+
+    /// Push a return address on to the stack.
+    fn push(&mut self, value: u64) -> Result<(), Box<dyn Error>> {
+        let size = self.pointer_size();
+        let sp = self.sp().wrapping_sub(size);
+
+        self.write_memory(sp, &value.to_le_bytes()[..size as usize])?;
+        self.set_sp(sp);
+
+        Ok(())
+    }
+
+    /// Pop a return address off of the stack.
+    fn pop(&mut self) -> Result<u64, Box<dyn Error>> {
+        let size = self.pointer_size();
+        let mut value = [0; 8];
+
+        self.read_memory(self.sp(), &mut value[..size as usize])?;
+        self.set_sp(self.sp().wrapping_add(size));
+
+        Ok(u64::from_le_bytes(value))
+    }
+}
@@ -1,19 +1,40 @@
-use std::collections::HashMap;
+use std::error::Error;
 
 use goblin::elf::program_header::PT_LOAD;
 use goblin::elf::Elf;
-use goblin::pe::section_table::IMAGE_SCN_MEM_EXECUTE;
+use goblin::pe::section_table::{SectionTable, IMAGE_SCN_MEM_EXECUTE};
 use goblin::pe::PE;
+use goblin::Object;
+use rand::rngs::StdRng;
 
-use common::jump_data::JumpData;
+use common::jump_data_table::{CodeRegion, ImageLayout};
 
 use crate::code_section::CodeSection;
-use crate::infestor::infest;
+use crate::decoys::DecoyBudget;
+use crate::density::Density;
+use crate::functions::{elf_functions, pe_functions, Function, FunctionSelection};
+use crate::hot_branches::HotBranches;
+use crate::infestor::{self, infest, Placement};
+use crate::jump_data_exporter::RegionEntries;
+use crate::markers::{elf_markers, pe_markers, Markers};
+use crate::options::{Disassembly, InfectOptions};
+use crate::print_utils::{should_print, Verbosity};
+use crate::relocations::{self, pe_relocations};
+use crate::seeds::{elf_seeds, pe_seeds};
+use crate::traversal::{self, ReachedCode};
 
 const DEFAULT_SECTION_NAME: &str = "unknown section";
 
-pub(crate) fn handle_elf(data: &mut Vec<u8>, elf: Elf) -> Vec<HashMap<u64, JumpData>> {
+pub(crate) fn handle_elf(
+    data: &mut [u8],
+    elf: Elf,
+    options: &InfectOptions,
+    hot: Option<&HotBranches>,
+    density: Density,
+    rng: &mut StdRng,
+) -> (Vec<RegionEntries>, ImageLayout) {
     let mut jdts = Vec::new();
+    let mut found_sections = Vec::new();
 
     let base_header = elf
         .program_headers
@@ -21,13 +42,91 @@ pub(crate) fn handle_elf(data: &mut Vec<u8>, elf: Elf) -> Vec<HashMap<u64, JumpD
         .find(|x| x.is_executable() && x.p_type == PT_LOAD)
         .expect("Couldn't find ELF image base");
 
-    println!("base 0x{:X}", base_header.p_vaddr);
+    let layout = ImageLayout {
+        base: base_header.p_vaddr,
+        entry: elf.entry,
+        bitness: if elf.is_64 { 64 } else { 32 },
+    };
 
-    for header in elf.section_headers {
+    if should_print(Verbosity::Verbose) {
+        println!("base 0x{:X}", base_header.p_vaddr);
+    }
+
+    let section_name = |sh_name| elf.shdr_strtab.get(sh_name).unwrap().unwrap();
+    let selected_size = elf
+        .section_headers
+        .iter()
+        .filter(|x| x.is_executable() && options.is_section_selected(section_name(x.sh_name)))
+        .map(|x| x.sh_size)
+        .sum();
+    let mut decoys = DecoyBudget::new(options.decoys, selected_size);
+
+    let seeds = elf_seeds(&elf, data);
+    let reached = match options.disassembly {
+        Disassembly::Linear => None,
+        Disassembly::Recursive => {
+            let regions: Vec<traversal::CodeRegion> = elf
+                .section_headers
+                .iter()
+                .filter(|x| x.is_executable())
+                .map(|x| traversal::CodeRegion {
+                    vaddr: x.sh_addr,
+                    data: &data[x.sh_offset as usize..(x.sh_offset + x.sh_size) as usize],
+                })
+                .collect();
+
+            Some(traverse(&regions, layout.bitness, seeds.clone()))
+        }
+    };
+
+    let markers = elf_markers(&elf, data);
+    let mut placement = Placement {
+        reached: reached.as_ref(),
+        starts: seeds.into_iter().collect(),
+        functions: select_functions(options, markers.as_ref(), || elf_functions(&elf, data)),
+        hot,
+        density,
+    };
+
+    if placement.density.is_capped() {
+        let mut candidates = Vec::new();
+        let selected = elf
+            .section_headers
+            .iter()
+            .filter(|x| x.is_executable() && options.is_section_selected(section_name(x.sh_name)));
+
+        for header in selected {
+            let start = header.sh_offset as usize;
+            let section = CodeSection::new(
+                header.sh_offset,
+                header.sh_addr,
+                base_header.p_vaddr,
+                &mut data[start..start + header.sh_size as usize],
+                section_name(header.sh_name),
+            );
+            candidates.extend(infestor::candidates(
+                &section,
+                layout.bitness,
+                &placement,
+                options,
+            ));
+        }
+
+        placement.density.choose(&candidates);
+    }
+
+    for header in &elf.section_headers {
         if !header.is_executable() {
             continue;
         }
 
+        let name = section_name(header.sh_name);
+        found_sections.push(name);
+
+        if !options.is_section_selected(name) {
+            continue;
+        }
+
         let start = header.sh_offset;
         let end = start + header.sh_size;
 
@@ -36,16 +135,112 @@ pub(crate) fn handle_elf(data: &mut Vec<u8>, elf: Elf) -> Vec<HashMap<u64, JumpD
             header.sh_addr,
             base_header.p_vaddr,
             &mut data[start as usize..end as usize],
-            elf.shdr_strtab.get(header.sh_name).unwrap().unwrap(),
+            name,
         );
-        jdts.push(infest(&mut section, if elf.is_64 { 64 } else { 32 }));
+        let decoys = decoys.take(header.sh_size);
+        jdts.push(infest(
+            &mut section,
+            layout.bitness,
+            decoys,
+            &mut placement,
+            rng,
+            options,
+        ));
     }
 
-    jdts
+    warn_missing_sections(&found_sections, options);
+    print_function_counts(placement.functions.as_ref());
+    strip_markers(data, markers.as_ref(), options);
+
+    (jdts, layout)
 }
 
-pub(crate) fn handle_pe(data: &mut Vec<u8>, pe: PE) -> Vec<HashMap<u64, JumpData>> {
+pub(crate) fn handle_pe(
+    data: &mut [u8],
+    pe: PE,
+    options: &InfectOptions,
+    hot: Option<&HotBranches>,
+    density: Density,
+    rng: &mut StdRng,
+) -> (Vec<RegionEntries>, ImageLayout) {
     let mut jdts = Vec::new();
+    let mut found_sections = Vec::new();
+
+    let layout = ImageLayout {
+        base: pe.image_base as u64,
+        entry: pe.image_base as u64 + pe.entry as u64,
+        bitness: if pe.is_64 { 64 } else { 32 },
+    };
+
+    let selected_size = pe
+        .sections
+        .iter()
+        .filter(|x| {
+            x.characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+                && options.is_section_selected(&pe_section_name(x))
+        })
+        .map(|x| x.size_of_raw_data as u64)
+        .sum();
+    let mut decoys = DecoyBudget::new(options.decoys, selected_size);
+
+    let seeds = pe_seeds(&pe, data);
+    let reached = match options.disassembly {
+        Disassembly::Linear => None,
+        Disassembly::Recursive => {
+            let regions: Vec<traversal::CodeRegion> = pe
+                .sections
+                .iter()
+                .filter(|x| x.characteristics & IMAGE_SCN_MEM_EXECUTE != 0)
+                .map(|x| {
+                    let start = x.pointer_to_raw_data as usize;
+                    traversal::CodeRegion {
+                        vaddr: x.virtual_address as u64 + pe.image_base as u64,
+                        data: &data[start..start + x.size_of_raw_data as usize],
+                    }
+                })
+                .collect();
+
+            Some(traverse(&regions, layout.bitness, seeds.clone()))
+        }
+    };
+
+    let markers = pe_markers(&pe, data);
+    let relocations = pe_relocations(&pe, data);
+    let mut placement = Placement {
+        reached: reached.as_ref(),
+        starts: seeds.into_iter().collect(),
+        functions: select_functions(options, markers.as_ref(), || pe_functions(&pe, data)),
+        hot,
+        density,
+    };
+
+    if placement.density.is_capped() {
+        let mut candidates = Vec::new();
+        let selected = pe.sections.iter().filter(|x| {
+            x.characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+                && options.is_section_selected(&pe_section_name(x))
+        });
+
+        for sec in selected {
+            let start = sec.pointer_to_raw_data as usize;
+            let name = pe_section_name(sec);
+            let section = CodeSection::new(
+                start as u64,
+                sec.virtual_address as u64 + pe.image_base as u64,
+                pe.image_base as u64,
+                &mut data[start..start + sec.size_of_raw_data as usize],
+                name.as_str(),
+            );
+            candidates.extend(infestor::candidates(
+                &section,
+                layout.bitness,
+                &placement,
+                options,
+            ));
+        }
+
+        placement.density.choose(&candidates);
+    }
 
     for sec in pe.sections {
         if sec.characteristics & IMAGE_SCN_MEM_EXECUTE == 0 {
@@ -54,19 +249,191 @@ pub(crate) fn handle_pe(data: &mut Vec<u8>, pe: PE) -> Vec<HashMap<u64, JumpData
 
         let start = sec.pointer_to_raw_data as usize;
         let end = start + sec.size_of_raw_data as usize;
-        let default_sec_name = DEFAULT_SECTION_NAME.to_string();
-        let sec_name = sec.real_name.unwrap_or(default_sec_name);
+        let sec_name = pe_section_name(&sec);
 
-        let mut section = CodeSection::new(
-            start as u64,
-            sec.virtual_address as u64 + pe.image_base as u64,
-            pe.image_base as u64,
-            &mut data[start..end],
-            sec_name.as_str(),
+        if options.is_section_selected(&sec_name) {
+            let mut section = CodeSection::new(
+                start as u64,
+                sec.virtual_address as u64 + pe.image_base as u64,
+                pe.image_base as u64,
+                &mut data[start..end],
+                sec_name.as_str(),
+            )
+            .with_relocations(relocations::in_section(
+                &relocations,
+                sec.virtual_address as u64,
+                sec.size_of_raw_data as u64,
+            ));
+
+            let decoys = decoys.take(sec.size_of_raw_data as u64);
+            jdts.push(infest(
+                &mut section,
+                layout.bitness,
+                decoys,
+                &mut placement,
+                rng,
+                options,
+            ));
+        }
+
+        found_sections.push(sec_name);
+    }
+
+    warn_missing_sections(&found_sections, options);
+    print_function_counts(placement.functions.as_ref());
+    strip_markers(data, markers.as_ref(), options);
+
+    (jdts, layout)
+}
+
+/// Returns the executable sections of an image, the way `infest` records them in the jump data
+/// table.
+pub(crate) fn code_regions(data: &[u8]) -> Result<Vec<CodeRegion>, Box<dyn Error>> {
+    let regions = match Object::parse(data)? {
+        Object::Elf(elf) => {
+            let base = elf
+                .program_headers
+                .iter()
+                .find(|x| x.is_executable() && x.p_type == PT_LOAD)
+                .ok_or("couldn't find the ELF image base")?
+                .p_vaddr;
+
+            elf.section_headers
+                .iter()
+                .filter(|x| x.is_executable())
+                .map(|x| CodeRegion {
+                    start: x.sh_addr - base,
+                    size: x.sh_size,
+                    file_offset: x.sh_offset,
+                    relocations: Vec::new(),
+                })
+                .collect()
+        }
+        Object::PE(pe) => {
+            let relocations = pe_relocations(&pe, data);
+
+            pe.sections
+                .iter()
+                .filter(|x| x.characteristics & IMAGE_SCN_MEM_EXECUTE != 0)
+                .map(|x| CodeRegion {
+                    start: x.virtual_address as u64,
+                    size: x.size_of_raw_data as u64,
+                    file_offset: x.pointer_to_raw_data as u64,
+                    relocations: relocations::in_section(
+                        &relocations,
+                        x.virtual_address as u64,
+                        x.size_of_raw_data as u64,
+                    ),
+                })
+                .collect()
+        }
+        _ => return Err("the nanomite'd binary isn't a PE/ELF file".into()),
+    };
+
+    Ok(regions)
+}
+
+/// Returns the layout of an image, the way `infest` records it in the jump data table.
+pub(crate) fn image_layout(data: &[u8]) -> Result<ImageLayout, Box<dyn Error>> {
+    let layout = match Object::parse(data)? {
+        Object::Elf(elf) => ImageLayout {
+            base: elf
+                .program_headers
+                .iter()
+                .find(|x| x.is_executable() && x.p_type == PT_LOAD)
+                .ok_or("couldn't find the ELF image base")?
+                .p_vaddr,
+            entry: elf.entry,
+            bitness: if elf.is_64 { 64 } else { 32 },
+        },
+        Object::PE(pe) => ImageLayout {
+            base: pe.image_base as u64,
+            entry: pe.image_base as u64 + pe.entry as u64,
+            bitness: if pe.is_64 { 64 } else { 32 },
+        },
+        _ => return Err("the nanomite'd binary isn't a PE/ELF file".into()),
+    };
+
+    Ok(layout)
+}
+
+/// Resolves the functions to place nanomites in, if the options or markers limit them.
+fn select_functions<F: FnOnce() -> Vec<Function>>(
+    options: &InfectOptions,
+    markers: Option<&Markers>,
+    functions: F,
+) -> Option<FunctionSelection> {
+    let marked = markers.map_or(&[][..], |x| x.functions.as_slice());
+
+    if should_print(Verbosity::Normal) && !marked.is_empty() {
+        println!("found {} protection markers", marked.len());
+    }
+
+    if !options.selects_functions() && marked.is_empty() {
+        return None;
+    }
+
+    Some(FunctionSelection::new(
+        functions(),
+        &options.functions,
+        &options.exclude_functions,
+        marked,
+    ))
+}
+
+/// Zeroes the markers, if the options ask for it.
+fn strip_markers(data: &mut [u8], markers: Option<&Markers>, options: &InfectOptions) {
+    if let Some(markers) = markers.filter(|_| options.strip_markers) {
+        for b in data[markers.file_range.clone()].iter_mut() {
+            *b = 0;
+        }
+    }
+}
+
+/// Prints the number of nanomites placed in each function.
+fn print_function_counts(functions: Option<&FunctionSelection>) {
+    let functions = match functions {
+        Some(functions) if should_print(Verbosity::Normal) => functions,
+        _ => return,
+    };
+
+    println!("nanomites per function:");
+
+    for (name, count) in functions.counts() {
+        println!("  {:>6} {}", count, name);
+    }
+}
+
+/// Recursively disassembles the image from the given function starts.
+fn traverse(regions: &[traversal::CodeRegion], bitness: u32, seeds: Vec<u64>) -> ReachedCode {
+    let seed_count = seeds.len();
+    let reached = ReachedCode::traverse(regions, bitness, seeds);
+
+    if should_print(Verbosity::Normal) {
+        println!(
+            "reached {} instructions from {} function starts",
+            reached.len(),
+            seed_count
         );
+    }
 
-        jdts.push(infest(&mut section, if pe.is_64 { 64 } else { 32 }));
+    reached
+}
+
+/// Returns the full name of a PE section. Long names are stored in the string table.
+pub(crate) fn pe_section_name(sec: &SectionTable) -> String {
+    match sec.real_name {
+        Some(ref name) => name.clone(),
+        None => sec.name().unwrap_or(DEFAULT_SECTION_NAME).to_string(),
     }
+}
 
-    jdts
+/// Warn about sections that were requested on the command line, but aren't executable sections
+/// of the binary.
+fn warn_missing_sections<S: AsRef<str>>(found_sections: &[S], options: &InfectOptions) {
+    for name in options.sections.iter() {
+        if !found_sections.iter().any(|x| x.as_ref() == name) {
+            eprintln!("warning: no executable section named {}", name);
+        }
+    }
 }
@@ -1,9 +1,14 @@
+use std::ops::Range;
+
+use common::jump_data_table::CodeRegion;
+
 pub(crate) struct CodeSection<'a, 'b> {
     file_offset: u64,
     vaddr: u64,
     base: u64,
     data: &'a mut [u8],
     name: &'b str,
+    relocations: Vec<Range<u64>>,
 }
 
 impl<'a, 'b> CodeSection<'a, 'b> {
@@ -25,6 +30,14 @@ impl<'a, 'b> CodeSection<'a, 'b> {
     pub fn name(&self) -> &'b str {
         self.name
     }
+    pub fn region(&self) -> CodeRegion {
+        CodeRegion {
+            start: self.vaddr - self.base,
+            size: self.data.len() as u64,
+            file_offset: self.file_offset,
+            relocations: self.relocations.clone(),
+        }
+    }
 }
 
 impl<'a, 'b> CodeSection<'a, 'b> {
@@ -35,6 +48,13 @@ impl<'a, 'b> CodeSection<'a, 'b> {
             base,
             data,
             name,
+            relocations: Vec::new(),
         }
     }
+
+    /// Sets the JDT keys of the bytes in the section the loader relocates, sorted.
+    pub fn with_relocations(mut self, relocations: Vec<Range<u64>>) -> Self {
+        self.relocations = relocations;
+        self
+    }
 }
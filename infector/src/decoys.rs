@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use common::jump_data::JumpData;
+use common::JumpType;
+
+/// Splits the requested number of decoys between the infected sections, in proportion to their
+/// size.
+pub(crate) struct DecoyBudget {
+    count: usize,
+    total_size: u64,
+    seen_size: u64,
+}
+
+impl DecoyBudget {
+    pub fn new(count: usize, total_size: u64) -> Self {
+        DecoyBudget {
+            count,
+            total_size,
+            seen_size: 0,
+        }
+    }
+
+    /// Returns the number of decoys to place in the next section. The shares add up to exactly
+    /// `count` once every section has been taken.
+    pub fn take(&mut self, section_size: u64) -> usize {
+        if self.total_size == 0 {
+            return 0;
+        }
+
+        let before = self.share(self.seen_size);
+        self.seen_size += section_size;
+
+        self.share(self.seen_size) - before
+    }
+
+    fn share(&self, size: u64) -> usize {
+        (self.count as u128 * size as u128 / self.total_size as u128) as usize
+    }
+}
+
+/// Adds up to `count` decoy entries to a section's jump entries, at randomly chosen `sites`.
+///
+/// Sites are the keys of 0xCC bytes that are never executed, such as padding between functions,
+/// immediates and junk bytes, so the runtime never looks their entries up, and the nanomite'd
+/// binary has an `int3` at every entry, real or not. Each decoy copies the jump type and length of
+/// a random real entry, and its target is the displacement of another random real
+/// entry, moved to the closest instruction in `boundaries`. Returns the number of decoys added.
+pub(crate) fn add_decoys<R: Rng>(
+    entries: &mut BTreeMap<u64, JumpData>,
+    sites: &[u64],
+    boundaries: &[u64],
+    count: usize,
+    rng: &mut R,
+) -> usize {
+    // There's no distribution to draw from.
+    if entries.is_empty() {
+        return 0;
+    }
+
+    let templates: Vec<JumpData> = entries.values().cloned().collect();
+    let mut added = 0;
+
+    for &site in sites.choose_multiple(rng, count) {
+        let template = templates.choose(rng).unwrap();
+
+        // The displacement of ret is the number of bytes it releases, not a target.
+        let j_true = if template.jump_type() == JumpType::Return {
+            template.j_true()
+        } else {
+            let displacement = templates.choose(rng).unwrap().j_true();
+            decoy_target(site, displacement, boundaries).unwrap_or_else(|| template.j_true())
+        };
+
+        entries.insert(
+            site,
+            JumpData::new(template.jump_type(), j_true, template.j_false()),
+        );
+        added += 1;
+    }
+
+    added
+}
+
+/// Returns the displacement from `site` to the instruction closest to `site + displacement`.
+/// Real branches never target themselves, so neither do decoys.
+fn decoy_target(site: u64, displacement: isize, boundaries: &[u64]) -> Option<isize> {
+    // Targets before the image base are moved to the first instruction instead.
+    let wanted = (site as i64).saturating_add(displacement as i64).max(0) as u64;
+    let index = match boundaries.binary_search(&wanted) {
+        Ok(index) => index,
+        Err(index) => {
+            // Pick whichever neighbour is closer.
+            let after = boundaries.get(index);
+            let before = index.checked_sub(1).and_then(|i| boundaries.get(i));
+
+            match (before, after) {
+                (Some(&b), Some(&a)) if wanted - b <= a - wanted => index - 1,
+                (Some(_), None) => index - 1,
+                _ => index,
+            }
+        }
+    };
+
+    let target = *boundaries.get(index)?;
+
+    if target == site {
+        return None;
+    }
+
+    Some(target.wrapping_sub(site) as i64 as isize)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use common::jump_data::JumpData;
+    use common::JumpType;
+
+    use crate::decoys::{add_decoys, decoy_target, DecoyBudget};
+
+    #[test]
+    fn budget_adds_up() {
+        let mut budget = DecoyBudget::new(100, 23 + 48 + 927 + 9);
+        let shares: Vec<usize> = [23, 48, 927, 9].iter().map(|x| budget.take(*x)).collect();
+
+        assert_eq!(shares.iter().sum::<usize>(), 100);
+        assert_eq!(DecoyBudget::new(100, 0).take(0), 0);
+    }
+
+    #[test]
+    fn targets_are_instructions() {
+        let boundaries = [0x10, 0x12, 0x18, 0x20];
+
+        assert_eq!(decoy_target(0x12, 7, &boundaries), Some(6));
+        assert_eq!(decoy_target(0x12, 0x100, &boundaries), Some(0xE));
+        assert_eq!(decoy_target(0x18, -0x100, &boundaries), Some(-8));
+        assert_eq!(decoy_target(0x12, 1, &boundaries), None);
+    }
+
+    #[test]
+    fn decoys_only_at_sites() {
+        let mut entries = BTreeMap::new();
+        entries.insert(0x10, JumpData::new(JumpType::JumpEqual, 8, 2));
+        entries.insert(0x20, JumpData::new(JumpType::Return, 0, 1));
+
+        let sites = [0x12, 0x18];
+        let boundaries = [0x10, 0x12, 0x18, 0x20];
+        let added = add_decoys(
+            &mut entries,
+            &sites,
+            &boundaries,
+            5,
+            &mut rand::thread_rng(),
+        );
+
+        assert_eq!(added, 2);
+        assert_eq!(entries.len(), 4);
+        assert!(sites.iter().all(|x| entries.contains_key(x)));
+        assert_eq!(
+            add_decoys(
+                &mut BTreeMap::new(),
+                &sites,
+                &boundaries,
+                5,
+                &mut rand::thread_rng()
+            ),
+            0
+        );
+    }
+}
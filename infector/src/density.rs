@@ -0,0 +1,119 @@
+use std::collections::HashSet;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+/// Picks a random subset of the branches that could be replaced with nanomites. The subset only
+/// depends on the seed and the order the branches are offered in, so infecting the same binary
+/// with the same seed and options picks the same branches.
+pub(crate) struct Density {
+    /// The chance of patching each branch.
+    ratio: f64,
+
+    /// The maximum number of nanomites, if there's a cap.
+    max_nanomites: Option<usize>,
+
+    /// The addresses of the branches picked under the cap, once every candidate is known.
+    chosen: Option<HashSet<u64>>,
+
+    rng: StdRng,
+}
+
+impl Density {
+    pub fn new(ratio: f64, max_nanomites: Option<usize>, seed: u64) -> Self {
+        Density {
+            ratio,
+            max_nanomites,
+            chosen: None,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Returns true if the number of nanomites is capped, so every candidate branch has to be
+    /// offered to `choose` first.
+    pub fn is_capped(&self) -> bool {
+        self.max_nanomites.is_some()
+    }
+
+    /// Picks the branches to patch under the cap, out of the addresses of every candidate in the
+    /// image, so they're spread over all of it. Each candidate is kept with the chance of the
+    /// ratio first.
+    pub fn choose(&mut self, candidates: &[u64]) {
+        let max_nanomites = match self.max_nanomites {
+            Some(max_nanomites) => max_nanomites,
+            None => return,
+        };
+
+        let ratio = self.ratio;
+        let rng = &mut self.rng;
+        let kept: Vec<u64> = candidates
+            .iter()
+            .copied()
+            .filter(|_| ratio >= 1.0 || rng.gen_bool(ratio))
+            .collect();
+
+        self.chosen = Some(kept.choose_multiple(rng, max_nanomites).copied().collect());
+    }
+
+    /// Returns true if the branch at `ip` should be patched.
+    pub fn select(&mut self, ip: u64) -> bool {
+        match &self.chosen {
+            Some(chosen) => chosen.contains(&ip),
+            None => self.ratio >= 1.0 || self.rng.gen_bool(self.ratio),
+        }
+    }
+
+    /// Returns true if some branches might not be selected.
+    pub fn is_limited(&self) -> bool {
+        self.ratio < 1.0 || self.max_nanomites.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::density::Density;
+
+    fn selected(density: &mut Density, branches: u64) -> Vec<bool> {
+        (0..branches).map(|x| density.select(x)).collect()
+    }
+
+    fn capped(ratio: f64, max_nanomites: usize, seed: u64, branches: u64) -> Vec<bool> {
+        let mut density = Density::new(ratio, Some(max_nanomites), seed);
+        density.choose(&(0..branches).collect::<Vec<_>>());
+        selected(&mut density, branches)
+    }
+
+    #[test]
+    fn ratio_and_cap() {
+        let picks = selected(&mut Density::new(0.25, None, 7), 10_000);
+        let count = picks.iter().filter(|x| **x).count();
+        assert!(count > 2_000 && count < 3_000, "{}", count);
+
+        // The same seed picks the same branches.
+        assert_eq!(picks, selected(&mut Density::new(0.25, None, 7), 10_000));
+        assert_ne!(picks, selected(&mut Density::new(0.25, None, 8), 10_000));
+
+        let picks = capped(1.0, 3, 7, 10);
+        assert_eq!(picks.iter().filter(|x| **x).count(), 3);
+
+        let picks = capped(1.0, 20, 7, 3);
+        assert!(picks.iter().all(|x| *x));
+    }
+
+    #[test]
+    fn cap_spans_every_branch() {
+        let picks = capped(0.5, 100, 7, 10_000);
+        let chosen: Vec<usize> = (0..picks.len()).filter(|x| picks[*x]).collect();
+        assert_eq!(chosen.len(), 100);
+
+        // The cap is spread over every branch, rather than spent on the first ones.
+        assert!(chosen[0] < 1_000, "{:?}", chosen);
+        assert!(chosen[99] >= 9_000, "{:?}", chosen);
+        let first_half = chosen.iter().filter(|x| **x < 5_000).count();
+        assert!(first_half > 30 && first_half < 70, "{:?}", chosen);
+
+        assert_eq!(picks, capped(0.5, 100, 7, 10_000));
+        assert_ne!(picks, capped(0.5, 100, 8, 10_000));
+    }
+}
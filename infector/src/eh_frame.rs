@@ -0,0 +1,258 @@
+//! Just enough of an `.eh_frame` parser to find the range of every function described by an FDE.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+const DW_EH_PE_OMIT: u8 = 0xFF;
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_ULEB128: u8 = 0x01;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SLEB128: u8 = 0x09;
+const DW_EH_PE_SDATA2: u8 = 0x0A;
+const DW_EH_PE_SDATA4: u8 = 0x0B;
+const DW_EH_PE_SDATA8: u8 = 0x0C;
+const DW_EH_PE_PCREL: u8 = 0x10;
+
+/// Reads little endian values out of a section, tracking the virtual address of the next byte.
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+    vaddr: u64,
+    pointer_size: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.offset..self.offset.checked_add(len)?)?;
+        self.offset += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    fn uleb128(&mut self) -> Option<u64> {
+        let mut result = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                result |= ((byte & 0x7F) as u64) << shift;
+            }
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+    }
+
+    fn sleb128(&mut self) -> Option<i64> {
+        let mut result = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                result |= ((byte & 0x7F) as i64) << shift;
+            }
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    result |= -1 << shift;
+                }
+
+                return Some(result);
+            }
+        }
+    }
+
+    fn c_str(&mut self) -> Option<&'a [u8]> {
+        let len = self.data.get(self.offset..)?.iter().position(|x| *x == 0)?;
+        let s = self.bytes(len)?;
+        self.offset += 1;
+        Some(s)
+    }
+
+    /// Reads a pointer stored with one of the `DW_EH_PE_*` encodings.
+    fn pointer(&mut self, encoding: u8) -> Option<u64> {
+        let field_vaddr = self.vaddr + self.offset as u64;
+
+        let value = match encoding & 0x0F {
+            DW_EH_PE_ABSPTR if self.pointer_size == 4 => self.u32()? as u64,
+            DW_EH_PE_ABSPTR => self.u64()?,
+            DW_EH_PE_ULEB128 => self.uleb128()?,
+            DW_EH_PE_UDATA2 => self.u16()? as u64,
+            DW_EH_PE_UDATA4 => self.u32()? as u64,
+            DW_EH_PE_UDATA8 => self.u64()?,
+            DW_EH_PE_SLEB128 => self.sleb128()? as u64,
+            DW_EH_PE_SDATA2 => self.u16()? as i16 as u64,
+            DW_EH_PE_SDATA4 => self.u32()? as i32 as u64,
+            DW_EH_PE_SDATA8 => self.u64()?,
+            _ => return None,
+        };
+
+        // Text and data relative pointers aren't used for FDE addresses by any common toolchain.
+        match encoding & 0x70 {
+            0 => Some(value),
+            DW_EH_PE_PCREL => Some(field_vaddr.wrapping_add(value)),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the initial location and address range of every FDE in an `.eh_frame` section loaded
+/// at `vaddr`. Parsing stops at the first malformed record, keeping what was found up to that
+/// point.
+pub(crate) fn function_ranges(data: &[u8], vaddr: u64, pointer_size: usize) -> Vec<(u64, u64)> {
+    let mut reader = Reader {
+        data,
+        offset: 0,
+        vaddr,
+        pointer_size,
+    };
+    let mut ranges = Vec::new();
+
+    // The FDE pointer encoding of each CIE, by offset.
+    let mut cie_encodings = HashMap::new();
+
+    while let Some(range) = parse_record(&mut reader, &mut cie_encodings) {
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+    }
+
+    ranges
+}
+
+/// Parses a single CIE or FDE, returning the initial location and address range if it's an FDE.
+/// Returns `None` at the end of the section.
+fn parse_record(
+    reader: &mut Reader,
+    cie_encodings: &mut HashMap<usize, u8>,
+) -> Option<Option<(u64, u64)>> {
+    let record_offset = reader.offset;
+    let mut length = reader.u32()? as u64;
+
+    // The zero terminator.
+    if length == 0 {
+        return None;
+    }
+
+    if length == 0xFFFF_FFFF {
+        length = reader.u64()?;
+    }
+
+    let id_offset = reader.offset;
+    let end = id_offset.checked_add(length.try_into().ok()?)?;
+    let id = reader.u32()?;
+
+    let range = if id == 0 {
+        cie_encodings.insert(record_offset, parse_cie(reader)?);
+        None
+    } else {
+        // The CIE pointer is relative to the field itself.
+        let cie_offset = id_offset.checked_sub(id as usize)?;
+        let encoding = *cie_encodings.get(&cie_offset)?;
+
+        if encoding == DW_EH_PE_OMIT {
+            None
+        } else {
+            // The address range uses the same format, but is never relative.
+            let start = reader.pointer(encoding)?;
+            let size = reader.pointer(encoding & 0x0F)?;
+            Some((start, size))
+        }
+    };
+
+    if end > reader.data.len() {
+        return None;
+    }
+    reader.offset = end;
+
+    Some(range)
+}
+
+/// Parses a CIE, after its id, returning the encoding of the FDE pointers that refer to it.
+fn parse_cie(reader: &mut Reader) -> Option<u8> {
+    let version = reader.u8()?;
+    let augmentation = reader.c_str()?;
+
+    if augmentation.starts_with(b"eh") {
+        reader.bytes(reader.pointer_size)?;
+    }
+
+    // Code and data alignment factors, and the return address register.
+    reader.uleb128()?;
+    reader.sleb128()?;
+    if version == 1 {
+        reader.u8()?;
+    } else {
+        reader.uleb128()?;
+    }
+
+    let mut encoding = DW_EH_PE_ABSPTR;
+
+    if augmentation.first() == Some(&b'z') {
+        reader.uleb128()?;
+
+        for c in augmentation[1..].iter() {
+            match c {
+                b'L' => {
+                    reader.u8()?;
+                }
+                b'P' => {
+                    let personality_encoding = reader.u8()?;
+                    // The personality routine can be indirect, which only changes how it's used.
+                    reader.pointer(personality_encoding & 0x7F)?;
+                }
+                b'R' => encoding = reader.u8()?,
+                b'S' | b'B' => {}
+                _ => return None,
+            }
+        }
+    }
+
+    Some(encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::eh_frame::function_ranges;
+
+    #[test]
+    fn pc_relative_fdes() {
+        #[rustfmt::skip]
+        let eh_frame = [
+            // CIE: "zR", pcrel | sdata4 FDE pointers.
+            0x14, 0, 0, 0, 0, 0, 0, 0, 1, b'z', b'R', 0, 1, 0x78, 0x10, 1, 0x1B,
+            0x0C, 0x07, 0x08, 0x90, 0x01, 0, 0,
+            // FDE at 0x18, with its pc_begin field at 0x20.
+            0x10, 0, 0, 0, 0x1C, 0, 0, 0, 0xE0, 0x0F, 0, 0, 0x10, 0, 0, 0, 0, 0, 0, 0,
+            // Terminator.
+            0, 0, 0, 0,
+        ];
+
+        assert_eq!(
+            function_ranges(&eh_frame, 0x4000, 8),
+            vec![(0x4000 + 0x20 + 0xFE0, 0x10)]
+        );
+    }
+}
@@ -0,0 +1,380 @@
+//! Resolves function names to address ranges, so nanomites can be limited to some functions.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use goblin::elf::sym::{STT_FUNC, STT_GNU_IFUNC};
+use goblin::elf::Elf;
+use goblin::pe::PE;
+
+use crate::eh_frame;
+
+/// A named function in the image.
+#[derive(Debug, Clone)]
+pub(crate) struct Function {
+    pub name: String,
+    pub start: u64,
+
+    /// The size of the function, if the symbol table records it.
+    pub size: Option<u64>,
+}
+
+/// Returns the functions in the ELF symbol table and dynamic symbol table, and the functions
+/// described by `.eh_frame`. Functions that only have unwind information are named after their
+/// address.
+pub(crate) fn elf_functions(elf: &Elf, data: &[u8]) -> Vec<Function> {
+    let symbols = elf
+        .syms
+        .iter()
+        .map(|x| (x, elf.strtab.get(x.st_name)))
+        .chain(
+            elf.dynsyms
+                .iter()
+                .map(|x| (x, elf.dynstrtab.get(x.st_name))),
+        );
+
+    let mut functions: Vec<Function> = symbols
+        .filter(|(sym, _)| matches!(sym.st_type(), STT_FUNC | STT_GNU_IFUNC) && sym.st_shndx != 0)
+        .map(|(sym, name)| Function {
+            name: match name {
+                Some(Ok(name)) => name.to_string(),
+                _ => String::new(),
+            },
+            start: sym.st_value,
+            size: if sym.st_size != 0 {
+                Some(sym.st_size)
+            } else {
+                None
+            },
+        })
+        .collect();
+
+    let pointer_size = if elf.is_64 { 8 } else { 4 };
+    let eh_frame = elf
+        .section_headers
+        .iter()
+        .find(|x| matches!(elf.shdr_strtab.get(x.sh_name), Some(Ok(".eh_frame"))));
+
+    if let Some(header) = eh_frame {
+        let start = header.sh_offset as usize;
+
+        if let Some(contents) = data.get(start..start + header.sh_size as usize) {
+            let ranges = eh_frame::function_ranges(contents, header.sh_addr, pointer_size);
+            add_unwind_ranges(&mut functions, ranges);
+        }
+    }
+
+    functions
+}
+
+/// Returns the exported functions and COFF function symbols of a PE binary, and the functions
+/// described by `.pdata`. Their sizes are taken from the `.pdata` entry starting at the same
+/// address, if there is one.
+pub(crate) fn pe_functions(pe: &PE, data: &[u8]) -> Vec<Function> {
+    let image_base = pe.image_base as u64;
+    let mut functions = Vec::new();
+
+    for export in pe.exports.iter().filter(|x| x.reexport.is_none()) {
+        functions.push(Function {
+            name: export.name.unwrap_or_default().to_string(),
+            start: image_base + export.rva as u64,
+            size: None,
+        });
+    }
+
+    if let Ok(symbols) = pe.header.coff_header.symbols(data) {
+        let strings = pe.header.coff_header.strings(data).ok();
+
+        for (index, inline_name, symbol) in symbols.iter() {
+            if !symbol.is_function_definition() || symbol.section_number <= 0 {
+                continue;
+            }
+
+            let section = match pe.sections.get(symbol.section_number as usize - 1) {
+                Some(section) => section,
+                None => continue,
+            };
+
+            let name = match (inline_name, &strings) {
+                (Some(name), _) => name,
+                (None, Some(strings)) => symbol.name(strings).unwrap_or_default(),
+                (None, None) => "",
+            };
+
+            // The function definition auxiliary record has the size of the function.
+            let size = if symbol.number_of_aux_symbols > 0 {
+                symbols
+                    .aux_function_definition(index + 1)
+                    .map(|x| x.total_size as u64)
+                    .filter(|x| *x != 0)
+            } else {
+                None
+            };
+
+            // Symbol values are relative to the start of their section.
+            functions.push(Function {
+                name: name.to_string(),
+                start: image_base + section.virtual_address as u64 + symbol.value as u64,
+                size,
+            });
+        }
+    }
+
+    if let Some(exception_data) = &pe.exception_data {
+        let ranges = exception_data
+            .functions()
+            .filter_map(Result::ok)
+            .map(|x| {
+                (
+                    image_base + x.begin_address as u64,
+                    x.end_address.saturating_sub(x.begin_address) as u64,
+                )
+            })
+            .collect();
+
+        add_unwind_ranges(&mut functions, ranges);
+    }
+
+    functions
+}
+
+/// Sizes the functions that start where an unwind entry does, and adds the remaining entries as
+/// functions named after their address.
+fn add_unwind_ranges(functions: &mut Vec<Function>, ranges: Vec<(u64, u64)>) {
+    let sizes: HashMap<u64, u64> = ranges.iter().copied().collect();
+    let mut named = HashSet::new();
+
+    for function in functions.iter_mut() {
+        named.insert(function.start);
+
+        if function.size.is_none() {
+            function.size = sizes.get(&function.start).copied();
+        }
+    }
+
+    for (start, size) in ranges {
+        if named.insert(start) {
+            functions.push(Function {
+                name: format!("sub_{:x}", start),
+                start,
+                size: Some(size),
+            });
+        }
+    }
+}
+
+/// Matches `name` against a pattern, where `*` matches any number of characters, and `?` matches
+/// a single character.
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+
+    // Where to resume after the last `*`, if the rest of the pattern doesn't match.
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the `*` match one more character.
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+/// The functions to place nanomites in, and the number placed in each one.
+pub(crate) struct FunctionSelection {
+    /// The address ranges of every known function, by start address.
+    ranges: BTreeMap<u64, (u64, String)>,
+
+    /// The ranges of the functions matching the include patterns, and of the marked functions.
+    /// `None` includes everything.
+    included: Option<Vec<(u64, u64)>>,
+
+    /// The ranges of the functions matching the exclude patterns.
+    excluded: Vec<(u64, u64)>,
+
+    /// The number of nanomites placed in each function, by start address.
+    counts: BTreeMap<u64, usize>,
+}
+
+impl FunctionSelection {
+    /// Resolves the include and exclude patterns, and the start addresses of the functions marked
+    /// in the source. Functions without a size are assumed to end where the next function starts.
+    pub fn new(
+        mut functions: Vec<Function>,
+        include: &[String],
+        exclude: &[String],
+        marked: &[u64],
+    ) -> Self {
+        functions.sort_by_key(|x| x.start);
+        functions.dedup_by(|a, b| a.start == b.start && a.name == b.name);
+
+        let mut ranges = BTreeMap::new();
+
+        for (i, function) in functions.iter().enumerate() {
+            let next_start = functions[i + 1..]
+                .iter()
+                .map(|x| x.start)
+                .find(|x| *x > function.start);
+
+            let end = match (function.size, next_start) {
+                (Some(size), _) => function.start + size,
+                (None, Some(next_start)) => next_start,
+                (None, None) => function.start + 1,
+            };
+
+            // Aliases of the same function are reported under the first name.
+            ranges
+                .entry(function.start)
+                .or_insert((end, function.name.clone()));
+        }
+
+        let matching = |patterns: &[String]| -> Vec<(u64, u64)> {
+            functions
+                .iter()
+                .filter(|x| patterns.iter().any(|p| glob_match(p, &x.name)))
+                .map(|x| (x.start, ranges[&x.start].0))
+                .collect()
+        };
+
+        for pattern in include.iter().chain(exclude.iter()) {
+            if !functions.iter().any(|x| glob_match(pattern, &x.name)) {
+                eprintln!("warning: no function matches {}", pattern);
+            }
+        }
+
+        let included = if include.is_empty() && marked.is_empty() {
+            None
+        } else {
+            let mut included = matching(include);
+
+            for start in marked {
+                match ranges.get(start) {
+                    Some((end, _)) => included.push((*start, *end)),
+                    None => eprintln!("warning: no function starts at marker 0x{:X}", start),
+                }
+            }
+
+            Some(included)
+        };
+
+        FunctionSelection {
+            excluded: matching(exclude),
+            included,
+            ranges,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Returns true if nanomites can be placed at `vaddr`.
+    pub fn is_selected(&self, vaddr: u64) -> bool {
+        let in_range = |x: &(u64, u64)| vaddr >= x.0 && vaddr < x.1;
+
+        self.included
+            .as_ref()
+            .is_none_or(|included| included.iter().any(in_range))
+            && !self.excluded.iter().any(in_range)
+    }
+
+    /// Counts a nanomite placed at `vaddr` towards the function containing it.
+    pub fn record(&mut self, vaddr: u64) {
+        if let Some((start, (end, _))) = self.ranges.range(..=vaddr).next_back() {
+            if vaddr < *end {
+                *self.counts.entry(*start).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Returns the name and nanomite count of every function that has nanomites, and of every
+    /// included function.
+    pub fn counts(&self) -> Vec<(&str, usize)> {
+        self.ranges
+            .iter()
+            .filter_map(|(start, (_, name))| {
+                let count = self.counts.get(start).copied().unwrap_or(0);
+                let included = self
+                    .included
+                    .as_ref()
+                    .is_some_and(|x| x.iter().any(|x| x.0 == *start));
+
+                if count > 0 || included {
+                    Some((name.as_str(), count))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::functions::{glob_match, Function, FunctionSelection};
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("check_license", "check_license"));
+        assert!(glob_match("*license*", "check_license_key"));
+        assert!(glob_match("aes_?_*", "aes_3_encrypt"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("aes_*", "chacha_encrypt"));
+        assert!(!glob_match("*_key", "check_license_key2"));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn selection() {
+        let function = |name: &str, start, size| Function {
+            name: name.to_string(),
+            start,
+            size,
+        };
+        let functions = vec![
+            function("check_license", 0x1000, Some(0x80)),
+            function("check_license_key", 0x1100, None),
+            function("decode_frame", 0x1200, Some(0x100)),
+        ];
+
+        let mut selection = FunctionSelection::new(
+            functions,
+            &["check_*".to_string()],
+            &["*_key".to_string()],
+            &[0x1200],
+        );
+
+        assert!(selection.is_selected(0x1000));
+        assert!(!selection.is_selected(0x1080));
+        assert!(!selection.is_selected(0x1150));
+        assert!(selection.is_selected(0x1250));
+        assert!(!selection.is_selected(0x1300));
+
+        selection.record(0x1010);
+        selection.record(0x1250);
+
+        assert_eq!(
+            selection.counts(),
+            vec![
+                ("check_license", 1),
+                ("check_license_key", 0),
+                ("decode_frame", 1)
+            ]
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! Keeps nanomites out of the branches a profile shows are hit the most, since every hit costs a
+//! round trip through the runtime.
+
+use std::collections::HashSet;
+
+use common::profile::Profile;
+
+/// The JDT keys of the branches to leave unpatched.
+pub(crate) struct HotBranches {
+    keys: HashSet<u64>,
+}
+
+impl HotBranches {
+    /// Picks the branches hit more than `max_hits` times. With a `hit_budget`, the coldest branches
+    /// are kept until their hits add up to the budget, and the rest are picked as well. Branches
+    /// missing from the profile were never hit, so they're always kept.
+    pub fn new(profile: &Profile, max_hits: Option<u64>, hit_budget: Option<u64>) -> Self {
+        let mut entries: Vec<(u64, u64)> = profile.hits.iter().map(|(k, v)| (*v, *k)).collect();
+        entries.sort_unstable();
+
+        let mut keys = HashSet::new();
+        let mut total: u64 = 0;
+
+        for (hits, key) in entries {
+            total = total.saturating_add(hits);
+
+            if max_hits.is_some_and(|x| hits > x) || hit_budget.is_some_and(|x| total > x) {
+                keys.insert(key);
+            }
+        }
+
+        HotBranches { keys }
+    }
+
+    /// Returns true if the branch with the given JDT key shouldn't be patched.
+    pub fn contains(&self, key: u64) -> bool {
+        self.keys.contains(&key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::profile::Profile;
+
+    use crate::hot_branches::HotBranches;
+
+    #[test]
+    fn thresholds() {
+        let mut profile = Profile::default();
+        profile.hits.insert(0x1000, 1);
+        profile.hits.insert(0x1010, 5);
+        profile.hits.insert(0x1020, 100);
+        profile.hits.insert(0x1030, 1000);
+
+        let hot = HotBranches::new(&profile, Some(50), None);
+        assert_eq!(hot.len(), 2);
+        assert!(hot.contains(0x1020) && hot.contains(0x1030));
+        assert!(!hot.contains(0x1040));
+
+        // 1 + 5 fits the budget, but 1 + 5 + 100 doesn't.
+        let hot = HotBranches::new(&profile, None, Some(50));
+        assert_eq!(hot.len(), 2);
+        assert!(!hot.contains(0x1000) && !hot.contains(0x1010));
+
+        let hot = HotBranches::new(&profile, Some(2), Some(2000));
+        assert_eq!(hot.len(), 3);
+        assert!(!hot.contains(0x1000));
+    }
+}
@@ -1,7 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet};
 
-use iced_x86::{Decoder, DecoderOptions, FlowControl, Formatter, Instruction, NasmFormatter};
+use iced_x86::{Code, Decoder, DecoderOptions, FlowControl, Formatter, Instruction, NasmFormatter};
 use num_traits::FromPrimitive;
+use rand::rngs::StdRng;
 use rand::Rng;
 use termcolor::Color;
 
@@ -9,28 +10,163 @@ use common::jump_data::JumpData;
 use common::JumpType;
 
 use crate::code_section::CodeSection;
-use crate::print_utils::print_color;
+use crate::decoys::add_decoys;
+use crate::density::Density;
+use crate::functions::FunctionSelection;
+use crate::hot_branches::HotBranches;
+use crate::jump_data_exporter::RegionEntries;
+use crate::options::{BranchKind, InfectOptions};
+use crate::print_utils::{print_color, should_print, Verbosity};
+use crate::traversal::ReachedCode;
 
 const HEXBYTES_COLUMN_BYTE_LENGTH: usize = 10;
 
-pub(crate) fn infest(section: &mut CodeSection, bitness: u32) -> HashMap<u64, JumpData> {
-    let result = create_nanomites(section, bitness);
+/// Decides which of the branches picked by `--branches` are replaced with nanomites. It's shared
+/// by every infected section of the image.
+pub(crate) struct Placement<'a> {
+    /// The instructions found by the recursive traversal, if it's used. Other bytes are never
+    /// patched.
+    pub reached: Option<&'a ReachedCode>,
+
+    /// Where execution is known to start, such as the entry point and function starts. An int3
+    /// there is the program's own, even right after padding.
+    pub starts: HashSet<u64>,
+
+    /// The functions to place nanomites in, if they're limited.
+    pub functions: Option<FunctionSelection>,
+
+    /// The branches a profile shows are too hot to patch.
+    pub hot: Option<&'a HotBranches>,
+
+    pub density: Density,
+}
+
+/// Why a branch wasn't replaced with a nanomite.
+enum Skipped {
+    Hot,
+    Density,
+}
+
+impl<'a> Placement<'a> {
+    /// Returns true if the given branch can be patched at all.
+    fn is_candidate(&self, instruction: &Instruction) -> bool {
+        self.reached
+            .is_none_or(|x| x.is_patchable(instruction.ip(), instruction.len()))
+            && self
+                .functions
+                .as_ref()
+                .is_none_or(|x| x.is_selected(instruction.ip()))
+    }
+
+    /// Decides whether to patch a candidate branch, with the given JDT key.
+    fn select(&mut self, instruction: &Instruction, key: u64) -> Result<(), Skipped> {
+        if self.hot.is_some_and(|x| x.contains(key)) {
+            return Err(Skipped::Hot);
+        }
+
+        if !self.density.select(instruction.ip()) {
+            return Err(Skipped::Density);
+        }
+
+        if let Some(functions) = self.functions.as_mut() {
+            functions.record(instruction.ip());
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the addresses of the branches of a section that the density would be asked about, so a
+/// cap on the number of nanomites can be spread over every section.
+pub(crate) fn candidates(
+    section: &CodeSection,
+    bitness: u32,
+    placement: &Placement,
+    options: &InfectOptions,
+) -> Vec<u64> {
+    let mut decoder = Decoder::new(bitness, section.data_ref(), DecoderOptions::NONE);
+    let mut instruction = Instruction::default();
+    let mut candidates = Vec::new();
+
+    decoder.set_ip(section.vaddr());
+    while decoder.can_decode() {
+        if let Some(reached) = placement.reached {
+            let next = next_reached(section, reached, decoder.position());
+
+            if next != decoder.position() {
+                decoder.set_position(next).unwrap();
+                decoder.set_ip(section.vaddr() + next as u64);
+                continue;
+            }
+        }
+
+        decoder.decode_out(&mut instruction);
+
+        let key = instruction.ip() - section.base();
+        let selected = branch_kind(&instruction)
+            .is_some_and(|kind| options.branches.contains(&kind))
+            && placement.is_candidate(&instruction)
+            && !placement.hot.is_some_and(|x| x.contains(key));
+
+        if selected {
+            candidates.push(instruction.ip());
+        }
+    }
+
+    candidates
+}
+
+/// Returns the position in the section of the next instruction the recursive traversal reached,
+/// at or after `position`.
+fn next_reached(section: &CodeSection, reached: &ReachedCode, position: usize) -> usize {
+    let ip = section.vaddr() + position as u64;
+
+    reached
+        .next_instruction(ip)
+        .map_or(section.data_ref().len(), |x| {
+            (x - section.vaddr()).min(section.data_ref().len() as u64) as usize
+        })
+}
+
+pub(crate) fn infest(
+    section: &mut CodeSection,
+    bitness: u32,
+    decoys: usize,
+    placement: &mut Placement,
+    rng: &mut StdRng,
+    options: &InfectOptions,
+) -> RegionEntries {
+    let result = create_nanomites(section, bitness, decoys, placement, rng, options);
 
     section.write_data(result.0.as_ref());
     result.1
 }
 
-fn create_nanomites(section: &CodeSection, bitness: u32) -> (Vec<u8>, HashMap<u64, JumpData>) {
-    print_color(
-        &*format!("\n[[ disassembling {} ]]\n\n", section.name()),
-        Color::Blue,
-    );
+fn create_nanomites(
+    section: &CodeSection,
+    bitness: u32,
+    decoys: usize,
+    placement: &mut Placement,
+    rng: &mut StdRng,
+    options: &InfectOptions,
+) -> (Vec<u8>, RegionEntries) {
+    let listing = should_print(Verbosity::Verbose);
+
+    if listing {
+        print_color(
+            &format!(
+                "\n[[ disassembling {} @ file offset 0x{:X} ]]\n\n",
+                section.name(),
+                section.file_offset()
+            ),
+            Color::Blue,
+        );
+    }
 
     let mut decoder = Decoder::new(bitness, section.data_ref(), DecoderOptions::NONE);
     let mut instruction = Instruction::default();
     let mut instructions = Vec::new();
     let mut formatter = NasmFormatter::new();
-    let mut rng = rand::thread_rng();
 
     // Change some options, there are many more
     formatter.options_mut().set_digit_separator("`");
@@ -40,112 +176,330 @@ fn create_nanomites(section: &CodeSection, bitness: u32) -> (Vec<u8>, HashMap<u6
 
     let mut output = String::new();
     let mut jump_entry;
-    let mut jump_entries = HashMap::new();
+    let mut jump_entries = BTreeMap::new();
+
+    // The keys of every instruction, and of the 0xCC bytes that are never executed, where decoys
+    // are placed.
+    let mut boundaries = Vec::new();
+    let mut decoy_sites = Vec::new();
+
+    // The keys of the program's own breakpoints, and the key after the last instruction if it was
+    // an int3.
+    let mut breakpoints = Vec::new();
+    let mut int3_end = None;
+
+    // Whether execution never continues past the last instruction, and whether the run of int3s
+    // being decoded is padding.
+    let mut after_exit = false;
+    let mut in_padding = false;
+
+    // The number of branches left unpatched because the profile shows they're hot, and because
+    // of the density.
+    let mut skipped_hot = 0;
+    let mut skipped_density = 0;
 
     decoder.set_ip(section.vaddr());
     while decoder.can_decode() {
+        if let Some(reached) = placement.reached {
+            let position = decoder.position();
+            let next = next_reached(section, reached, position);
+
+            // Bytes that were never reached, such as data and padding, are kept as is.
+            if next != position {
+                let data = section.data_ref();
+
+                for index in position..next {
+                    let after_int3 = index.checked_sub(1).and_then(|i| data.get(i)) == Some(&0xCC);
+
+                    if is_padding(data, index, after_int3) {
+                        decoy_sites.push(section.vaddr() + index as u64 - section.base());
+                    }
+                }
+
+                instructions.extend_from_slice(&section.data_ref()[position..next]);
+                decoder.set_position(next).unwrap();
+                decoder.set_ip(section.vaddr() + next as u64);
+                continue;
+            }
+        }
+
         // decode the instruction.
         decoder.decode_out(&mut instruction);
 
-        print!("{:016X} ", instruction.ip());
+        if listing {
+            print!("{:016X} ", instruction.ip());
+        }
 
         // get the bytes that make up the instruction
         let start_index = (instruction.ip() - section.vaddr()) as usize;
         let instr_bytes = &section.data_ref()[start_index..start_index + instruction.len()];
 
-        // does the instruction contain an 0xCC (int 3)?
-        let mut contains_cc = false;
-
-        // print each hex byte in the instruction.
-        for b in instr_bytes.iter() {
-            print!("{:02X} ", b);
-
-            if b.eq(&0xCC_u8) {
-                contains_cc = true;
+        if listing {
+            // print each hex byte in the instruction.
+            for b in instr_bytes.iter() {
+                print!("{:02X} ", b);
             }
-        }
 
-        // Print padding
-        if instr_bytes.len() < HEXBYTES_COLUMN_BYTE_LENGTH {
-            for _ in 0..HEXBYTES_COLUMN_BYTE_LENGTH - instr_bytes.len() {
-                print!("   ");
+            // Print padding
+            if instr_bytes.len() < HEXBYTES_COLUMN_BYTE_LENGTH {
+                for _ in 0..HEXBYTES_COLUMN_BYTE_LENGTH - instr_bytes.len() {
+                    print!("   ");
+                }
             }
-        }
 
-        // format the instruction & print to the console.
-        output.clear();
-        formatter.format(&instruction, &mut output);
-        print!(" {}", output);
+            // format the instruction & print to the console.
+            output.clear();
+            formatter.format(&instruction, &mut output);
+            print!(" {}", output);
+        }
 
         // reset the jump entry that might be added to the jdt
         jump_entry = None;
-
-        if contains_cc {
-            print_color(" << FAKE NANOMITE >> ", Color::Red);
-
-            // todo this should be random.
-            jump_entry = Some(JumpData::new(JumpType::JumpParity, 100, 1000));
-        }
+        let key = instruction.ip() - section.base();
+        boundaries.push(key);
 
         // was this instruction patched to an 0xCC?
         let mut patched = true;
 
-        match instruction.flow_control() {
-            FlowControl::ConditionalBranch => {
+        let mut kind = branch_kind(&instruction)
+            .filter(|kind| options.branches.contains(kind) && placement.is_candidate(&instruction));
+
+        if kind.is_some() {
+            match placement.select(&instruction, key) {
+                Ok(()) => {}
+                // Branches the profile shows are hot are kept, so they don't go through the
+                // runtime.
+                Err(Skipped::Hot) => {
+                    kind = None;
+                    skipped_hot += 1;
+
+                    if listing {
+                        print_color(" <=========== [[ HOT ]]", Color::Yellow);
+                    }
+                }
+                Err(Skipped::Density) => {
+                    kind = None;
+                    skipped_density += 1;
+                }
+            }
+        }
+
+        match kind {
+            Some(_) => {
                 // Found a (un)conditional branch. Replace the code with INT 3, and replace the extra
                 // bytes with random bytes. The random bytes are needed, as we don't want to fixup jump locations.
-                print_color(" <=========== [[ NANOMITE ]]", Color::Green);
+                if listing {
+                    print_color(" <=========== [[ NANOMITE ]]", Color::Green);
+                }
                 jump_entry = Some(instr_to_jump_entry(instruction));
 
                 // Push the int 3 opcode.
                 instructions.push(0xCC_u8);
 
-                // Add junk bytes.
+                // Add junk bytes. Some of them are an int3, so decoys can be placed after
+                // nanomites too.
+                let int3 = match instr_bytes.len() {
+                    1 => None,
+                    len => Some(rng.gen_range(1..len)).filter(|_| rng.gen()),
+                };
+
                 for (i, _) in instr_bytes.iter().enumerate() {
                     if i == 0 {
                         continue;
                     }
 
-                    let rnd_byte: u8 = rng.gen();
+                    let rnd_byte: u8 = if int3 == Some(i) { 0xCC } else { rng.gen() };
                     instructions.push(rnd_byte);
+
+                    // The junk bytes are skipped over, so they're never executed.
+                    if rnd_byte == 0xCC {
+                        decoy_sites.push(key + i as u64);
+                    }
+                }
+            }
+            _ => {
+                patched = false;
+
+                if instruction.code() == Code::Int3 {
+                    // A run of int3s is padding if it can't be reached by falling through, and
+                    // ends where a function starts. The recursive traversal only decodes code it
+                    // reached, so those are always the program's own.
+                    let start = placement.starts.contains(&instruction.ip());
+
+                    if int3_end != Some(key) || start {
+                        in_padding = placement.reached.is_none()
+                            && after_exit
+                            && !start
+                            && section.data_ref().get(start_index + 1) == Some(&0xCC);
+                    }
+
+                    if in_padding {
+                        decoy_sites.push(key);
+                    } else {
+                        breakpoints.push(key);
+                    }
+                } else {
+                    // Immediates and displacements can contain 0xCC as well, which looks like an
+                    // int3 but is never executed as one.
+                    for (i, _) in instr_bytes.iter().enumerate().filter(|x| *x.1 == 0xCC) {
+                        decoy_sites.push(key + i as u64);
+                    }
                 }
             }
-            _ => patched = false,
         }
 
-        println!();
+        if listing {
+            println!();
+        }
 
         // The instruction was not patched, and needs to be added to the code buffer.
         if !patched {
             instructions.extend_from_slice(instr_bytes);
         }
 
+        int3_end = (instruction.code() == Code::Int3).then(|| key + 1);
+        after_exit = ends_flow(&instruction);
+
         // If there was a jcc, then add the jump entry to the jdt
         if let Some(entry) = jump_entry {
-            println!("key {:X}", instruction.ip() - section.base());
-            jump_entries.insert(instruction.ip() - section.base(), entry);
+            if listing {
+                println!("key {:X}", key);
+            }
+            jump_entries.insert(key, entry);
         }
     }
 
-    print_color("   [[ placing nanomites ]]\n", Color::Cyan);
+    let nanomites = jump_entries.len();
+    let decoys = add_decoys(&mut jump_entries, &decoy_sites, &boundaries, decoys, rng);
+
+    for key in breakpoints.iter() {
+        jump_entries.insert(*key, JumpData::new(JumpType::Passthrough, 0, 1));
+    }
+
+    if should_print(Verbosity::Normal) {
+        print_color(
+            &format!("[[ placing nanomites in {} ]]\n", section.name()),
+            Color::Cyan,
+        );
+
+        println!(
+            "section size: {}\nnanomite'd size: {}\nnanomites: {}\ndecoys: {}\nbreakpoints: {}",
+            section.data_ref().len(),
+            instructions.len(),
+            nanomites,
+            decoys,
+            breakpoints.len()
+        );
+
+        if placement.hot.is_some() {
+            println!("hot branches skipped: {}", skipped_hot);
+        }
+
+        if placement.density.is_limited() {
+            println!("branches skipped by density: {}", skipped_density);
+        }
+    }
+
+    // Each entry key is bound to the code around it, nanomites and junk bytes included.
+    let entries = RegionEntries::new(section.region(), &instructions, jump_entries);
+
+    (instructions, entries)
+}
+
+/// Returns the kind of branch an instruction is, if it's one that can be replaced with a nanomite.
+fn branch_kind(instr: &Instruction) -> Option<BranchKind> {
+    match instr.flow_control() {
+        FlowControl::ConditionalBranch => Some(BranchKind::Conditional),
+        FlowControl::UnconditionalBranch if instr.is_jmp_short_or_near() => Some(BranchKind::Jump),
+        // 16 bit calls and returns use a 16 bit return address, which the runtime doesn't emulate.
+        FlowControl::Call if matches!(instr.code(), Code::Call_rel32_32 | Code::Call_rel32_64) => {
+            Some(BranchKind::Call)
+        }
+        FlowControl::Return
+            if matches!(
+                instr.code(),
+                Code::Retnd | Code::Retnd_imm16 | Code::Retnq | Code::Retnq_imm16
+            ) =>
+        {
+            Some(BranchKind::Return)
+        }
+        _ => None,
+    }
+}
 
-    println!(
-        "section size: {}\nnanomite'd size: {}\njdt size: {}",
-        section.data_ref().len(),
-        instructions.len(),
-        jump_entries.len()
-    );
+/// Returns true if the 0xCC at `index` of the section is part of a run of them, `after_int3`
+/// being whether the previous byte was one. It's only used for bytes the recursive traversal
+/// never reached, where runs are padding between functions.
+fn is_padding(data: &[u8], index: usize, after_int3: bool) -> bool {
+    data.get(index) == Some(&0xCC) && (after_int3 || data.get(index + 1) == Some(&0xCC))
+}
 
-    (instructions, jump_entries)
+/// Returns true if execution never continues past the instruction to the next one.
+fn ends_flow(instruction: &Instruction) -> bool {
+    matches!(
+        instruction.flow_control(),
+        FlowControl::UnconditionalBranch
+            | FlowControl::IndirectBranch
+            | FlowControl::Return
+            | FlowControl::Exception
+    )
 }
 
 fn instr_to_jump_entry(instr: Instruction) -> JumpData {
-    let cc = instr.condition_code() as u8;
-    let jump_type: Option<JumpType> = FromPrimitive::from_u8(cc);
+    let jump_type = match instr.flow_control() {
+        FlowControl::UnconditionalBranch => JumpType::Jump,
+        FlowControl::Call => JumpType::Call,
+        FlowControl::Return => {
+            // ret imm16 releases extra bytes from the stack after popping the return address.
+            let release = if instr.op_count() == 1 {
+                instr.immediate16()
+            } else {
+                0
+            };
+
+            return JumpData::new(JumpType::Return, release as isize, instr.len());
+        }
+        _ => match counter_jump_type(&instr) {
+            Some(jump_type) => jump_type,
+            None => {
+                let cc = instr.condition_code() as u8;
+                let jump_type: Option<JumpType> = FromPrimitive::from_u8(cc);
+                jump_type.unwrap()
+            }
+        },
+    };
 
     let j_true = instr.near_branch_target() as i64 - instr.ip() as i64;
     let j_false = instr.len();
     assert_ne!(j_true, 0);
 
-    JumpData::new(jump_type.unwrap(), j_true as isize, j_false)
+    JumpData::new(jump_type, j_true as isize, j_false)
+}
+
+/// Maps `loop`, `loope`, `loopne` and `jcxz` to their jump type, which depends on the size of the
+/// counter register they use.
+fn counter_jump_type(instr: &Instruction) -> Option<JumpType> {
+    let jump_type = match instr.code() {
+        Code::Loop_rel8_16_CX | Code::Loop_rel8_32_CX => JumpType::Loop16,
+        Code::Loop_rel8_16_ECX | Code::Loop_rel8_32_ECX | Code::Loop_rel8_64_ECX => {
+            JumpType::Loop32
+        }
+        Code::Loop_rel8_16_RCX | Code::Loop_rel8_64_RCX => JumpType::Loop64,
+        Code::Loope_rel8_16_CX | Code::Loope_rel8_32_CX => JumpType::LoopEqual16,
+        Code::Loope_rel8_16_ECX | Code::Loope_rel8_32_ECX | Code::Loope_rel8_64_ECX => {
+            JumpType::LoopEqual32
+        }
+        Code::Loope_rel8_16_RCX | Code::Loope_rel8_64_RCX => JumpType::LoopEqual64,
+        Code::Loopne_rel8_16_CX | Code::Loopne_rel8_32_CX => JumpType::LoopNotEqual16,
+        Code::Loopne_rel8_16_ECX | Code::Loopne_rel8_32_ECX | Code::Loopne_rel8_64_ECX => {
+            JumpType::LoopNotEqual32
+        }
+        Code::Loopne_rel8_16_RCX | Code::Loopne_rel8_64_RCX => JumpType::LoopNotEqual64,
+        Code::Jcxz_rel8_16 | Code::Jcxz_rel8_32 => JumpType::JumpCxZero,
+        Code::Jecxz_rel8_16 | Code::Jecxz_rel8_32 | Code::Jecxz_rel8_64 => JumpType::JumpEcxZero,
+        Code::Jrcxz_rel8_16 | Code::Jrcxz_rel8_64 => JumpType::JumpRcxZero,
+        _ => return None,
+    };
+
+    Some(jump_type)
 }
@@ -0,0 +1,261 @@
+//! Prints the decrypted entries of a jump data table, and checks each one against the nanomite'd
+//! binary. Every entry must be in a code region, decrypt, and have an `int3` at it, or the
+//! command fails.
+
+use std::error::Error;
+use std::fs;
+
+use goblin::elf::program_header::PT_LOAD;
+use goblin::Object;
+use serde::Serialize;
+
+use common::container::{self, Arch};
+use common::jump_data_table::JumpDataTable;
+use common::payload;
+use common::JumpType;
+
+use crate::options::{InspectOptions, OutputFormat};
+
+/// A decrypted entry, with its displacements turned into link-time addresses.
+#[derive(Serialize)]
+struct Entry {
+    /// The key of the entry, relative to the image base.
+    offset: u64,
+    address: u64,
+
+    #[serde(rename = "type")]
+    jump_type: JumpType,
+
+    /// Where the branch goes when it's taken. Returns and passthrough entries have no target.
+    target: Option<u64>,
+
+    /// The address of the next instruction, where conditional branches go when they aren't taken,
+    /// and calls return to.
+    next: u64,
+
+    /// The length of the replaced instruction.
+    length: usize,
+
+    /// The number of bytes `ret` releases from the stack, after popping the return address.
+    release: Option<u64>,
+
+    /// Whether the nanomite'd binary has an `int3` at the entry. Decoys have one too.
+    int3: bool,
+}
+
+/// An entry that failed one of the checks.
+#[derive(Serialize)]
+struct Problem {
+    offset: u64,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct Report {
+    arch: &'static str,
+    cipher: &'static str,
+    features: u32,
+    infection: u64,
+    binary_hash: String,
+    base: u64,
+    entry: u64,
+    entries: Vec<Entry>,
+    problems: Vec<Problem>,
+}
+
+/// A loaded range of the image, and where it's stored in the file.
+struct Segment {
+    vaddr: u64,
+    size: u64,
+    file_offset: u64,
+}
+
+pub(crate) fn inspect(options: &InspectOptions) -> Result<(), Box<dyn Error>> {
+    let binary = fs::read(&options.binary)?;
+    let jdt = fs::read(&options.jdt)?;
+
+    let (header, binary, jdt) = container::unwrap_pair(&binary, &jdt)?;
+
+    let cipher = header.cipher.decryptor().ok_or_else(|| {
+        format!(
+            "the jump data table is encrypted with {}, which the infector was built without",
+            header.cipher.name()
+        )
+    })?;
+
+    let jdt = snap::raw::Decoder::new().decompress_vec(jdt)?;
+    let jdt: JumpDataTable = bincode::deserialize(&jdt)?;
+
+    let binary = payload::decrypt(cipher.as_ref(), &jdt.salt, &header, binary)?;
+    let binary = snap::raw::Decoder::new().decompress_vec(&binary)?;
+
+    let master = jdt.master_key(&binary);
+    let segments = segments(&binary)?;
+    let mut entries = Vec::with_capacity(jdt.table.len());
+    let mut problems = Vec::new();
+
+    for key in jdt.table.keys() {
+        let mut problem = |message: String| {
+            problems.push(Problem {
+                offset: *key,
+                message,
+            })
+        };
+
+        // The entry key is bound to the code around the nanomite, as it's stored in the file.
+        let code_hash = match jdt.region(*key).and_then(|x| {
+            let section = binary.get(x.file_offset as usize..)?;
+            x.window_hash(section, *key)
+        }) {
+            Some(code_hash) => code_hash,
+            None => {
+                problem("isn't in any code region".to_string());
+                continue;
+            }
+        };
+
+        let jump_data = match jdt.get_jump_data(*key, &code_hash, &master, cipher.as_ref()) {
+            Ok(jump_data) => jump_data,
+            Err(e) => {
+                problem(format!("can't be decrypted: {}", e));
+                continue;
+            }
+        };
+
+        let address = jdt.layout.base.wrapping_add(*key);
+        let int3 = segments
+            .iter()
+            .find(|x| address >= x.vaddr && address - x.vaddr < x.size)
+            .and_then(|x| binary.get((x.file_offset + address - x.vaddr) as usize))
+            == Some(&0xCC);
+
+        // Nanomites, the program's own breakpoints and decoys are all placed at an int3.
+        if !int3 {
+            problem(format!(
+                "is a {:?} entry, but there's no int3 at it",
+                jump_data.jump_type()
+            ));
+        }
+
+        let (target, release) = match jump_data.jump_type() {
+            JumpType::Return => (None, Some(jump_data.j_true() as u64)),
+            JumpType::Passthrough => (None, None),
+            _ => (Some(address.wrapping_add(jump_data.j_true() as u64)), None),
+        };
+
+        entries.push(Entry {
+            offset: *key,
+            address,
+            jump_type: jump_data.jump_type(),
+            target,
+            next: address.wrapping_add(jump_data.j_false() as u64),
+            length: jump_data.j_false(),
+            release,
+            int3,
+        });
+    }
+
+    let report = Report {
+        arch: match header.arch {
+            Arch::X86 => "x86",
+            Arch::X86_64 => "x86-64",
+        },
+        cipher: header.cipher.name(),
+        features: header.features,
+        infection: header.infection,
+        binary_hash: header
+            .binary_hash
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect(),
+        base: jdt.layout.base,
+        entry: jdt.layout.entry,
+        entries,
+        problems,
+    };
+
+    match options.format {
+        OutputFormat::Text => print_text(&report),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+
+    if !report.problems.is_empty() {
+        return Err(format!("{} entries failed the checks", report.problems.len()).into());
+    }
+
+    Ok(())
+}
+
+fn print_text(report: &Report) {
+    println!(
+        "infection 0x{:016X}, {}, {}, features 0x{:X}",
+        report.infection, report.arch, report.cipher, report.features
+    );
+    println!("original binary sha256 {}", report.binary_hash);
+    println!(
+        "image base 0x{:X}, entry point 0x{:X}\n",
+        report.base, report.entry
+    );
+
+    println!(
+        "{:<10} {:<18} {:<16} {:<18} {:<18} {:>6}  int3",
+        "offset", "address", "type", "target", "next", "length"
+    );
+
+    for entry in report.entries.iter() {
+        let target = match (entry.target, entry.release) {
+            (Some(target), _) => format!("0x{:X}", target),
+            (None, Some(release)) => format!("release {}", release),
+            (None, None) => "-".to_string(),
+        };
+
+        println!(
+            "{:<10} {:<18} {:<16} {:<18} {:<18} {:>6}  {}",
+            format!("0x{:X}", entry.offset),
+            format!("0x{:X}", entry.address),
+            format!("{:?}", entry.jump_type),
+            target,
+            format!("0x{:X}", entry.next),
+            entry.length,
+            if entry.int3 { "yes" } else { "no" }
+        );
+    }
+
+    println!(
+        "\n{} entries decrypted, {} failed the checks",
+        report.entries.len(),
+        report.problems.len()
+    );
+
+    for problem in report.problems.iter() {
+        println!("  0x{:X} {}", problem.offset, problem.message);
+    }
+}
+
+/// Returns the parts of the image that are loaded from the file.
+fn segments(binary: &[u8]) -> Result<Vec<Segment>, Box<dyn Error>> {
+    let segments = match Object::parse(binary)? {
+        Object::Elf(elf) => elf
+            .program_headers
+            .iter()
+            .filter(|x| x.p_type == PT_LOAD)
+            .map(|x| Segment {
+                vaddr: x.p_vaddr,
+                size: x.p_filesz,
+                file_offset: x.p_offset,
+            })
+            .collect(),
+        Object::PE(pe) => pe
+            .sections
+            .iter()
+            .map(|x| Segment {
+                vaddr: pe.image_base as u64 + x.virtual_address as u64,
+                size: x.size_of_raw_data as u64,
+                file_offset: x.pointer_to_raw_data as u64,
+            })
+            .collect(),
+        _ => return Err("the nanomite'd binary isn't a PE/ELF file".into()),
+    };
+
+    Ok(segments)
+}
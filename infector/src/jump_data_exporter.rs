@@ -1,17 +1,52 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
-use rand::{thread_rng, Rng};
+use rand::rngs::StdRng;
+use rand::Rng;
 
+use common::cipher::Cipher;
 use common::jump_data::JumpData;
-use common::jump_data_table::JumpDataTable;
-use common::RekkEncKey;
+use common::jump_data_table::{CodeRegion, ImageLayout, JumpDataTable};
+use common::keys::MasterKey;
 
-pub fn export_jdt(table: Vec<HashMap<u64, JumpData>>) -> Vec<u8> {
-    let mut master_jdt = HashMap::new();
+/// The entries placed in an infected section, each with the hash of the code its key is bound to.
+pub struct RegionEntries {
+    pub region: CodeRegion,
+    pub entries: BTreeMap<u64, (JumpData, [u8; 32])>,
+}
+
+impl RegionEntries {
+    /// Hashes the code around each entry. `code` is the content of the section, after the
+    /// nanomites were placed, exactly as the runtime will find it in memory.
+    pub fn new(region: CodeRegion, code: &[u8], entries: BTreeMap<u64, JumpData>) -> RegionEntries {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| (key, (value, region.window_hash(code, key).unwrap())))
+            .collect();
+
+        RegionEntries { region, entries }
+    }
+}
+
+/// Encrypts every entry with its own key, and serializes the table. The keys are derived from the
+/// `salt`, the nanomite'd `binary` and the code around each nanomite, so none of them can change
+/// after this. The output only depends on the entries, the binary, the salt and the state of
+/// `rng`.
+pub fn export_jdt(
+    table: Vec<RegionEntries>,
+    binary: &[u8],
+    layout: ImageLayout,
+    salt: [u8; 32],
+    cipher: &dyn Cipher,
+    rng: &mut StdRng,
+) -> Vec<u8> {
+    let mut master_jdt = BTreeMap::new();
+    let mut regions = Vec::with_capacity(table.len());
 
     // merge all the jdts into one "master" jdt
     for jdt in table {
-        for (key, value) in jdt.into_iter() {
+        regions.push(jdt.region);
+
+        for (key, value) in jdt.entries.into_iter() {
             // If we insert a duplicate, the old value is returned. Assert that there are no
             // duplicates, or we'll have a bad time.
             assert!(master_jdt.insert(key, value).is_none());
@@ -19,19 +54,23 @@ pub fn export_jdt(table: Vec<HashMap<u64, JumpData>>) -> Vec<u8> {
     }
 
     // Convert the JumpData to EncryptedJumpData
-    let mut encrypted_jdt = HashMap::new();
-    let mut rng = thread_rng();
+    let mut encrypted_jdt = BTreeMap::new();
 
-    let iv = rng.gen::<[u8; 16]>();
+    let master = MasterKey::new(&salt, binary);
 
-    for (key, value) in master_jdt.into_iter() {
-        let enc_key = rng.gen::<[u8; 32]>();
-        encrypted_jdt.insert(key, value.encrypt(RekkEncKey(enc_key), &iv));
+    for (key, (value, code_hash)) in master_jdt.into_iter() {
+        let nonce = rng.gen::<[u8; 12]>();
+        encrypted_jdt.insert(
+            key,
+            value.encrypt(cipher, &master.entry_key(key, &code_hash), nonce, key),
+        );
     }
 
     let jdt = JumpDataTable {
         table: encrypted_jdt,
-        iv,
+        salt,
+        layout,
+        regions,
     };
 
     let serialized_jdt = bincode::serialize(&jdt).unwrap();
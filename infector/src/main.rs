@@ -1,19 +1,46 @@
 use core::fmt;
-use std::env;
 use std::error::Error;
 use std::fs;
-use std::path::Path;
+use std::process;
 
 use goblin::Object;
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+use structopt::StructOpt;
+
+use common::cipher::{Cipher, CipherKind};
+use common::container::{self, Arch, Content, Feature, Header};
+use common::packed;
+use common::payload;
+use common::profile::Profile;
+use common::JumpType;
 
 use crate::binary_parser::*;
+use crate::density::Density;
+use crate::hot_branches::HotBranches;
+use crate::inspect::inspect;
 use crate::jump_data_exporter::export_jdt;
+use crate::options::{BranchKind, Cli, Command, InfectOptions, PackOptions};
+use crate::print_utils::{set_verbosity, should_print, Verbosity};
+use crate::upgrade::upgrade;
 
 mod binary_parser;
 mod code_section;
+mod decoys;
+mod density;
+mod eh_frame;
+mod functions;
+mod hot_branches;
 mod infestor;
+mod inspect;
 mod jump_data_exporter;
+mod markers;
+mod options;
 mod print_utils;
+mod relocations;
+mod seeds;
+mod traversal;
+mod upgrade;
 
 #[derive(Debug, Clone)]
 struct InvalidFileError;
@@ -26,38 +53,215 @@ impl fmt::Display for InvalidFileError {
 
 impl Error for InvalidFileError {}
 
-fn run() -> Result<(), Box<dyn Error>> {
-    for (i, arg) in env::args().enumerate() {
-        if i == 1 {
-            let path = Path::new(arg.as_str());
-            let data = fs::read(path)?;
-
-            let object = { Object::parse(&data) };
-
-            let mut data = data.to_vec();
-            let jdts;
-
-            if let Ok(Object::PE(pe)) = object {
-                jdts = Some(handle_pe(&mut data, pe));
-            } else if let Ok(Object::Elf(elf)) = object {
-                jdts = Some(handle_elf(&mut data, elf));
-            } else {
-                return Err(InvalidFileError.into());
-            }
-
-            // compress the binary.
-            let mut encoder = snap::raw::Encoder::new();
-            let compressed_binary = encoder.compress_vec(&data)?;
-
-            fs::write(Path::new("jdt.bin"), export_jdt(jdts.unwrap()))?;
-            fs::write(Path::new("nanomite.bin"), compressed_binary)?;
+fn infect(options: &InfectOptions) -> Result<(), Box<dyn Error>> {
+    let data = fs::read(&options.binary)?;
+    let binary_hash = container::hash_binary(&data);
+
+    let object = { Object::parse(&data) };
+    let hot = hot_branches(options)?;
+    let cipher = cipher(options.cipher)?;
+    let mut rng = seeded_rng(options.seed);
+    let density = Density::new(options.density, options.max_nanomites, rng.gen());
+
+    let mut data = data.to_vec();
+    let (jdts, layout) = match object {
+        Ok(Object::PE(pe)) => handle_pe(&mut data, pe, options, hot.as_ref(), density, &mut rng),
+        Ok(Object::Elf(elf)) => {
+            handle_elf(&mut data, elf, options, hot.as_ref(), density, &mut rng)
         }
+        _ => return Err(InvalidFileError.into()),
+    };
+
+    if options.dry_run {
+        if should_print(Verbosity::Normal) {
+            let nanomites: usize = jdts.iter().map(|x| x.entries.len()).sum();
+            println!("dry run: {} jdt entries, no files written", nanomites);
+        }
+
+        return Ok(());
     }
+
+    // compress the binary.
+    let mut encoder = snap::raw::Encoder::new();
+    let compressed_binary = encoder.compress_vec(&data)?;
+
+    let passthrough = jdts
+        .iter()
+        .flat_map(|x| x.entries.values())
+        .any(|(x, _)| x.jump_type() == JumpType::Passthrough);
+    let salt = rng.gen();
+    let jdt = export_jdt(jdts, &data, layout, salt, cipher.as_ref(), &mut rng);
+
+    // Both files get the same infection id, so the runtime can tell they belong together.
+    let infection = rng.gen();
+    let header = |content| Header {
+        content,
+        arch: Arch::from_bitness(layout.bitness).unwrap(),
+        features: features(options, passthrough),
+        infection,
+        binary_hash,
+        cipher: cipher.kind(),
+    };
+
+    // The binary is encrypted with a key derived from the table, so strings and decompression
+    // don't reveal it. Everything the key is derived from is stored in the packed executable.
+    let binary_payload = payload::encrypt(
+        cipher.as_ref(),
+        &salt,
+        &header(Content::Binary),
+        &compressed_binary,
+        rng.gen(),
+    );
+
+    fs::create_dir_all(&options.out_dir)?;
+    fs::write(
+        options.jdt_path(),
+        container::wrap(&header(Content::JumpDataTable), &jdt),
+    )?;
+    fs::write(
+        options.binary_path(),
+        container::wrap(&header(Content::Binary), &binary_payload),
+    )?;
+
+    if should_print(Verbosity::Normal) {
+        println!(
+            "wrote {} and {}",
+            options.jdt_path().display(),
+            options.binary_path().display()
+        );
+    }
+
     Ok(())
 }
 
+/// Returns the `Feature`s an infection with the given options uses, as a bit set.
+fn features(options: &InfectOptions, passthrough: bool) -> u32 {
+    let mut features = 0;
+
+    if options
+        .branches
+        .iter()
+        .any(|x| matches!(x, BranchKind::Call | BranchKind::Return))
+    {
+        features |= Feature::CallReturn as u32;
+    }
+
+    if passthrough {
+        features |= Feature::Passthrough as u32;
+    }
+
+    if options.decoys > 0 {
+        features |= Feature::Decoys as u32;
+    }
+
+    features
+}
+
+/// Reads the profile, if one was given, and picks the branches too hot to patch.
+fn hot_branches(options: &InfectOptions) -> Result<Option<HotBranches>, Box<dyn Error>> {
+    let path = match &options.profile {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let profile = Profile::read(path)?;
+
+    if options.max_hits.is_none() && options.hit_budget.is_none() {
+        eprintln!("warning: the profile has no effect without --max-hits or --hit-budget");
+    }
+
+    let hot = HotBranches::new(&profile, options.max_hits, options.hit_budget);
+
+    if should_print(Verbosity::Normal) {
+        println!(
+            "{} of {} profiled branches are too hot to patch",
+            hot.len(),
+            profile.hits.len()
+        );
+    }
+
+    Ok(Some(hot))
+}
+
+/// Returns the cipher to encrypt a jump data table with, defaulting to the fastest one available.
+fn cipher(kind: Option<CipherKind>) -> Result<Box<dyn Cipher>, Box<dyn Error>> {
+    let kind = kind
+        .or_else(CipherKind::best)
+        .ok_or("the infector was built without any cipher")?;
+
+    let cipher = kind.encryptor().ok_or_else(|| {
+        format!(
+            "the {} cipher isn't available, either the infector was built without it, or the \
+             CPU doesn't support it",
+            kind.name()
+        )
+    })?;
+
+    Ok(cipher)
+}
+
+/// Returns the generator every random choice of an infection is drawn from. The seed is printed
+/// when it was picked at random, so the infection can be reproduced.
+fn seeded_rng(seed: Option<u64>) -> StdRng {
+    let random = seed.is_none();
+    let seed = seed.unwrap_or_else(|| thread_rng().gen());
+
+    if random && should_print(Verbosity::Normal) {
+        println!("seed: {}", seed);
+    }
+
+    StdRng::seed_from_u64(seed)
+}
+
+fn pack(options: &PackOptions) -> Result<(), Box<dyn Error>> {
+    let stub = fs::read(&options.stub)?;
+    let binary = fs::read(&options.binary)?;
+    let jdt = fs::read(&options.jdt)?;
+
+    // Catch mismatched or corrupt files now, rather than when the protected binary is run.
+    container::unwrap_pair(&binary, &jdt).map_err(|e| {
+        format!(
+            "couldn't pack {} and {}: {}",
+            options.binary.display(),
+            options.jdt.display(),
+            e
+        )
+    })?;
+
+    fs::write(&options.output, packed::pack(&stub, &binary, &jdt))?;
+
+    // Keep the stub's permissions, so the protected binary is executable.
+    fs::set_permissions(&options.output, fs::metadata(&options.stub)?.permissions())?;
+
+    if should_print(Verbosity::Normal) {
+        println!("wrote {}", options.output.display());
+    }
+
+    Ok(())
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::from_args();
+
+    set_verbosity(if cli.quiet {
+        Verbosity::Quiet
+    } else if cli.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    });
+
+    match cli.command {
+        Command::Infect(ref options) => infect(options),
+        Command::Pack(ref options) => pack(options),
+        Command::Inspect(ref options) => inspect(options),
+        Command::Upgrade(ref options) => upgrade(options),
+    }
+}
+
 fn main() {
     if let Err(e) = run() {
-        println!("error: {}", e);
+        eprintln!("error: {}", e);
+        process::exit(1);
     }
 }
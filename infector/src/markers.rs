@@ -0,0 +1,79 @@
+//! Finds the functions marked for protection with the `rekk-marker` crate or `rekk.h`.
+
+use std::convert::TryInto;
+use std::ops::Range;
+
+use goblin::elf::Elf;
+use goblin::pe::PE;
+
+use crate::binary_parser::pe_section_name;
+
+/// The name MSVC's linker gives the marker section, as image section names are limited to 8
+/// bytes.
+const TRUNCATED_SECTION: &str = ".rekk.pr";
+
+/// The marker section of a binary.
+pub(crate) struct Markers {
+    /// The start of every marked function.
+    pub functions: Vec<u64>,
+
+    /// Where the markers are in the file.
+    pub file_range: Range<usize>,
+}
+
+/// Each marker is the offset from the marker to the start of the function.
+fn parse(contents: &[u8], vaddr: u64) -> Vec<u64> {
+    contents
+        .chunks_exact(4)
+        .enumerate()
+        .map(|(i, x)| {
+            let offset = i32::from_le_bytes(x.try_into().unwrap());
+            (vaddr + i as u64 * 4).wrapping_add(offset as i64 as u64)
+        })
+        .collect()
+}
+
+pub(crate) fn elf_markers(elf: &Elf, data: &[u8]) -> Option<Markers> {
+    let header = elf.section_headers.iter().find(|x| {
+        matches!(elf.shdr_strtab.get(x.sh_name), Some(Ok(name)) if name == rekk_marker::SECTION)
+    })?;
+
+    let file_range = header.sh_offset as usize..(header.sh_offset + header.sh_size) as usize;
+
+    Some(Markers {
+        functions: parse(data.get(file_range.clone())?, header.sh_addr),
+        file_range,
+    })
+}
+
+pub(crate) fn pe_markers(pe: &PE, data: &[u8]) -> Option<Markers> {
+    let section = pe.sections.iter().find(|x| {
+        let name = pe_section_name(x);
+        name == rekk_marker::SECTION || name == TRUNCATED_SECTION
+    })?;
+
+    // The raw data is padded to the file alignment, the virtual size is the real size.
+    let size = section.virtual_size.min(section.size_of_raw_data) as usize;
+    let start = section.pointer_to_raw_data as usize;
+    let file_range = start..start + size;
+
+    Some(Markers {
+        functions: parse(
+            data.get(file_range.clone())?,
+            pe.image_base as u64 + section.virtual_address as u64,
+        ),
+        file_range,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::markers::parse;
+
+    #[test]
+    fn relative_offsets() {
+        let contents = [0xF0, 0xFF, 0xFF, 0xFF, 0x00, 0x01, 0x00, 0x00];
+
+        assert_eq!(parse(&contents, 0x2000), vec![0x1FF0, 0x2104]);
+    }
+}
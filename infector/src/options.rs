@@ -0,0 +1,350 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use structopt::StructOpt;
+
+use common::cipher::CipherKind;
+
+/// Places nanomites in PE/ELF binaries.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "infector")]
+pub(crate) struct Cli {
+    /// Only print errors.
+    #[structopt(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Print the disassembly of every infected section.
+    #[structopt(short, long, global = true)]
+    pub verbose: bool,
+
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) enum Command {
+    /// Infect a binary with nanomites, and export the nanomite'd binary and jump data table.
+    Infect(InfectOptions),
+
+    /// Append a nanomite'd binary and jump data table to a copy of the runtime stub.
+    Pack(PackOptions),
+
+    /// Print the entries of a jump data table, and check them against the nanomite'd binary.
+    Inspect(InspectOptions),
+
+    /// Convert a nanomite'd binary and jump data table written by the first infector, without a
+    /// header, to the current format.
+    Upgrade(UpgradeOptions),
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct InfectOptions {
+    /// The PE/ELF binary to infect.
+    #[structopt(parse(from_os_str))]
+    pub binary: PathBuf,
+
+    /// Directory to write the nanomite'd binary and jump data table to.
+    #[structopt(short, long, default_value = ".", parse(from_os_str))]
+    pub out_dir: PathBuf,
+
+    /// Prefix prepended to the names of the output files.
+    #[structopt(short, long, default_value = "")]
+    pub prefix: String,
+
+    /// Comma separated list of sections to infect. Defaults to every executable section.
+    #[structopt(short, long, use_delimiter = true)]
+    pub sections: Vec<String>,
+
+    /// Disassemble and place nanomites, but don't write any output files.
+    #[structopt(short = "n", long)]
+    pub dry_run: bool,
+
+    /// Comma separated list of the kinds of branches to replace with nanomites.
+    #[structopt(
+        short,
+        long,
+        use_delimiter = true,
+        default_value = "jcc",
+        possible_values = &BranchKind::NAMES
+    )]
+    pub branches: Vec<BranchKind>,
+
+    /// How to find the instructions of each section. `recursive` follows the control flow from
+    /// the known function starts, so data embedded in the code is never patched.
+    #[structopt(
+        short = "D",
+        long,
+        default_value = "linear",
+        possible_values = &Disassembly::NAMES
+    )]
+    pub disassembly: Disassembly,
+
+    /// Comma separated list of functions to place nanomites in. Accepts `*` and `?` wildcards.
+    /// Defaults to every function.
+    #[structopt(short, long, use_delimiter = true)]
+    pub functions: Vec<String>,
+
+    /// Comma separated list of functions to never place nanomites in. Accepts `*` and `?`
+    /// wildcards.
+    #[structopt(short = "x", long, use_delimiter = true)]
+    pub exclude_functions: Vec<String>,
+
+    /// Zero the `.rekk.protect` markers after placing nanomites, so the protected functions can't
+    /// be found from them.
+    #[structopt(long)]
+    pub strip_markers: bool,
+
+    /// Number of decoy entries to add to the jump data table. Decoys are placed at 0xCC bytes that
+    /// are never executed, and look like real entries.
+    #[structopt(short, long, default_value = "100")]
+    pub decoys: usize,
+
+    /// A profile recorded by running a stub built with the `profile` feature. Branches it
+    /// shows are hit too often, according to `--max-hits` and `--hit-budget`, aren't patched.
+    #[structopt(long, parse(from_os_str))]
+    pub profile: Option<PathBuf>,
+
+    /// Don't patch branches the profile shows were hit more than this many times.
+    #[structopt(long, requires = "profile")]
+    pub max_hits: Option<u64>,
+
+    /// The total number of nanomite hits to allow, going by the profile. The least hit branches
+    /// are patched until their hits add up to the budget.
+    #[structopt(long, requires = "profile")]
+    pub hit_budget: Option<u64>,
+
+    /// The fraction of the branches to replace with nanomites, from 0 to 1. The branches are
+    /// picked at random, according to `--seed`.
+    #[structopt(long, default_value = "1", parse(try_from_str = parse_density))]
+    pub density: f64,
+
+    /// The maximum number of nanomites to place. They're picked at random out of every selected
+    /// branch, so they're spread over the whole image.
+    #[structopt(long)]
+    pub max_nanomites: Option<usize>,
+
+    /// Seeds every random choice, from the branches picked by `--density` to the junk bytes,
+    /// decoys, key salt and nonces. Infecting the same binary with the same options and seed
+    /// writes identical files. Defaults to a random seed.
+    #[structopt(long, env = "REKK_SEED")]
+    pub seed: Option<u64>,
+
+    /// The cipher to encrypt the jump data table with: aesni, aes-soft or chacha20. Defaults to
+    /// AES-NI if the CPU supports it. Runtimes built without the cipher can't run the infection.
+    #[structopt(long, parse(try_from_str = parse_cipher))]
+    pub cipher: Option<CipherKind>,
+}
+
+fn parse_cipher(s: &str) -> Result<CipherKind, String> {
+    CipherKind::from_name(s).ok_or_else(|| {
+        let names: Vec<&str> = CipherKind::ALL.iter().map(|x| x.name()).collect();
+        format!("unknown cipher {}, expected one of {}", s, names.join(", "))
+    })
+}
+
+fn parse_density(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(density) if (0.0..=1.0).contains(&density) => Ok(density),
+        Ok(_) => Err("the density must be between 0 and 1".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// The kinds of branch instructions that can be replaced with nanomites.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum BranchKind {
+    /// Conditional jumps, such as `je` and `jne`.
+    Conditional,
+
+    /// Direct `jmp`.
+    Jump,
+
+    /// Direct near `call`.
+    Call,
+
+    /// Near `ret`.
+    Return,
+}
+
+impl BranchKind {
+    const NAMES: [&'static str; 4] = ["jcc", "jmp", "call", "ret"];
+}
+
+#[derive(Debug)]
+pub(crate) struct InvalidBranchKindError(String);
+
+impl fmt::Display for InvalidBranchKindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown branch kind {}", self.0)
+    }
+}
+
+impl FromStr for BranchKind {
+    type Err = InvalidBranchKindError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jcc" => Ok(BranchKind::Conditional),
+            "jmp" => Ok(BranchKind::Jump),
+            "call" => Ok(BranchKind::Call),
+            "ret" => Ok(BranchKind::Return),
+            _ => Err(InvalidBranchKindError(s.to_string())),
+        }
+    }
+}
+
+/// How the infector finds the instructions to patch.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Disassembly {
+    /// Decode every byte of each section, in order.
+    Linear,
+
+    /// Only decode instructions reachable from the entry point, symbols and other function
+    /// starts.
+    Recursive,
+}
+
+impl Disassembly {
+    const NAMES: [&'static str; 2] = ["linear", "recursive"];
+}
+
+#[derive(Debug)]
+pub(crate) struct InvalidDisassemblyError(String);
+
+impl fmt::Display for InvalidDisassemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown disassembly mode {}", self.0)
+    }
+}
+
+impl FromStr for Disassembly {
+    type Err = InvalidDisassemblyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Disassembly::Linear),
+            "recursive" => Ok(Disassembly::Recursive),
+            _ => Err(InvalidDisassemblyError(s.to_string())),
+        }
+    }
+}
+
+impl InfectOptions {
+    /// Returns true if the section with the given name should be infected.
+    pub fn is_section_selected(&self, name: &str) -> bool {
+        self.sections.is_empty() || self.sections.iter().any(|x| x == name)
+    }
+
+    /// Returns true if nanomites should only be placed in some functions.
+    pub fn selects_functions(&self) -> bool {
+        !self.functions.is_empty() || !self.exclude_functions.is_empty()
+    }
+
+    pub fn jdt_path(&self) -> PathBuf {
+        self.out_dir.join(format!("{}jdt.bin", self.prefix))
+    }
+
+    pub fn binary_path(&self) -> PathBuf {
+        self.out_dir.join(format!("{}nanomite.bin", self.prefix))
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct PackOptions {
+    /// The prebuilt runtime stub.
+    #[structopt(long, parse(from_os_str))]
+    pub stub: PathBuf,
+
+    /// The compressed nanomite'd binary produced by `infect`.
+    #[structopt(long, default_value = "nanomite.bin", parse(from_os_str))]
+    pub binary: PathBuf,
+
+    /// The jump data table produced by `infect`.
+    #[structopt(long, default_value = "jdt.bin", parse(from_os_str))]
+    pub jdt: PathBuf,
+
+    /// Where to write the protected binary.
+    #[structopt(short, long, parse(from_os_str))]
+    pub output: PathBuf,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct InspectOptions {
+    /// The compressed nanomite'd binary produced by `infect`.
+    #[structopt(long, default_value = "nanomite.bin", parse(from_os_str))]
+    pub binary: PathBuf,
+
+    /// The jump data table produced by `infect`.
+    #[structopt(long, default_value = "jdt.bin", parse(from_os_str))]
+    pub jdt: PathBuf,
+
+    /// How to print the entries.
+    #[structopt(
+        short,
+        long,
+        default_value = "text",
+        possible_values = &OutputFormat::NAMES
+    )]
+    pub format: OutputFormat,
+}
+
+#[derive(StructOpt, Debug)]
+pub(crate) struct UpgradeOptions {
+    /// The compressed nanomite'd binary produced by `infect`.
+    #[structopt(long, default_value = "nanomite.bin", parse(from_os_str))]
+    pub binary: PathBuf,
+
+    /// The jump data table produced by `infect`.
+    #[structopt(long, default_value = "jdt.bin", parse(from_os_str))]
+    pub jdt: PathBuf,
+
+    /// Directory to write the upgraded nanomite'd binary and jump data table to. Defaults to the
+    /// current directory, replacing the old files.
+    #[structopt(short, long, default_value = ".", parse(from_os_str))]
+    pub out_dir: PathBuf,
+
+    /// Seeds the new key salt and nonces. Defaults to a random seed.
+    #[structopt(long, env = "REKK_SEED")]
+    pub seed: Option<u64>,
+
+    /// The cipher to encrypt the jump data table with: aesni, aes-soft or chacha20. Defaults to
+    /// AES-NI if the CPU supports it. Runtimes built without the cipher can't run the infection.
+    #[structopt(long, parse(try_from_str = parse_cipher))]
+    pub cipher: Option<CipherKind>,
+}
+
+/// How `inspect` prints the jump data table.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum OutputFormat {
+    /// A table, for people.
+    Text,
+
+    /// A JSON object, for scripts.
+    Json,
+}
+
+impl OutputFormat {
+    const NAMES: [&'static str; 2] = ["text", "json"];
+}
+
+#[derive(Debug)]
+pub(crate) struct InvalidOutputFormatError(String);
+
+impl fmt::Display for InvalidOutputFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown output format {}", self.0)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = InvalidOutputFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(InvalidOutputFormatError(s.to_string())),
+        }
+    }
+}
@@ -1,7 +1,32 @@
 use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
 
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
+/// How much the infector prints to the console.
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+pub(crate) enum Verbosity {
+    /// Only errors are printed.
+    Quiet = 0,
+
+    /// A summary of each infected section is printed.
+    Normal = 1,
+
+    /// The disassembly of each infected section is printed as well.
+    Verbose = 2,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+pub(crate) fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+/// Returns true if messages of the given verbosity should be printed.
+pub(crate) fn should_print(verbosity: Verbosity) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= verbosity as u8
+}
+
 pub(crate) fn print_color(text: &str, color: Color) {
     let mut stdout = StandardStream::stdout(ColorChoice::Always);
     stdout
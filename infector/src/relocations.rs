@@ -0,0 +1,141 @@
+//! Finds the base relocations of a PE image. The loader patches these bytes whenever the image
+//! isn't loaded at its preferred base, so they're left out of the code entry keys are bound to.
+
+use std::convert::TryInto;
+use std::ops::Range;
+
+use goblin::pe::PE;
+
+/// Relocation types, stored in the top 4 bits of each entry of a block.
+const IMAGE_REL_BASED_HIGH: u16 = 1;
+const IMAGE_REL_BASED_LOW: u16 = 2;
+const IMAGE_REL_BASED_HIGHLOW: u16 = 3;
+const IMAGE_REL_BASED_HIGHADJ: u16 = 4;
+const IMAGE_REL_BASED_DIR64: u16 = 10;
+
+/// Each block starts with the RVA of its page and its size, followed by 2 byte entries.
+const BLOCK_HEADER_SIZE: usize = 8;
+
+/// Parses the content of the base relocation directory. Returns the RVAs of the relocated bytes,
+/// sorted.
+fn parse(mut contents: &[u8]) -> Vec<Range<u64>> {
+    let mut relocations = Vec::new();
+
+    while contents.len() >= BLOCK_HEADER_SIZE {
+        let page = u32::from_le_bytes(contents[0..4].try_into().unwrap()) as u64;
+        let size = u32::from_le_bytes(contents[4..8].try_into().unwrap()) as usize;
+
+        if size < BLOCK_HEADER_SIZE || size > contents.len() {
+            break;
+        }
+
+        let mut entries = contents[BLOCK_HEADER_SIZE..size]
+            .chunks_exact(2)
+            .map(|x| u16::from_le_bytes(x.try_into().unwrap()));
+
+        while let Some(entry) = entries.next() {
+            let rva = page + (entry & 0xFFF) as u64;
+
+            let length = match entry >> 12 {
+                IMAGE_REL_BASED_HIGH | IMAGE_REL_BASED_LOW => 2,
+                IMAGE_REL_BASED_HIGHLOW => 4,
+                IMAGE_REL_BASED_DIR64 => 8,
+                IMAGE_REL_BASED_HIGHADJ => {
+                    // The low half of the adjusted value takes up the next entry.
+                    entries.next();
+                    2
+                }
+                // IMAGE_REL_BASED_ABSOLUTE only pads the block to a 4 byte boundary. The other
+                // types are for other architectures.
+                _ => continue,
+            };
+
+            relocations.push(rva..rva + length);
+        }
+
+        contents = &contents[size..];
+    }
+
+    relocations.sort_by_key(|x| x.start);
+    relocations
+}
+
+/// Returns the RVAs of the bytes the loader relocates, sorted. Images without a base relocation
+/// directory are always loaded at their preferred base.
+pub(crate) fn pe_relocations(pe: &PE, data: &[u8]) -> Vec<Range<u64>> {
+    let directory = match pe
+        .header
+        .optional_header
+        .and_then(|x| *x.data_directories.get_base_relocation_table())
+    {
+        Some(directory) => directory,
+        None => return Vec::new(),
+    };
+
+    let rva = directory.virtual_address as u64;
+    let section = pe.sections.iter().find(|x| {
+        rva >= x.virtual_address as u64 && rva < x.virtual_address as u64 + x.virtual_size as u64
+    });
+
+    let start = match section {
+        Some(x) => (rva - x.virtual_address as u64 + x.pointer_to_raw_data as u64) as usize,
+        None => return Vec::new(),
+    };
+
+    match data.get(start..start + directory.size as usize) {
+        Some(contents) => parse(contents),
+        None => Vec::new(),
+    }
+}
+
+/// Returns the relocations inside the section at `start`, with `size` bytes.
+pub(crate) fn in_section(relocations: &[Range<u64>], start: u64, size: u64) -> Vec<Range<u64>> {
+    relocations
+        .iter()
+        .filter(|x| x.end > start && x.start < start + size)
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::relocations::{in_section, parse};
+
+    fn block(page: u32, entries: &[u16]) -> Vec<u8> {
+        let mut block = page.to_le_bytes().to_vec();
+        block.extend_from_slice(&(8 + entries.len() as u32 * 2).to_le_bytes());
+        block.extend(entries.iter().flat_map(|x| x.to_le_bytes().to_vec()));
+        block
+    }
+
+    #[test]
+    fn parses_blocks() {
+        let mut contents = block(0x2000, &[0xA010, 0x3008, 0x0000]);
+        contents.extend(block(0x1000, &[0x4FFC, 0x1234, 0x2002]));
+        // A block claiming to be larger than the directory ends it.
+        contents.extend(block(0x3000, &[0xA000]));
+        let end = contents.len();
+        contents[end - 6..end - 2].copy_from_slice(&0x100u32.to_le_bytes());
+
+        assert_eq!(
+            parse(&contents),
+            vec![
+                0x1002..0x1004,
+                0x1FFC..0x1FFE,
+                0x2008..0x200C,
+                0x2010..0x2018,
+            ]
+        );
+    }
+
+    #[test]
+    fn filters_by_section() {
+        let relocations = vec![0x0FFE..0x1002, 0x1010..0x1018, 0x2000..0x2004];
+
+        assert_eq!(
+            in_section(&relocations, 0x1000, 0x1000),
+            vec![0x0FFE..0x1002, 0x1010..0x1018]
+        );
+        assert!(in_section(&relocations, 0x3000, 0x1000).is_empty());
+    }
+}
@@ -0,0 +1,67 @@
+//! The known function starts of an image, used to seed the recursive traversal.
+
+use std::convert::TryInto;
+
+use goblin::elf::Elf;
+use goblin::pe::PE;
+
+use crate::functions::{elf_functions, pe_functions};
+
+/// Sections holding arrays of constructor and destructor pointers.
+const ELF_INIT_ARRAYS: [&str; 3] = [".preinit_array", ".init_array", ".fini_array"];
+
+/// Returns the entry point, functions, initializers and finalizers of an ELF binary.
+pub(crate) fn elf_seeds(elf: &Elf, data: &[u8]) -> Vec<u64> {
+    let pointer_size = if elf.is_64 { 8 } else { 4 };
+    let mut seeds = vec![elf.entry];
+
+    seeds.extend(elf_functions(elf, data).iter().map(|x| x.start));
+
+    if let Some(dynamic) = &elf.dynamic {
+        seeds.extend(
+            [dynamic.info.init, dynamic.info.fini]
+                .iter()
+                .filter(|x| **x != 0),
+        );
+    }
+
+    let init_arrays = elf.section_headers.iter().filter(|x| {
+        matches!(elf.shdr_strtab.get(x.sh_name), Some(Ok(name)) if ELF_INIT_ARRAYS.contains(&name))
+    });
+
+    for header in init_arrays {
+        let start = header.sh_offset as usize;
+        let contents = match data.get(start..start + header.sh_size as usize) {
+            Some(contents) => contents,
+            None => continue,
+        };
+
+        // Position independent executables relocate these at load time, so only the pointers
+        // of fixed address executables are useful here.
+        seeds.extend(
+            contents
+                .chunks_exact(pointer_size)
+                .map(|x| read_pointer(x, pointer_size)),
+        );
+    }
+
+    seeds
+}
+
+/// Returns the entry point and functions of a PE binary.
+pub(crate) fn pe_seeds(pe: &PE, data: &[u8]) -> Vec<u64> {
+    let image_base = pe.image_base as u64;
+    let mut seeds = vec![image_base + pe.entry as u64];
+
+    seeds.extend(pe_functions(pe, data).iter().map(|x| x.start));
+
+    seeds
+}
+
+fn read_pointer(bytes: &[u8], pointer_size: usize) -> u64 {
+    if pointer_size == 4 {
+        u32::from_le_bytes(bytes.try_into().unwrap()) as u64
+    } else {
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+}
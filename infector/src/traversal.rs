@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+
+use iced_x86::{Code, Decoder, DecoderOptions, FlowControl, Instruction, OpKind};
+
+/// An executable part of the image, such as a section.
+pub(crate) struct CodeRegion<'a> {
+    pub vaddr: u64,
+    pub data: &'a [u8],
+}
+
+impl<'a> CodeRegion<'a> {
+    fn contains(&self, vaddr: u64) -> bool {
+        vaddr >= self.vaddr && vaddr - self.vaddr < self.data.len() as u64
+    }
+}
+
+/// The instructions reached by a recursive traversal of the code, starting from known function
+/// starts. Unlike a linear sweep, data embedded in the code is never decoded as instructions.
+pub(crate) struct ReachedCode {
+    /// The length of every reached instruction, by virtual address.
+    instructions: BTreeMap<u64, usize>,
+}
+
+impl ReachedCode {
+    /// Follows the control flow from every seed. Calls are assumed to return, but indirect
+    /// branches aren't followed, as their targets aren't known.
+    pub fn traverse<I: IntoIterator<Item = u64>>(
+        regions: &[CodeRegion],
+        bitness: u32,
+        seeds: I,
+    ) -> Self {
+        let mut instructions = BTreeMap::new();
+        let mut pending: Vec<u64> = seeds.into_iter().collect();
+        let mut instruction = Instruction::default();
+
+        while let Some(start) = pending.pop() {
+            let region = match regions.iter().find(|x| x.contains(start)) {
+                Some(region) => region,
+                None => continue,
+            };
+
+            let offset = (start - region.vaddr) as usize;
+            let mut decoder = Decoder::new(bitness, &region.data[offset..], DecoderOptions::NONE);
+            decoder.set_ip(start);
+
+            while decoder.can_decode() && !instructions.contains_key(&decoder.ip()) {
+                decoder.decode_out(&mut instruction);
+
+                if instruction.is_invalid() {
+                    break;
+                }
+
+                instructions.insert(instruction.ip(), instruction.len());
+
+                let falls_through = match instruction.flow_control() {
+                    FlowControl::Next | FlowControl::IndirectCall => true,
+                    FlowControl::ConditionalBranch
+                    | FlowControl::Call
+                    | FlowControl::XbeginXabortXend => {
+                        pending.extend(direct_target(&instruction));
+                        true
+                    }
+                    FlowControl::UnconditionalBranch => {
+                        pending.extend(direct_target(&instruction));
+                        false
+                    }
+                    // int3 and int1 are used to trap, or as padding.
+                    FlowControl::Interrupt => {
+                        !matches!(instruction.code(), Code::Int3 | Code::Int1)
+                    }
+                    FlowControl::IndirectBranch | FlowControl::Return | FlowControl::Exception => {
+                        false
+                    }
+                };
+
+                if !falls_through {
+                    break;
+                }
+            }
+        }
+
+        ReachedCode { instructions }
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    /// Returns the address of the first reached instruction at or after `vaddr`.
+    pub fn next_instruction(&self, vaddr: u64) -> Option<u64> {
+        self.instructions.range(vaddr..).next().map(|x| *x.0)
+    }
+
+    /// Returns true if the instruction at `vaddr` doesn't overlap any other reached instruction,
+    /// so it's safe to overwrite.
+    pub fn is_patchable(&self, vaddr: u64, len: usize) -> bool {
+        self.instructions.get(&vaddr) == Some(&len)
+            && self
+                .instructions
+                .range(vaddr + 1..vaddr + len as u64)
+                .next()
+                .is_none()
+            && self
+                .instructions
+                .range(..vaddr)
+                .next_back()
+                .is_none_or(|(start, len)| start + *len as u64 <= vaddr)
+    }
+}
+
+/// Returns the target of a direct branch.
+fn direct_target(instruction: &Instruction) -> Option<u64> {
+    match instruction.op0_kind() {
+        OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64 => {
+            Some(instruction.near_branch_target())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::traversal::{CodeRegion, ReachedCode};
+
+    #[test]
+    fn skips_inline_data() {
+        #[rustfmt::skip]
+        let code = [
+            // 0x1000: je 0x1006
+            0x74, 0x04,
+            // 0x1002: jmp 0x1007
+            0xEB, 0x03,
+            // 0x1004: data, which decodes as a jcc that overlaps the next instruction.
+            0x0F, 0x84,
+            // 0x1006: ret
+            0xC3,
+            // 0x1007: nop, ret
+            0x90, 0xC3,
+            // 0x1009: unreachable
+            0x75, 0x00,
+        ];
+        let regions = [CodeRegion {
+            vaddr: 0x1000,
+            data: &code,
+        }];
+
+        let reached = ReachedCode::traverse(&regions, 64, vec![0x1000]);
+
+        assert_eq!(reached.len(), 5);
+        assert_eq!(reached.next_instruction(0x1003), Some(0x1006));
+        assert_eq!(reached.next_instruction(0x1009), None);
+        assert!(reached.is_patchable(0x1000, 2));
+        assert!(!reached.is_patchable(0x1004, 6));
+    }
+}
@@ -0,0 +1,243 @@
+//! Converts the files of an infection written by the first infector to the current format, so
+//! existing infections keep working without infecting the original binary again.
+//!
+//! The first infector only compressed both files, without a header, and didn't record the layout
+//! of the image, which is read from the nanomite'd binary instead. The code of the nanomite'd
+//! binary is kept as is, and encrypted. The entries of the jump data table are decrypted, and
+//! encrypted again like `infect` would, with the given cipher, and bound to the code around their
+//! nanomite.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+
+use aes::Aes256;
+use block_modes::block_padding::Pkcs7;
+use block_modes::{BlockMode, Cbc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use common::container::{self, Arch, ContainerError, Content, Header, MAGIC, VERSION};
+use common::jump_data::JumpData;
+use common::payload;
+
+use crate::binary_parser::{code_regions, image_layout};
+use crate::jump_data_exporter::{export_jdt, RegionEntries};
+use crate::options::UpgradeOptions;
+use crate::print_utils::{should_print, Verbosity};
+use crate::{cipher, seeded_rng};
+
+type Aes256Cbc = Cbc<Aes256, Pkcs7>;
+
+/// The longest an x86 instruction can be.
+const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+/// A table written by the first infector. Every entry was encrypted with AES-256-CBC, with a
+/// random key and a shared IV.
+#[derive(Serialize, Deserialize)]
+struct TableV0 {
+    table: BTreeMap<u64, EntryV0>,
+    iv: [u8; 16],
+}
+
+/// An entry of a table written by the first infector, stored next to its key.
+#[derive(Serialize, Deserialize)]
+struct EntryV0 {
+    key: [u8; 32],
+    data: Vec<u8>,
+}
+
+pub(crate) fn upgrade(options: &UpgradeOptions) -> Result<(), Box<dyn Error>> {
+    let binary = fs::read(&options.binary)?;
+    let jdt = fs::read(&options.jdt)?;
+
+    if jdt.starts_with(&MAGIC) {
+        container::unwrap(&jdt, Content::JumpDataTable)?;
+        return Err(format!("{} is already version {}", options.jdt.display(), VERSION).into());
+    }
+
+    if binary.starts_with(&MAGIC) {
+        return Err(ContainerError::Mismatch.into());
+    }
+
+    let not_compressed = |name| {
+        format!(
+            "the {} is neither a rekk container, nor compressed by the first infector",
+            name
+        )
+    };
+
+    let decompressed_binary = snap::raw::Decoder::new()
+        .decompress_vec(&binary)
+        .map_err(|_| not_compressed(Content::Binary))?;
+    let decompressed_jdt = snap::raw::Decoder::new()
+        .decompress_vec(&jdt)
+        .map_err(|_| not_compressed(Content::JumpDataTable))?;
+
+    let cipher = cipher(options.cipher)?;
+    let mut rng = seeded_rng(options.seed);
+
+    let entries = headerless_entries(&decompressed_jdt)?;
+    let layout = image_layout(&decompressed_binary)?;
+
+    let count = entries.len();
+    let regions = region_entries(&decompressed_binary, entries)?;
+
+    // The original binary isn't known, so the nanomite'd one stands in for it.
+    let infection = rng.gen();
+    let header = |content| Header {
+        content,
+        arch: Arch::from_bitness(layout.bitness).unwrap(),
+        features: 0,
+        infection,
+        binary_hash: container::hash_binary(&decompressed_binary),
+        cipher: cipher.kind(),
+    };
+    let jdt_header = header(Content::JumpDataTable);
+    let binary_header = header(Content::Binary);
+
+    let salt = rng.gen();
+    let jdt = export_jdt(
+        regions,
+        &decompressed_binary,
+        layout,
+        salt,
+        cipher.as_ref(),
+        &mut rng,
+    );
+
+    let binary_payload =
+        payload::encrypt(cipher.as_ref(), &salt, &binary_header, &binary, rng.gen());
+
+    let jdt_path = options.out_dir.join("jdt.bin");
+    let binary_path = options.out_dir.join("nanomite.bin");
+
+    fs::create_dir_all(&options.out_dir)?;
+    fs::write(&jdt_path, container::wrap(&jdt_header, &jdt))?;
+    fs::write(
+        &binary_path,
+        container::wrap(&binary_header, &binary_payload),
+    )?;
+
+    if should_print(Verbosity::Normal) {
+        println!(
+            "upgraded {} entries from the headerless format to version {}",
+            count, VERSION
+        );
+        println!("wrote {} and {}", jdt_path.display(), binary_path.display());
+    }
+
+    Ok(())
+}
+
+/// Decrypts every entry of a table written by the first infector.
+fn headerless_entries(jdt: &[u8]) -> Result<BTreeMap<u64, JumpData>, Box<dyn Error>> {
+    let jdt: TableV0 = bincode::deserialize(jdt)?;
+    let mut entries = BTreeMap::new();
+
+    for (addr, entry) in jdt.table.iter() {
+        let jump_data = decrypt_cbc(&entry.key, &jdt.iv, &entry.data, *addr)?;
+
+        // The first infector added a fake entry at every instruction with a 0xCC byte. They're
+        // either never looked up, or at an int3 of the program's own, which the runtime now
+        // delivers to it.
+        if jump_data.j_false() <= MAX_INSTRUCTION_LENGTH {
+            entries.insert(*addr, jump_data);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Splits the entries by the executable section they're in, and hashes the code around each one,
+/// like `infect` does.
+fn region_entries(
+    binary: &[u8],
+    mut entries: BTreeMap<u64, JumpData>,
+) -> Result<Vec<RegionEntries>, Box<dyn Error>> {
+    let mut regions = Vec::new();
+
+    for region in code_regions(binary)? {
+        let code = binary
+            .get(region.file_offset as usize..(region.file_offset + region.size) as usize)
+            .ok_or("an executable section is outside of the nanomite'd binary")?;
+
+        let mut inside = entries.split_off(&region.start);
+        entries.append(&mut inside.split_off(&(region.start + region.size)));
+        regions.push(RegionEntries::new(region, code, inside));
+    }
+
+    if let Some(key) = entries.keys().next() {
+        return Err(format!(
+            "the entry at offset 0x{:X} isn't in an executable section",
+            key
+        )
+        .into());
+    }
+
+    Ok(regions)
+}
+
+fn decrypt_cbc(
+    key: &[u8; 32],
+    iv: &[u8; 16],
+    data: &[u8],
+    addr: u64,
+) -> Result<JumpData, Box<dyn Error>> {
+    let aes = Aes256Cbc::new_var(key, iv)?;
+    let data = aes
+        .decrypt_vec(data)
+        .map_err(|_| format!("couldn't decrypt the entry at offset 0x{:X}", addr))?;
+
+    Ok(bincode::deserialize(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use block_modes::BlockMode;
+
+    use common::jump_data::JumpData;
+    use common::JumpType;
+
+    use crate::upgrade::{headerless_entries, Aes256Cbc, EntryV0, TableV0};
+
+    const IV: [u8; 16] = [3; 16];
+
+    fn entry(key: [u8; 32], jump_data: &JumpData) -> EntryV0 {
+        let aes = Aes256Cbc::new_var(&key, &IV).unwrap();
+
+        EntryV0 {
+            key,
+            data: aes.encrypt_vec(&bincode::serialize(jump_data).unwrap()),
+        }
+    }
+
+    #[test]
+    fn decrypts_headerless_table() {
+        let mut table = TableV0 {
+            table: BTreeMap::new(),
+            iv: IV,
+        };
+        table.table.insert(
+            0x20,
+            entry([5; 32], &JumpData::new(JumpType::JumpNotEqual, -0x10, 2)),
+        );
+        // A fake entry, at an instruction that merely contains a 0xCC byte.
+        table.table.insert(
+            0x40,
+            entry([6; 32], &JumpData::new(JumpType::Jump, 0x7F, 0x5A5A)),
+        );
+
+        let entries = headerless_entries(&bincode::serialize(&table).unwrap()).unwrap();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[&0x20].jump_type(), JumpType::JumpNotEqual);
+        assert_eq!(entries[&0x20].j_true(), -0x10);
+
+        // Each entry is decrypted with its own key.
+        table.table.get_mut(&0x20).unwrap().key = [7; 32];
+        assert!(headerless_entries(&bincode::serialize(&table).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,41 @@
+/*
+ * Marks functions for protection by the rekk infector.
+ *
+ *     REKK_PROTECTED int check_license(const char *key) { ... }
+ *     REKK_PROTECT(check_license);
+ *
+ * Each marked function gets a record in the .rekk.protect section. When a binary has markers,
+ * the infector only places nanomites in the marked functions. Records are 32 bit offsets from the
+ * record to the start of the function, so they don't need to be relocated.
+ *
+ * Requires GCC or Clang.
+ */
+
+#ifndef REKK_H
+#define REKK_H
+
+#define REKK_STRINGIFY(x) #x
+#define REKK_EXPAND_STRINGIFY(x) REKK_STRINGIFY(x)
+
+#ifdef _WIN32
+#define REKK_SECTION_BEGIN ".section .rekk.protect,\"dr\"\n"
+#define REKK_SECTION_END ".text\n"
+#else
+/* Retained, so --gc-sections doesn't throw the markers away. */
+#define REKK_SECTION_BEGIN ".pushsection .rekk.protect,\"aR\"\n"
+#define REKK_SECTION_END ".popsection\n"
+#endif
+
+/*
+ * Nanomites are only placed in the function itself, not in copies inlined into its callers. The
+ * marker isn't a use the compiler can see, so keep the function around as well.
+ */
+#define REKK_PROTECTED __attribute__((noinline, used))
+
+/* Marks a function for protection. Must be used at file scope, after the function is declared. */
+#define REKK_PROTECT(function)                                                                     \
+    __asm__(REKK_SECTION_BEGIN ".balign 4\n"                                                       \
+                               ".long " REKK_EXPAND_STRINGIFY(__USER_LABEL_PREFIX__) #function     \
+                               " - .\n" REKK_SECTION_END)
+
+#endif
@@ -0,0 +1,61 @@
+//! Marks functions for protection by the infector.
+//!
+//! Each marked function gets a record in the `.rekk.protect` section. When a binary has markers,
+//! the infector only places nanomites in the marked functions. Records are 32 bit offsets from
+//! the record to the start of the function, so they don't need to be relocated.
+//!
+//! ```
+//! #[inline(never)]
+//! fn check_license(key: &str) -> bool {
+//!     key.len() == 16 && key.starts_with("REKK")
+//! }
+//!
+//! rekk_marker::protect!(check_license);
+//!
+//! fn main() {
+//!     assert!(!check_license("1234"));
+//! }
+//! ```
+//!
+//! Marked functions should be `#[inline(never)]`, as nanomites are only placed in the function
+//! itself, not in the copies inlined into its callers.
+
+#![no_std]
+
+/// The name of the section the markers are placed in.
+pub const SECTION: &str = ".rekk.protect";
+
+/// Marks one or more functions for protection. Must be used at module level.
+#[cfg(not(windows))]
+#[macro_export]
+macro_rules! protect {
+    ($($function:path),+ $(,)?) => {
+        $(
+            ::core::arch::global_asm!(
+                // Retained, so --gc-sections doesn't throw the markers away.
+                ".pushsection .rekk.protect,\"aR\"",
+                ".balign 4",
+                ".long {} - .",
+                ".popsection",
+                sym $function,
+            );
+        )+
+    };
+}
+
+/// Marks one or more functions for protection. Must be used at module level.
+#[cfg(windows)]
+#[macro_export]
+macro_rules! protect {
+    ($($function:path),+ $(,)?) => {
+        $(
+            ::core::arch::global_asm!(
+                ".section .rekk.protect,\"dr\"",
+                ".balign 4",
+                ".long {} - .",
+                ".text",
+                sym $function,
+            );
+        )+
+    };
+}
@@ -1,37 +1,150 @@
-use common::jump_data_table::JumpDataTable;
+use common::cipher::Cipher;
+use common::jump_data_table::{JDTError, JumpDataTable};
+use common::keys::MasterKey;
+use common::profile::Profile;
+use common::thread_context::ThreadContext;
+use common::JumpType;
+use libc::user_regs_struct;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
 use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
 use nix::sys::ptrace;
-use nix::sys::signal::Signal;
-use nix::sys::wait::{waitpid, WaitStatus};
+use nix::sys::ptrace::{Event, Options};
+use nix::sys::signal;
+use nix::sys::signal::{SigHandler, SigSet, Signal};
+use nix::sys::stat;
+use nix::sys::uio::{process_vm_readv, process_vm_writev, IoVec, RemoteIoVec};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd;
 use nix::unistd::{fexecve, fork, ForkResult, Pid};
-use procfs::process::Process;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
 use std::ffi::CString;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::{env, error};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::{env, error, fs, mem, process, ptr};
+
+use crate::loader;
+use crate::profiling::profile_path;
+use crate::trap_action::TrapActions;
+
+/// Signals that are about the runtime itself, so they aren't passed on to the tracee. SIGKILL and
+/// SIGSTOP can't be caught anyway.
+const OWN_SIGNALS: [i32; 9] = [
+    libc::SIGKILL,
+    libc::SIGSTOP,
+    libc::SIGCHLD,
+    libc::SIGSEGV,
+    libc::SIGBUS,
+    libc::SIGFPE,
+    libc::SIGILL,
+    libc::SIGTRAP,
+    libc::SIGSYS,
+];
+
+/// The protected process, which signals sent to the runtime are passed on to.
+static TRACEE: AtomicI32 = AtomicI32::new(0);
+
+/// Set when a job control stop signal reaches the runtime, so it stops along with the tracee.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The event of the stops of a seized task that aren't signal deliveries. libc doesn't define it
+/// for glibc.
+const PTRACE_EVENT_STOP: i32 = 128;
 
 pub fn run() {
+    // The nanomite'd binary and JDT are appended to our own image by the infector.
+    let image = match fs::read("/proc/self/exe") {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("error: couldn't read /proc/self/exe: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let protected = match loader::load(&image) {
+        Ok(protected) => protected,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // Both sides of the fork need the binary. The child runs it, and the keys of the JDT are
+    // derived from it.
+    let binary = protected.binary;
+
+    // The child waits for the write end to be closed, so it's seized before it runs the binary.
+    let (ready_read, ready_write) = match unistd::pipe2(OFlag::O_CLOEXEC) {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            eprintln!("error: couldn't create a pipe: {}", e);
+            process::exit(1);
+        }
+    };
+
     match unsafe { fork() } {
-        Ok(ForkResult::Parent { child }) => parent(child).unwrap(),
-        Ok(ForkResult::Child) => run_binary().unwrap(),
+        Ok(ForkResult::Parent { child }) => {
+            unistd::close(ready_read).ok();
+
+            match parent(child, ready_write, protected.jdt, &binary, protected.cipher) {
+                Ok(Termination::Exited(code)) => process::exit(code),
+                Ok(Termination::Signaled(signal)) => reraise(signal),
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        Ok(ForkResult::Child) => {
+            unistd::close(ready_write).ok();
+            run_binary(&binary, ready_read).unwrap()
+        }
         Err(_) => panic!("unknown err"),
     }
 }
 
-fn run_binary() -> Result<(), Box<dyn error::Error>> {
-    let binary = include_bytes!("../../nanomite.bin");
+/// How the protected process terminated.
+enum Termination {
+    /// The process exited with the given exit code.
+    Exited(i32),
+
+    /// The process was killed by the given signal.
+    Signaled(Signal),
+}
 
+/// Kill ourselves with the signal that killed the protected process, so our parent sees the
+/// same termination status.
+fn reraise(signal: Signal) -> ! {
+    // The tracee already dumped core if it was going to. Don't overwrite it with our own.
+    let no_core = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe { libc::setrlimit(libc::RLIMIT_CORE, &no_core) };
+
+    let mut mask = SigSet::empty();
+    mask.add(signal);
+
+    // We might be ignoring the signal, or have it blocked.
+    unsafe { signal::signal(signal, SigHandler::SigDfl) }.ok();
+    mask.thread_unblock().ok();
+    signal::raise(signal).ok();
+
+    // The default action of some signals is to do nothing. Exit like a shell would report it.
+    process::exit(128 + signal as i32);
+}
+
+fn run_binary(binary: &[u8], ready: RawFd) -> Result<(), Box<dyn error::Error>> {
     // create the memory file descriptor
     let fd_name = CString::new("child")?;
     let fd = memfd_create(&fd_name, MemFdCreateFlag::MFD_CLOEXEC)?;
 
-    // decompress the binary.
-    let mut decoder = snap::raw::Decoder::new();
-    let d_bin = decoder.decompress_vec(binary)?;
-
-    unistd::write(fd, d_bin.as_slice())?;
+    unistd::write(fd, binary)?;
 
-    ptrace::traceme()?;
+    // Wait until the parent has seized us. It closes the pipe whether it succeeded or not, and
+    // kills us if it didn't.
+    unistd::read(ready, &mut [0])?;
 
     let args: Vec<CString> = env::args().map(|s| CString::new(s).unwrap()).collect();
     let env: Vec<CString> = env::vars()
@@ -43,97 +156,492 @@ fn run_binary() -> Result<(), Box<dyn error::Error>> {
     Ok(())
 }
 
-fn parent(child_pid: Pid) -> Result<(), Box<dyn error::Error>> {
-    let jdt = include_bytes!("../../jdt.bin");
+fn parent(
+    child_pid: Pid,
+    ready: RawFd,
+    jdt: JumpDataTable,
+    binary: &[u8],
+    cipher: Box<dyn Cipher>,
+) -> Result<Termination, Box<dyn error::Error>> {
+    let master = jdt.master_key(binary);
+
+    // Unlike PTRACE_TRACEME, seizing reports group-stops as PTRACE_EVENT_STOP, and lets them be
+    // held with PTRACE_LISTEN until SIGCONT ends them.
+    let seized = ptrace::seize(
+        child_pid,
+        Options::PTRACE_O_TRACECLONE
+            | Options::PTRACE_O_TRACEFORK
+            | Options::PTRACE_O_TRACEVFORK
+            | Options::PTRACE_O_TRACEEXEC
+            | Options::PTRACE_O_TRACESYSGOOD
+            | Options::PTRACE_O_EXITKILL,
+    );
+    unistd::close(ready)?;
+
+    if let Err(e) = seized {
+        signal::kill(child_pid, Signal::SIGKILL).ok();
+        return Err(format!("couldn't trace the protected process: {}", e).into());
+    }
+
+    // Whoever started us only knows our pid, so signals sent to us are meant for the tracee. Let
+    // the tracee decide what to do with them, instead of dying or stopping before it.
+    forward_signals(child_pid);
 
-    let mut first_stop = true;
+    let profile_path = profile_path();
+    let mut tracer = Tracer::new(jdt, master, cipher, child_pid, profile_path.is_some());
+    let mut termination = None;
 
     loop {
-        let status = waitpid(child_pid, None).unwrap();
+        let status = match tracer.pending.pop_front() {
+            Some(status) => status,
+            None => match waitpid(None, Some(WaitPidFlag::__WALL)) {
+                Ok(status) => status,
+                // Every tracee is gone.
+                Err(nix::Error::Sys(Errno::ECHILD)) => break,
+                Err(e) => return Err(e.into()),
+            },
+        };
 
         match status {
-            WaitStatus::Exited(_, _) => {
-                break;
-            }
-            WaitStatus::Signaled(_, _, _) => {}
-            WaitStatus::Stopped(pid, signal) => {
-                if first_stop && signal == Signal::SIGTRAP {
-                    first_stop = false;
-                    ptrace::cont(pid, None)?;
-                    continue;
+            WaitStatus::Exited(pid, code) => {
+                if pid == child_pid {
+                    termination = Some(Termination::Exited(code));
                 }
 
-                if signal == Signal::SIGTRAP {
-                    handle_int3(jdt, pid)?;
-                }
+                tracer.remove_task(pid);
 
-                if signal == Signal::SIGILL {
-                    let regs = ptrace::getregs(pid).unwrap();
-                    println!("SIGILL 0x{:X}", regs.rip);
+                // A task dropped while it was being killed still reports its exit, so don't stop
+                // before the protected process has.
+                if tracer.tasks.is_empty() && termination.is_some() {
                     break;
                 }
+            }
+            WaitStatus::Signaled(pid, signal, _) => {
+                if pid == child_pid {
+                    termination = Some(Termination::Signaled(signal));
+                }
 
-                if signal == Signal::SIGSEGV {
-                    let regs = ptrace::getregs(pid).unwrap();
-                    println!("SIGSEGV 0x{:X}", regs.rip);
+                tracer.remove_task(pid);
+
+                if tracer.tasks.is_empty() && termination.is_some() {
                     break;
                 }
-
-                if signal == Signal::SIGCHLD {}
             }
-            WaitStatus::PtraceEvent(_, _, _) => {}
+            WaitStatus::Stopped(pid, signal) => {
+                // Seized tasks report group-stops as events, so this is always a signal
+                // delivery.
+                let handled = if signal == Signal::SIGTRAP {
+                    tracer.handle_int3(pid)
+                } else {
+                    // Deliver every other signal to the tracee, as if it wasn't being traced.
+                    // Fatal signals will kill it, just like they would the unprotected program.
+                    ptrace::cont(pid, signal).map_err(Into::into)
+                };
+
+                tracer.unless_gone(pid, handled)?;
+            }
+            WaitStatus::PtraceEvent(pid, signal, event) => {
+                let handled = if event == Event::PTRACE_EVENT_EXEC as i32 {
+                    tracer.handle_exec(pid)
+                } else if event == PTRACE_EVENT_STOP {
+                    tracer.handle_stop(pid, signal, child_pid)
+                } else {
+                    // Clone, fork or vfork. The new task will report its own stop, so just resume
+                    // the task that created it.
+                    ptrace::cont(pid, None).map_err(Into::into)
+                };
+
+                tracer.unless_gone(pid, handled)?;
+            }
             WaitStatus::PtraceSyscall(_) => {}
             WaitStatus::Continued(_) => {}
             WaitStatus::StillAlive => {}
         }
     }
 
-    Ok(())
+    if let (Some(profile), Some(path)) = (&tracer.profile, &profile_path) {
+        if let Err(e) = profile.merge_into(path) {
+            eprintln!(
+                "warning: couldn't write the profile to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    termination.ok_or_else(|| "the protected process was never reaped".into())
+}
+
+/// Passes every signal the runtime can catch, other than its own, on to the tracee.
+fn forward_signals(child_pid: Pid) {
+    TRACEE.store(child_pid.as_raw(), Ordering::SeqCst);
+
+    for signal in 1..=libc::SIGRTMAX() {
+        if OWN_SIGNALS.contains(&signal) {
+            continue;
+        }
+
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = forward as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+
+        // glibc reserves a few realtime signals for itself, and refuses to change them.
+        unsafe { libc::sigaction(signal, &action, ptr::null_mut()) };
+    }
+}
+
+/// Sends a signal the runtime received on to the tracee. Signals the kernel generates, like the
+/// ones the terminal sends to the foreground process group, already reached the tracee, since
+/// it's in our process group, so only signals sent by another process are passed on.
+extern "C" fn forward(signal: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // Whether or not it was sent to us alone, a job control stop means our job is stopping.
+    if [libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU].contains(&signal) {
+        STOP_REQUESTED.store(true, Ordering::SeqCst);
+    } else if signal == libc::SIGCONT {
+        STOP_REQUESTED.store(false, Ordering::SeqCst);
+    }
+
+    let info = unsafe { &*info };
+    let sent = [libc::SI_USER, libc::SI_QUEUE, libc::SI_TKILL].contains(&info.si_code)
+        && unsafe { info.si_pid() != libc::getpid() };
+
+    if sent {
+        unsafe { libc::kill(TRACEE.load(Ordering::SeqCst), signal) };
+    }
+}
+
+/// Keeps a task in its group-stop until SIGCONT ends it, without resuming it like PTRACE_CONT
+/// would.
+fn listen(pid: Pid) -> nix::Result<()> {
+    let ret = unsafe {
+        libc::ptrace(
+            libc::PTRACE_LISTEN,
+            pid.as_raw(),
+            ptr::null_mut::<libc::c_void>(),
+            ptr::null_mut::<libc::c_void>(),
+        )
+    };
+
+    Errno::result(ret).map(drop)
+}
+
+/// Whether handling a task's stop failed because the task is gone. Ptrace requests fail with ESRCH
+/// once a task has left its stop, which a stopped task only does when it's killed. Other errors,
+/// like failing to read its memory, are checked against the task the same way.
+fn is_gone(pid: Pid, error: &(dyn error::Error + 'static)) -> bool {
+    let esrch = nix::Error::Sys(Errno::ESRCH);
+
+    error.downcast_ref::<nix::Error>() == Some(&esrch)
+        || ptrace::getsiginfo(pid).err() == Some(esrch)
+}
+
+/// Returns the address the kernel entered a task's executable at, from its auxiliary vector.
+/// Unlike the memory maps, this isn't thrown off by the segment layout, or by libraries
+/// mapped below the executable.
+fn loaded_entry(pid: Pid) -> Result<u64, Box<dyn error::Error>> {
+    let auxv = fs::read(format!("/proc/{}/auxv", pid))?;
+
+    for pair in auxv.chunks_exact(16) {
+        let key = u64::from_ne_bytes(pair[..8].try_into()?);
+        let value = u64::from_ne_bytes(pair[8..].try_into()?);
+
+        if key == libc::AT_ENTRY {
+            return Ok(value);
+        }
+    }
+
+    Err("no AT_ENTRY in the auxiliary vector".into())
+}
+
+/// Returns the device and inode of the image a task is executing.
+fn image_id(pid: Pid) -> nix::Result<(u64, u64)> {
+    let stat = stat::stat(format!("/proc/{}/exe", pid).as_str())?;
+    Ok((stat.st_dev, stat.st_ino))
+}
+
+/// Services the nanomites of the protected process, and of every thread and descendant process
+/// that runs the same image.
+struct Tracer {
+    jdt: JumpDataTable,
+
+    /// The key every entry key of the JDT is derived from.
+    master: MasterKey,
+
+    /// Decrypts the entries of the JDT.
+    cipher: Box<dyn Cipher>,
+
+    /// Every task (thread or process) being traced. New tasks are traced automatically, and report
+    /// a SIGSTOP before they start running.
+    tasks: HashSet<Pid>,
+
+    /// How far the image was moved from its link-time addresses, for each task that hit a
+    /// nanomite.
+    load_biases: HashMap<Pid, u64>,
+
+    /// The device and inode of the nanomite'd image, used to recognise it after an exec.
+    image: Option<(u64, u64)>,
+
+    /// The number of times each nanomite was hit, if a profile is being recorded.
+    profile: Option<Profile>,
+
+    /// The SIGTRAP handlers of the tracees, kept across nanomites.
+    trap_actions: TrapActions,
+
+    /// Wait statuses reaped while waiting on a single task, for the main loop to handle.
+    pending: VecDeque<WaitStatus>,
 }
 
-static VADDR: AtomicU64 = AtomicU64::new(0);
+impl Tracer {
+    fn new(
+        jdt: JumpDataTable,
+        master: MasterKey,
+        cipher: Box<dyn Cipher>,
+        child_pid: Pid,
+        profile: bool,
+    ) -> Tracer {
+        let mut tasks = HashSet::new();
+        tasks.insert(child_pid);
+
+        Tracer {
+            jdt,
+            master,
+            cipher,
+            tasks,
+            load_biases: HashMap::new(),
+            image: None,
+            profile: if profile {
+                Some(Profile::default())
+            } else {
+                None
+            },
+            trap_actions: TrapActions::default(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn remove_task(&mut self, pid: Pid) {
+        self.tasks.remove(&pid);
+        self.load_biases.remove(&pid);
+        self.trap_actions.forget(pid);
+    }
+
+    /// Returns the result of handling a task's stop, unless the task was killed while it was
+    /// stopped, by another thread's exit_group for example. It's dropped then, and its exit, and
+    /// the exit status of the protected process, are still waited for.
+    fn unless_gone(
+        &mut self,
+        pid: Pid,
+        handled: Result<(), Box<dyn error::Error>>,
+    ) -> Result<(), Box<dyn error::Error>> {
+        match handled {
+            Err(e) if is_gone(pid, e.as_ref()) => {
+                self.remove_task(pid);
+                Ok(())
+            }
+            handled => handled,
+        }
+    }
+
+    /// Handles a PTRACE_EVENT_STOP. New tasks report one with SIGTRAP before they run, and every
+    /// task reports one with the stop signal when a group-stop starts, and with SIGTRAP again
+    /// when SIGCONT ends it.
+    fn handle_stop(
+        &mut self,
+        pid: Pid,
+        signal: Signal,
+        child_pid: Pid,
+    ) -> Result<(), Box<dyn error::Error>> {
+        self.tasks.insert(pid);
+
+        if signal == Signal::SIGTRAP {
+            ptrace::cont(pid, None)?;
+            return Ok(());
+        }
 
-fn handle_int3(comp_enc_jdt: &[u8], pid: Pid) -> Result<(), Box<dyn error::Error>> {
-    // decompress JDT.
-    let mut decoder = snap::raw::Decoder::new();
-    let raw_jdt = decoder.decompress_vec(comp_enc_jdt)?;
+        // The tracee stopped because of SIGSTOP, SIGTSTP, etc. Every task stays stopped until
+        // SIGCONT, like it would without us.
+        listen(pid)?;
 
-    let mut vaddr = VADDR.load(Ordering::Relaxed);
+        // Whoever started us is waiting on the runtime, not the tracee. If the stop was meant for
+        // our whole job, like one from the terminal, stop ourselves as well, and we're continued
+        // by the same SIGCONT as the tracee. A stop sent to the tracee alone is usually ended by a
+        // SIGCONT sent to it alone, which we have to be running to let through.
+        if pid == child_pid && STOP_REQUESTED.swap(false, Ordering::SeqCst) {
+            signal::raise(Signal::SIGSTOP)?;
+        }
 
-    if vaddr == 0 {
-        let proc = Process::new(pid.as_raw())?;
-        let proc_maps = proc.maps()?;
+        Ok(())
+    }
 
-        let map = proc_maps.iter().find(|x| x.perms.contains('x')).unwrap();
+    fn handle_exec(&mut self, pid: Pid) -> Result<(), Box<dyn error::Error>> {
+        // If a thread other than the leader called exec, it takes over the leader's pid.
+        let former_pid = Pid::from_raw(ptrace::getevent(pid)? as i32);
+        self.remove_task(former_pid);
+        self.remove_task(pid);
+
+        // The first exec runs the nanomite'd image. Later ones might load it at a different
+        // address, or might not run it at all.
+        let image = image_id(pid)?;
+        if *self.image.get_or_insert(image) == image {
+            self.tasks.insert(pid);
+            ptrace::cont(pid, None)?;
+        } else {
+            ptrace::detach(pid, None)?;
+        }
 
-        VADDR.store(map.address.0, Ordering::Relaxed);
-        vaddr = map.address.0;
+        Ok(())
     }
 
-    let jdt: JumpDataTable = bincode::deserialize(raw_jdt.as_slice()).unwrap();
+    /// Emulates the nanomite a task trapped on. Traps that aren't nanomites are the program's own,
+    /// and are delivered to it, so its SIGTRAP handler runs just like it would without us.
+    fn handle_int3(&mut self, pid: Pid) -> Result<(), Box<dyn error::Error>> {
+        // Only int3 reports SI_KERNEL. SIGTRAPs sent with kill or raise can't be nanomites.
+        if ptrace::getsiginfo(pid)?.si_code != libc::SI_KERNEL {
+            return self.deliver_trap(pid);
+        }
 
-    // get the ip
+        let load_bias = match self.load_biases.get(&pid) {
+            Some(load_bias) => *load_bias,
+            None => {
+                let load_bias = self.jdt.layout.load_bias(loaded_entry(pid)?);
+                self.load_biases.insert(pid, load_bias);
+                load_bias
+            }
+        };
+
+        // get the ip
+        let regs = ptrace::getregs(pid)?;
+        let key = self.jdt.layout.key(regs.rip - 1, load_bias);
+        let mut context = PtraceContext {
+            pid,
+            regs,
+            pointer_size: self.jdt.layout.bitness as u64 / 8,
+        };
+
+        // The entry key is bound to the code around the nanomite, as it is in memory now.
+        let jump_data = match self.jdt.read_code_hash(key, load_bias, &mut context)? {
+            Some(code_hash) => {
+                self.jdt
+                    .get_jump_data(key, &code_hash, &self.master, self.cipher.as_ref())
+            }
+            None => Err(JDTError::NotFound),
+        };
+
+        let jump_data = match jump_data {
+            Ok(jump_data) if jump_data.jump_type() != JumpType::Passthrough => jump_data,
+            // A breakpoint of the program's own. RIP already points after it, like the kernel
+            // reports it.
+            Ok(_) | Err(JDTError::NotFound) => return self.deliver_trap(pid),
+            // Don't guess where a tampered entry jumps to. The tracees are killed when we exit.
+            Err(e) => return Err(e.into()),
+        };
+
+        if let Some(profile) = self.profile.as_mut() {
+            profile.record(key);
+        }
+
+        // The trap may have cost the program its SIGTRAP handler.
+        if self.jdt.layout.bitness == 64 {
+            self.trap_actions
+                .keep(pid, &regs, true, &mut self.pending)?;
+        }
 
-    let regs = ptrace::getregs(pid);
+        // RIP points after the int 3, rewind it to the nanomite.
+        context.set_ip(regs.rip - 1);
+
+        jump_data.emulate(&mut context)?;
+
+        ptrace::setregs(pid, context.regs)?;
+        ptrace::cont(pid, None)?;
 
-    if regs.is_err() {
-        return Ok(());
+        Ok(())
     }
 
-    let mut regs = regs?;
-    let jump_data = jdt.get_jump_data(regs.rip - vaddr - 1);
+    /// Delivers a SIGTRAP to a task. A handler it runs is intact, so it's remembered on the way
+    /// in, in case a nanomite the handler hits makes the kernel reset it.
+    fn deliver_trap(&mut self, pid: Pid) -> Result<(), Box<dyn error::Error>> {
+        if self.jdt.layout.bitness != 64 || !self.trap_actions.caught(pid)?.1 {
+            ptrace::cont(pid, Signal::SIGTRAP)?;
+            return Ok(());
+        }
+
+        // Stepping stops the task as soon as the handler is entered, without a trap of its own.
+        ptrace::step(pid, Signal::SIGTRAP)?;
+
+        match waitpid(pid, Some(WaitPidFlag::__WALL))? {
+            WaitStatus::Stopped(_, Signal::SIGTRAP) => {}
+            status => {
+                self.pending.push_back(status);
+                return Ok(());
+            }
+        }
 
-    if jump_data.is_err() {
+        let regs = ptrace::getregs(pid)?;
+        self.trap_actions
+            .keep(pid, &regs, false, &mut self.pending)?;
         ptrace::cont(pid, None)?;
-        return Ok(());
+
+        Ok(())
     }
+}
 
-    let jump_data = jump_data.unwrap();
-    let ip_offset = jump_data.get_ip_offset(regs.eflags);
-    regs.rip = (regs.rip as i64 + ip_offset as i64 - 1) as u64;
+/// A traced thread's registers, and access to its memory.
+struct PtraceContext {
+    pid: Pid,
+    regs: user_regs_struct,
+    pointer_size: u64,
+}
 
-    ptrace::setregs(pid, regs)?;
-    ptrace::cont(pid, None)?;
+impl ThreadContext for PtraceContext {
+    fn ip(&self) -> u64 {
+        self.regs.rip
+    }
 
-    Ok(())
+    fn set_ip(&mut self, ip: u64) {
+        self.regs.rip = ip;
+    }
+
+    fn flags(&self) -> u64 {
+        self.regs.eflags
+    }
+
+    fn sp(&self) -> u64 {
+        self.regs.rsp
+    }
+
+    fn set_sp(&mut self, sp: u64) {
+        self.regs.rsp = sp;
+    }
+
+    fn cx(&self) -> u64 {
+        self.regs.rcx
+    }
+
+    fn set_cx(&mut self, cx: u64) {
+        self.regs.rcx = cx;
+    }
+
+    fn pointer_size(&self) -> u64 {
+        self.pointer_size
+    }
+
+    fn read_memory(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Box<dyn error::Error>> {
+        let remote = RemoteIoVec {
+            base: addr as usize,
+            len: buf.len(),
+        };
+
+        process_vm_readv(self.pid, &[IoVec::from_mut_slice(buf)], &[remote])?;
+        Ok(())
+    }
+
+    fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<(), Box<dyn error::Error>> {
+        let remote = RemoteIoVec {
+            base: addr as usize,
+            len: data.len(),
+        };
+
+        process_vm_writev(self.pid, &[IoVec::from_slice(data)], &[remote])?;
+        Ok(())
+    }
 }
@@ -0,0 +1,51 @@
+//! Loads the protected program appended to the runtime. This is the same on every platform, only
+//! running and debugging it differs.
+
+use std::error::Error;
+
+use common::cipher::Cipher;
+use common::jump_data_table::JumpDataTable;
+use common::{container, packed, payload};
+
+/// The protected program, ready to run.
+pub struct Protected {
+    pub jdt: JumpDataTable,
+
+    /// The decrypted and decompressed nanomite'd binary. It only ever exists in memory.
+    pub binary: Vec<u8>,
+
+    /// Decrypts the entries of the JDT.
+    pub cipher: Box<dyn Cipher>,
+}
+
+/// Unpacks the nanomite'd binary and JDT from our own image, checks they belong together, and
+/// decrypts the binary. The binary is authenticated as a whole before any of it is returned.
+pub fn load(image: &[u8]) -> Result<Protected, Box<dyn Error>> {
+    let (binary, jdt) = packed::unpack(image)?;
+
+    let (header, binary, jdt) = container::unwrap_pair(binary, jdt)
+        .map_err(|e| format!("couldn't load the protected program: {}", e))?;
+
+    let cipher = header.cipher.decryptor().ok_or_else(|| {
+        format!(
+            "the protected program is encrypted with {}, which this runtime was built without",
+            header.cipher.name()
+        )
+    })?;
+
+    // decompress and deserialize the JDT once, it's used for every nanomite.
+    let jdt = snap::raw::Decoder::new().decompress_vec(jdt)?;
+    let jdt: JumpDataTable = bincode::deserialize(&jdt)?;
+
+    // The key of the binary is derived from the JDT.
+    let binary = payload::decrypt(cipher.as_ref(), &jdt.salt, &header, binary)?;
+    let binary = snap::raw::Decoder::new()
+        .decompress_vec(&binary)
+        .map_err(|e| format!("couldn't decompress the protected program: {}", e))?;
+
+    Ok(Protected {
+        jdt,
+        binary,
+        cipher,
+    })
+}
@@ -1,6 +1,12 @@
+mod loader;
+mod profiling;
+
 #[cfg(target_os = "linux")]
 mod linux_runtime;
 
+#[cfg(target_os = "linux")]
+mod trap_action;
+
 #[cfg(target_os = "linux")]
 use crate::linux_runtime::run;
 
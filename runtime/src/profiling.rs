@@ -0,0 +1,15 @@
+use std::path::PathBuf;
+
+/// Returns the file to add the nanomite hits to, if a profile should be recorded.
+///
+/// A profile shows which entries of the JDT are real nanomites, so only stubs built with the
+/// `profile` feature record one. Release stubs never read `REKK_PROFILE`.
+#[cfg(feature = "profile")]
+pub fn profile_path() -> Option<PathBuf> {
+    std::env::var_os(common::profile::PROFILE_ENV).map(PathBuf::from)
+}
+
+#[cfg(not(feature = "profile"))]
+pub fn profile_path() -> Option<PathBuf> {
+    None
+}
@@ -0,0 +1,364 @@
+//! Keeps the program's SIGTRAP handler when it hits a nanomite with SIGTRAP blocked.
+//!
+//! The kernel can't deliver a trap to a thread that blocks SIGTRAP, so it resets the handler to
+//! the default and unblocks SIGTRAP before the tracer even sees the trap. That's right for the
+//! program's own breakpoints, which then kill it like they would without us, but a nanomite isn't
+//! a trap as far as the program is concerned, so its handler and mask are put back.
+//!
+//! The tracer can't read the handler itself, so the tracee makes the rt_sigaction calls for us,
+//! at a `syscall` instruction in its own memory. Whenever the handler is seen it's remembered,
+//! and marked with SA_NOCLDSTOP, which means nothing for SIGTRAP. A reset keeps everything but the
+//! handler, so the default action with the mark on it was reset by the kernel, rather than set
+//! by the program.
+//!
+//! An ignored SIGTRAP is reset by any trap, before it can be seen, so only handlers are kept. So
+//! is the mask of a thread that blocks SIGTRAP while it has the default action. 32-bit tracees
+//! are left to the kernel.
+
+use libc::user_regs_struct;
+use nix::errno::Errno;
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::sys::uio::{process_vm_readv, process_vm_writev, IoVec, RemoteIoVec};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::{error, fs};
+
+const PTRACE_GETSIGMASK: libc::c_uint = 0x420a;
+const PTRACE_SETSIGMASK: libc::c_uint = 0x420b;
+
+/// The bit of SIGTRAP in a signal mask.
+const SIGTRAP_BIT: u64 = 1 << (libc::SIGTRAP - 1);
+
+/// Marks a handler we've seen. Only SIGCHLD looks at it.
+const MARK: u64 = libc::SA_NOCLDSTOP as u64;
+
+/// The red zone below the stack pointer belongs to the interrupted code.
+const RED_ZONE: u64 = 128;
+
+/// `struct kernel_sigaction` of x86-64, as rt_sigaction takes it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct SigAction {
+    handler: u64,
+    flags: u64,
+    restorer: u64,
+    mask: u64,
+}
+
+impl SigAction {
+    const SIZE: usize = 32;
+
+    fn from_bytes(bytes: &[u8; SigAction::SIZE]) -> SigAction {
+        let word = |i: usize| u64::from_ne_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+
+        SigAction {
+            handler: word(0),
+            flags: word(1),
+            restorer: word(2),
+            mask: word(3),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; SigAction::SIZE] {
+        let mut bytes = [0; SigAction::SIZE];
+        let words = [self.handler, self.flags, self.restorer, self.mask];
+
+        for (chunk, word) in bytes.chunks_exact_mut(8).zip(words.iter()) {
+            chunk.copy_from_slice(&word.to_ne_bytes());
+        }
+
+        bytes
+    }
+}
+
+/// The SIGTRAP handler of every traced process that has one.
+#[derive(Default)]
+pub struct TrapActions {
+    /// The handler each process had when it was last seen, with the mark.
+    actions: HashMap<Pid, SigAction>,
+
+    /// The address of a `syscall` instruction in each process.
+    syscalls: HashMap<Pid, u64>,
+
+    /// The process each task belongs to.
+    tgids: HashMap<Pid, Pid>,
+
+    /// /proc/<pid>/stat of each process. It's checked at every nanomite, and reading it again is
+    /// much cheaper than opening it again.
+    stats: HashMap<Pid, File>,
+}
+
+impl TrapActions {
+    /// Forgets a task, when it exits or execs another image, along with its process if it's the
+    /// leader.
+    pub fn forget(&mut self, pid: Pid) {
+        self.tgids.remove(&pid);
+        self.actions.remove(&pid);
+        self.syscalls.remove(&pid);
+        self.stats.remove(&pid);
+    }
+
+    /// Returns the process a task belongs to, and whether it has a SIGTRAP handler.
+    pub fn caught(&mut self, pid: Pid) -> Result<(Pid, bool), Box<dyn error::Error>> {
+        let tgid = match self.tgids.get(&pid) {
+            Some(tgid) => *tgid,
+            None => {
+                let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+                let tgid = status
+                    .lines()
+                    .find_map(|x| x.strip_prefix("Tgid:"))
+                    .ok_or_else(|| format!("no Tgid in /proc/{}/status", pid))?;
+                let tgid = Pid::from_raw(tgid.trim().parse()?);
+
+                self.tgids.insert(pid, tgid);
+                tgid
+            }
+        };
+
+        let stat = match self.stats.entry(tgid) {
+            Entry::Occupied(stat) => stat.into_mut(),
+            Entry::Vacant(stat) => stat.insert(File::open(format!("/proc/{}/stat", tgid))?),
+        };
+
+        let mut buf = [0; 1024];
+        let len = stat.read_at(&mut buf, 0)?;
+        let stat = String::from_utf8_lossy(&buf[..len]);
+
+        // The name in parentheses can hold anything, sigcatch is the 34th field.
+        let caught = stat
+            .rsplit_once(')')
+            .and_then(|(_, fields)| fields.split_whitespace().nth(31))
+            .ok_or_else(|| format!("no sigcatch in /proc/{}/stat", tgid))?;
+
+        Ok((tgid, caught.parse::<u64>()? & SIGTRAP_BIT != 0))
+    }
+
+    /// Checks the SIGTRAP handler at a stop with the registers `regs`. An intact handler is
+    /// remembered. If `restore` is set, the stop is a nanomite, and a handler the kernel reset
+    /// for it is put back, and SIGTRAP blocked again.
+    ///
+    /// Statuses of other stops or exits of the task met on the way are added to `pending`. The
+    /// task is left in a syscall stop, with its registers put back.
+    pub fn keep(
+        &mut self,
+        pid: Pid,
+        regs: &user_regs_struct,
+        restore: bool,
+        pending: &mut VecDeque<WaitStatus>,
+    ) -> Result<(), Box<dyn error::Error>> {
+        let (tgid, caught) = self.caught(pid)?;
+        let saved = self.actions.get(&tgid).copied();
+
+        if !caught && (saved.is_none() || !restore) {
+            return Ok(());
+        }
+
+        let syscall = match self.syscalls.get(&tgid) {
+            Some(syscall) => *syscall,
+            None => {
+                let syscall = find_syscall(pid)?;
+                self.syscalls.insert(tgid, syscall);
+                syscall
+            }
+        };
+
+        // Nothing but SIGKILL and SIGSTOP interrupts the calls then.
+        let mask = sigmask(pid)?;
+        set_sigmask(pid, !0)?;
+
+        let mut calls = Calls {
+            pid,
+            regs,
+            syscall,
+            resent: Vec::new(),
+            pending,
+        };
+
+        let mut reset = false;
+        let result = calls.sigaction(None).and_then(|action| {
+            if caught {
+                if action.flags & MARK == 0 {
+                    let marked = SigAction {
+                        flags: action.flags | MARK,
+                        ..action
+                    };
+                    calls.sigaction(Some(marked))?;
+                    self.actions.insert(tgid, marked);
+                } else {
+                    self.actions.insert(tgid, action);
+                }
+            } else if let Some(saved) = saved {
+                if action
+                    == (SigAction {
+                        handler: 0,
+                        ..saved
+                    })
+                {
+                    calls.sigaction(Some(saved))?;
+                    reset = true;
+                } else {
+                    // The program changed it itself.
+                    self.actions.remove(&tgid);
+                }
+            }
+
+            Ok(())
+        });
+
+        ptrace::setregs(pid, *regs)?;
+        set_sigmask(pid, if reset { mask | SIGTRAP_BIT } else { mask })?;
+
+        for signal in calls.resent {
+            unsafe { libc::syscall(libc::SYS_tgkill, tgid.as_raw(), pid.as_raw(), signal) };
+        }
+
+        result
+    }
+}
+
+/// rt_sigaction calls made by a stopped task.
+struct Calls<'a> {
+    pid: Pid,
+    regs: &'a user_regs_struct,
+    syscall: u64,
+
+    /// Stop signals that arrived during the calls, to be sent again afterwards.
+    resent: Vec<Signal>,
+
+    pending: &'a mut VecDeque<WaitStatus>,
+}
+
+impl Calls<'_> {
+    /// Sets the SIGTRAP action of the task, if `action` is given, and returns the one it had.
+    fn sigaction(&mut self, action: Option<SigAction>) -> Result<SigAction, Box<dyn error::Error>> {
+        let scratch = (self.regs.rsp - RED_ZONE - 2 * SigAction::SIZE as u64) & !15;
+        let old = scratch + SigAction::SIZE as u64;
+
+        if let Some(action) = action {
+            let remote = RemoteIoVec {
+                base: scratch as usize,
+                len: SigAction::SIZE,
+            };
+            process_vm_writev(
+                self.pid,
+                &[IoVec::from_slice(&action.to_bytes())],
+                &[remote],
+            )?;
+        }
+
+        let mut call = *self.regs;
+        call.rip = self.syscall;
+        call.rax = libc::SYS_rt_sigaction as u64;
+        call.rdi = libc::SIGTRAP as u64;
+        call.rsi = if action.is_some() { scratch } else { 0 };
+        call.rdx = old;
+        call.r10 = 8;
+        ptrace::setregs(self.pid, call)?;
+
+        // Into the call, and out of it.
+        self.until_syscall_stop()?;
+        self.until_syscall_stop()?;
+
+        let ret = ptrace::getregs(self.pid)?.rax as i64;
+        if ret < 0 {
+            return Err(nix::Error::Sys(Errno::from_i32(-ret as i32)).into());
+        }
+
+        let mut bytes = [0; SigAction::SIZE];
+        let remote = RemoteIoVec {
+            base: old as usize,
+            len: SigAction::SIZE,
+        };
+        process_vm_readv(self.pid, &[IoVec::from_mut_slice(&mut bytes)], &[remote])?;
+
+        Ok(SigAction::from_bytes(&bytes))
+    }
+
+    fn until_syscall_stop(&mut self) -> Result<(), Box<dyn error::Error>> {
+        loop {
+            ptrace::syscall(self.pid, None)?;
+
+            match waitpid(self.pid, Some(WaitPidFlag::__WALL))? {
+                WaitStatus::PtraceSyscall(_) => return Ok(()),
+                WaitStatus::Stopped(_, signal) | WaitStatus::PtraceEvent(_, signal, _) => {
+                    if signal != Signal::SIGTRAP {
+                        self.resent.push(signal);
+                    }
+                }
+                status => {
+                    self.pending.push_back(status);
+                    return Err(nix::Error::Sys(Errno::ESRCH).into());
+                }
+            }
+        }
+    }
+}
+
+/// Returns the address of a `syscall` instruction in the executable mappings of a task,
+/// preferring the vDSO.
+fn find_syscall(pid: Pid) -> Result<u64, Box<dyn error::Error>> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    let mut mappings: Vec<&str> = maps
+        .lines()
+        .filter(|x| x.split_whitespace().nth(1) == Some("r-xp"))
+        .collect();
+    mappings.sort_by_key(|x| !x.ends_with("[vdso]"));
+
+    for mapping in mappings {
+        let range = mapping.split_whitespace().next().unwrap_or_default();
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (
+                u64::from_str_radix(start, 16)?,
+                u64::from_str_radix(end, 16)?,
+            ),
+            None => continue,
+        };
+
+        let mut code = vec![0; (end - start) as usize];
+        let remote = RemoteIoVec {
+            base: start as usize,
+            len: code.len(),
+        };
+        if process_vm_readv(pid, &[IoVec::from_mut_slice(&mut code)], &[remote]).is_err() {
+            continue;
+        }
+
+        if let Some(offset) = code.windows(2).position(|x| x == [0x0f, 0x05]) {
+            return Ok(start + offset as u64);
+        }
+    }
+
+    Err(format!("no syscall instruction in the memory of {}", pid).into())
+}
+
+fn sigmask(pid: Pid) -> nix::Result<u64> {
+    let mut mask = 0u64;
+    let ret = unsafe {
+        libc::ptrace(
+            PTRACE_GETSIGMASK,
+            pid.as_raw(),
+            8 as *mut libc::c_void,
+            &mut mask as *mut u64 as *mut libc::c_void,
+        )
+    };
+
+    Errno::result(ret).map(|_| mask)
+}
+
+fn set_sigmask(pid: Pid, mut mask: u64) -> nix::Result<()> {
+    let ret = unsafe {
+        libc::ptrace(
+            PTRACE_SETSIGMASK,
+            pid.as_raw(),
+            8 as *mut libc::c_void,
+            &mut mask as *mut u64 as *mut libc::c_void,
+        )
+    };
+
+    Errno::result(ret).map(drop)
+}
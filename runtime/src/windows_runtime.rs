@@ -1,66 +1,159 @@
-use std::ffi::CString;
-use std::ptr::null_mut;
-use std::{error, mem};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr::{self, null_mut};
+use std::{env, error, fs, io, mem, process, slice};
 
+use ntapi::ntmmapi::{NtCreateSection, NtMapViewOfSection, NtUnmapViewOfSection, ViewUnmap};
 use ntapi::ntpebteb::PEB;
 use ntapi::ntpsapi::{
-    NtQueryInformationProcess, ProcessBasicInformation, PROCESS_BASIC_INFORMATION,
+    NtCurrentProcess, NtQueryInformationProcess, ProcessBasicInformation, PROCESS_BASIC_INFORMATION,
 };
-use snap::raw::Decoder;
-use winapi::_core::ffi::c_void;
-use winapi::shared::minwindef::{DWORD, MAX_PATH, TRUE};
-use winapi::shared::ntdef::HANDLE;
+use winapi::shared::minwindef::{DWORD, FALSE, TRUE};
+use winapi::shared::ntdef::{HANDLE, LARGE_INTEGER, NTSTATUS, NT_SUCCESS, PVOID};
 use winapi::shared::winerror::SUCCEEDED;
 use winapi::um::debugapi::{ContinueDebugEvent, WaitForDebugEvent};
-use winapi::um::fileapi::{
-    CreateFileA, DeleteFileA, GetTempFileNameA, GetTempPathA, WriteFile, CREATE_ALWAYS,
-};
 use winapi::um::handleapi::CloseHandle;
-use winapi::um::memoryapi::ReadProcessMemory;
-use winapi::um::minwinbase::DEBUG_EVENT;
-use winapi::um::processenv::GetCommandLineA;
+use winapi::um::memoryapi::{ReadProcessMemory, VirtualProtectEx, WriteProcessMemory};
+use winapi::um::minwinbase::{
+    DEBUG_EVENT, EXCEPTION_BREAKPOINT, EXCEPTION_DEBUG_EVENT, EXIT_PROCESS_DEBUG_EVENT,
+};
+use winapi::um::processenv::GetCommandLineW;
 use winapi::um::processthreadsapi::{
-    CreateProcessA, GetThreadContext, OpenThread, ResumeThread, SetThreadContext, SuspendThread,
-    PROCESS_INFORMATION, STARTUPINFOA,
+    CreateProcessW, GetExitCodeProcess, GetThreadContext, OpenThread, ResumeThread,
+    SetThreadContext, SuspendThread, TerminateProcess, PROCESS_INFORMATION, STARTUPINFOW,
 };
 use winapi::um::synchapi::WaitForSingleObject;
-use winapi::um::winbase::{DEBUG_PROCESS, INFINITE};
+use winapi::um::winbase::{DebugSetProcessKillOnExit, CREATE_SUSPENDED, DEBUG_PROCESS, INFINITE};
 use winapi::um::winnt::{
-    CONTEXT, CONTEXT_CONTROL, DBG_CONTINUE, FILE_ATTRIBUTE_NORMAL, FILE_SHARE_READ,
-    FILE_SHARE_WRITE, GENERIC_WRITE, THREAD_ALL_ACCESS,
+    CONTEXT, CONTEXT_CONTROL, CONTEXT_INTEGER, DBG_CONTINUE, DBG_EXCEPTION_NOT_HANDLED,
+    IMAGE_DATA_DIRECTORY, IMAGE_DIRECTORY_ENTRY_BASERELOC, IMAGE_DOS_HEADER, IMAGE_DOS_SIGNATURE,
+    IMAGE_FILE_HEADER, IMAGE_FILE_RELOCS_STRIPPED, IMAGE_NT_HEADERS64,
+    IMAGE_NT_OPTIONAL_HDR64_MAGIC, IMAGE_NT_SIGNATURE, IMAGE_OPTIONAL_HEADER64,
+    IMAGE_REL_BASED_ABSOLUTE, IMAGE_REL_BASED_DIR64, IMAGE_SCN_MEM_EXECUTE, IMAGE_SCN_MEM_WRITE,
+    IMAGE_SECTION_HEADER, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_READONLY, PAGE_READWRITE,
+    SECTION_ALL_ACCESS, SEC_COMMIT, THREAD_ALL_ACCESS,
 };
 
-use common::jump_data_table::JumpDataTable;
+use common::cipher::Cipher;
+use common::jump_data_table::{JDTError, JumpDataTable};
+use common::keys::MasterKey;
+use common::profile::Profile;
+use common::thread_context::ThreadContext;
+use common::JumpType;
+
+use crate::loader;
+use crate::profiling::profile_path;
 
 pub fn run() {
-    unsafe {
-        //run_binary();
-        let result = run_binary().unwrap();
-        run_handler(result.0, result.1);
-    }
+    // The nanomite'd binary and JDT are appended to our own image by the infector.
+    let image = match env::current_exe().and_then(fs::read) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("error: couldn't read the runtime image: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let protected = match loader::load(&image) {
+        Ok(protected) => protected,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let exit_code = unsafe {
+        run_binary(&protected.binary).and_then(|proc_info| {
+            run_handler(
+                proc_info,
+                &protected.jdt,
+                &protected.binary,
+                protected.cipher.as_ref(),
+            )
+        })
+    };
+
+    let exit_code = match exit_code {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // Exit with the same code as the protected process. Crashes are reported as an NTSTATUS
+    // exit code, so this covers those too.
+    process::exit(exit_code as i32);
 }
 
-unsafe fn run_handler(proc_info: PROCESS_INFORMATION, proc_name: String) {
+/// Debugs the protected process until it exits, returning its exit code. If a nanomite can't be
+/// emulated, the process is terminated, and the error is returned once it's cleaned up after.
+unsafe fn run_handler(
+    proc_info: PROCESS_INFORMATION,
+    jdt: &JumpDataTable,
+    binary: &[u8],
+    cipher: &dyn Cipher,
+) -> Result<u32, Box<dyn error::Error>> {
     let mut debug_event = mem::zeroed::<DEBUG_EVENT>();
 
     // Calculate the address the image was loaded into.
     let base_addr = read_remote_peb(proc_info.hProcess).ImageBaseAddress as u64;
 
-    // Unpack the JDT
-    let mut decoder = Decoder::new();
-    let unpacked = decoder
-        .decompress_vec(include_bytes!("../../jdt.bin"))
-        .unwrap();
-    let jdt: JumpDataTable = bincode::deserialize(unpacked.as_slice()).unwrap();
+    let master = jdt.master_key(binary);
+
+    // Count the hits of every nanomite, if asked to record a profile.
+    let profile_path = profile_path();
+    let mut profile = profile_path.as_ref().map(|_| Profile::default());
+
+    // The loader of every debugged process breaks into the debugger once the process is
+    // initialised. That breakpoint is meant for us, not the program.
+    let mut initialised = HashSet::new();
+
+    // Why the process was terminated, if it was.
+    let mut failure = None;
 
     loop {
         WaitForDebugEvent(&mut debug_event, INFINITE);
+        let mut continue_status = DBG_CONTINUE;
 
         match debug_event.dwDebugEventCode {
             // Received an exception.
-            1 => handle_int3(debug_event.dwThreadId, base_addr, &jdt),
-            // The process has exited.
-            5 => {
+            EXCEPTION_DEBUG_EVENT => {
+                let code = debug_event.u.Exception().ExceptionRecord.ExceptionCode;
+
+                continue_status = if code != EXCEPTION_BREAKPOINT {
+                    // The program's own exceptions go to its handlers, or terminate it, like
+                    // they would without us.
+                    DBG_EXCEPTION_NOT_HANDLED
+                } else if initialised.insert(debug_event.dwProcessId) {
+                    DBG_CONTINUE
+                } else if debug_event.dwProcessId != proc_info.dwProcessId || failure.is_some() {
+                    // Only the protected process has nanomites.
+                    DBG_EXCEPTION_NOT_HANDLED
+                } else {
+                    let handled = handle_int3(
+                        proc_info.hProcess,
+                        debug_event.dwThreadId,
+                        base_addr,
+                        jdt,
+                        &master,
+                        cipher,
+                        profile.as_mut(),
+                    );
+
+                    // Don't let the thread carry on from a nanomite that wasn't emulated. The
+                    // process is terminated, and reports its exit like any other.
+                    handled.unwrap_or_else(|e| {
+                        TerminateProcess(proc_info.hProcess, 1);
+                        failure = Some(e);
+                        DBG_CONTINUE
+                    })
+                };
+            }
+            // The protected process has exited. Its children are debugged too, but their exits
+            // are only continued.
+            EXIT_PROCESS_DEBUG_EVENT if debug_event.dwProcessId == proc_info.dwProcessId => {
                 break;
             }
             // Something no bueno happened.
@@ -73,10 +166,13 @@ unsafe fn run_handler(proc_info: PROCESS_INFORMATION, proc_name: String) {
         ContinueDebugEvent(
             debug_event.dwProcessId,
             debug_event.dwThreadId,
-            DBG_CONTINUE,
+            continue_status,
         );
     }
 
+    // Children that outlive the protected process are detached when we exit, instead of killed.
+    DebugSetProcessKillOnExit(FALSE);
+
     // Let the process terminate.
     ContinueDebugEvent(
         debug_event.dwProcessId,
@@ -87,15 +183,31 @@ unsafe fn run_handler(proc_info: PROCESS_INFORMATION, proc_name: String) {
     // Wait for the process to terminate.
     WaitForSingleObject(proc_info.hProcess, INFINITE);
 
+    let mut exit_code = 0;
+    GetExitCodeProcess(proc_info.hProcess, &mut exit_code);
+
     // Free handles
     CloseHandle(proc_info.hProcess);
     CloseHandle(proc_info.hThread);
 
-    // Delete the dropped file.
-    DeleteFileA(proc_name.as_ptr() as *const _);
+    if let (Some(profile), Some(path)) = (&profile, &profile_path) {
+        if let Err(e) = profile.merge_into(path) {
+            eprintln!(
+                "warning: couldn't write the profile to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(exit_code),
+    }
 }
 
-unsafe fn read_remote_peb(proc_handle: HANDLE) -> PEB {
+/// Returns the address of the PEB of a process.
+unsafe fn peb_address(proc_handle: HANDLE) -> u64 {
     let mut pbi = mem::zeroed::<PROCESS_BASIC_INFORMATION>();
     let mut written = 0;
 
@@ -108,13 +220,17 @@ unsafe fn read_remote_peb(proc_handle: HANDLE) -> PEB {
         &mut written as *mut _ as _,
     );
 
+    pbi.PebBaseAddress as u64
+}
+
+unsafe fn read_remote_peb(proc_handle: HANDLE) -> PEB {
     let mut peb = mem::zeroed::<PEB>();
     let mut written = 0;
 
     // Read the PEB.
     ReadProcessMemory(
         proc_handle,
-        pbi.PebBaseAddress as *const _,
+        peb_address(proc_handle) as *const _,
         &mut peb as *mut _ as _,
         mem::size_of::<PEB>(),
         &mut written as *mut _ as _,
@@ -123,7 +239,17 @@ unsafe fn read_remote_peb(proc_handle: HANDLE) -> PEB {
     peb
 }
 
-unsafe fn handle_int3(thread_id: DWORD, base_addr: u64, jdt: &JumpDataTable) {
+/// Emulates the nanomite a thread hit, and returns how to continue from the breakpoint. Breakpoints
+/// that aren't nanomites are the program's own, and are left for its exception handlers.
+unsafe fn handle_int3(
+    process: HANDLE,
+    thread_id: DWORD,
+    base_addr: u64,
+    jdt: &JumpDataTable,
+    master: &MasterKey,
+    cipher: &dyn Cipher,
+    profile: Option<&mut Profile>,
+) -> Result<DWORD, Box<dyn error::Error>> {
     // Open a handle to the thread.
     let handle = OpenThread(THREAD_ALL_ACCESS, TRUE, thread_id);
 
@@ -135,111 +261,558 @@ unsafe fn handle_int3(thread_id: DWORD, base_addr: u64, jdt: &JumpDataTable) {
     SuspendThread(handle);
 
     let mut context = mem::zeroed::<CONTEXT>();
-    context.ContextFlags = CONTEXT_CONTROL; // We only need RIP and EFLAGS.
+    context.ContextFlags = CONTEXT_CONTROL | CONTEXT_INTEGER; // We need RIP, RSP, EFLAGS and RCX.
 
     // Get the thread context.
     let ret = GetThreadContext(handle, &mut context);
 
-    // LdrpInitializeProcess will trigger a breakpoint if the process is being debugged when it is
-    // created. This function will handle that breakpoint, but GetThreadContext only allows us to
-    // get the context of threads we own. Thus, if GetThreadContext fails we should just move on.
+    // Without the context, the breakpoint can't be emulated, so it's the program's problem.
     if ret == 0 {
         ResumeThread(handle);
         CloseHandle(handle);
-        return;
+        return Ok(DBG_EXCEPTION_NOT_HANDLED);
     }
 
-    let jump_data = jdt.get_jump_data(context.Rip - 1 - base_addr);
+    let load_bias = base_addr.wrapping_sub(jdt.layout.base);
+    let key = jdt.layout.key(context.Rip - 1, load_bias);
+    let mut thread = WindowsThreadContext {
+        process,
+        context,
+        pointer_size: jdt.layout.bitness as u64 / 8,
+    };
+
+    // The entry key is bound to the code around the nanomite, as it is in memory now.
+    let jump_data = match jdt.read_code_hash(key, load_bias, &mut thread) {
+        Ok(Some(code_hash)) => jdt.get_jump_data(key, &code_hash, master, cipher),
+        Ok(None) => Err(JDTError::NotFound),
+        Err(e) => {
+            ResumeThread(handle);
+            CloseHandle(handle);
+            return Err(format!("couldn't read the code around a nanomite: {}", e).into());
+        }
+    };
+
+    let jump_data = match jump_data {
+        Ok(jump_data) if jump_data.jump_type() != JumpType::Passthrough => jump_data,
+        // Don't guess where a tampered entry jumps to. The process is terminated instead.
+        Err(e @ JDTError::Tampered(_)) | Err(e @ JDTError::Malformed(_)) => {
+            ResumeThread(handle);
+            CloseHandle(handle);
+            return Err(e.into());
+        }
+        // A breakpoint of the program's own. Leave the context alone, so its handlers see the
+        // same exception they would without us.
+        _ => {
+            ResumeThread(handle);
+            CloseHandle(handle);
+            return Ok(DBG_EXCEPTION_NOT_HANDLED);
+        }
+    };
 
-    // If there was an error getting the jump data, jump to the next instruction and hope for the
-    // best.
-    if jump_data.is_err() {
-        context.Rip += 1;
-        SetThreadContext(handle, &context);
-        ResumeThread(handle);
-        CloseHandle(handle);
-        return;
+    if let Some(profile) = profile {
+        profile.record(key);
     }
 
-    let jump_data = jump_data.unwrap();
+    // RIP points after the int 3, rewind it to the nanomite.
+    thread.set_ip(context.Rip - 1);
 
-    // Add the signed offset to RIP.
-    let offset = jump_data.get_ip_offset(context.EFlags as u64);
-    context.Rip = (context.Rip as i64 + offset as i64 - 1) as u64;
+    // Calls and returns access the stack, which can fail. The context is left alone then.
+    let emulated = jump_data.emulate(&mut thread);
 
-    // Update RIP, resume the thread, and get rid of our handle.
-    SetThreadContext(handle, &context);
+    // Update the context, resume the thread, and get rid of our handle.
+    if emulated.is_ok() {
+        SetThreadContext(handle, &thread.context);
+    }
     ResumeThread(handle);
     CloseHandle(handle);
+
+    emulated.map_err(|e| format!("couldn't emulate the nanomite at offset 0x{:X}: {}", key, e))?;
+
+    Ok(DBG_CONTINUE)
 }
 
-unsafe fn run_binary() -> Result<(PROCESS_INFORMATION, String), Box<dyn error::Error>> {
-    let binary = include_bytes!("../../nanomite.bin");
+/// A debugged thread's context, and access to its process's memory.
+struct WindowsThreadContext {
+    process: HANDLE,
+    context: CONTEXT,
+    pointer_size: u64,
+}
 
-    // decompress the binary.
-    let mut decoder = Decoder::new();
-    let d_bin = decoder.decompress_vec(binary)?;
+impl ThreadContext for WindowsThreadContext {
+    fn ip(&self) -> u64 {
+        self.context.Rip
+    }
 
-    // Get the path to %temp%.
-    let mut buf: [u8; MAX_PATH] = [0; MAX_PATH];
-    GetTempPathA(MAX_PATH as u32, buf.as_mut_ptr() as *mut _);
+    fn set_ip(&mut self, ip: u64) {
+        self.context.Rip = ip;
+    }
 
-    let prefix = CString::new("").unwrap();
+    fn flags(&self) -> u64 {
+        self.context.EFlags as u64
+    }
 
-    // Get a random file name in that directory.
-    let mut temp_file_name_buf: [u8; MAX_PATH] = [0; MAX_PATH];
-    GetTempFileNameA(
-        buf.as_mut_ptr() as *mut _,
-        prefix.as_ptr(),
-        0,
-        temp_file_name_buf.as_mut_ptr() as *mut _,
-    );
+    fn sp(&self) -> u64 {
+        self.context.Rsp
+    }
 
-    // Create a file with the generated name to write to.
-    let h_file = CreateFileA(
-        temp_file_name_buf.as_ptr() as *mut _,
-        GENERIC_WRITE,
-        FILE_SHARE_READ | FILE_SHARE_WRITE,
-        null_mut(),
-        CREATE_ALWAYS,
-        FILE_ATTRIBUTE_NORMAL,
-        null_mut(),
-    );
+    fn set_sp(&mut self, sp: u64) {
+        self.context.Rsp = sp;
+    }
 
-    // Write the nanomite'd binary to the new file.
-    let mut written = 0;
-    WriteFile(
-        h_file,
-        d_bin.as_ptr() as *const c_void,
-        d_bin.len() as u32,
-        &mut written,
-        null_mut(),
-    );
+    fn cx(&self) -> u64 {
+        self.context.Rcx
+    }
+
+    fn set_cx(&mut self, cx: u64) {
+        self.context.Rcx = cx;
+    }
+
+    fn pointer_size(&self) -> u64 {
+        self.pointer_size
+    }
+
+    fn read_memory(&mut self, addr: u64, buf: &mut [u8]) -> Result<(), Box<dyn error::Error>> {
+        let mut read = 0;
+        let ret = unsafe {
+            ReadProcessMemory(
+                self.process,
+                addr as *const _,
+                buf.as_mut_ptr() as *mut _,
+                buf.len(),
+                &mut read,
+            )
+        };
+
+        if ret == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(())
+    }
+
+    fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<(), Box<dyn error::Error>> {
+        let mut written = 0;
+        let ret = unsafe {
+            WriteProcessMemory(
+                self.process,
+                addr as *mut _,
+                data.as_ptr() as *const _,
+                data.len(),
+                &mut written,
+            )
+        };
+
+        if ret == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(())
+    }
+}
+
+/// The headers of the nanomite'd image.
+struct Headers {
+    /// Where the NT headers start.
+    nt_offset: usize,
+    nt: IMAGE_NT_HEADERS64,
+    sections: Vec<IMAGE_SECTION_HEADER>,
+}
+
+impl Headers {
+    fn read(binary: &[u8]) -> Result<Headers, Box<dyn error::Error>> {
+        let malformed = "the protected program isn't a valid PE image";
+
+        let dos: IMAGE_DOS_HEADER = read_struct(binary, 0).ok_or(malformed)?;
+        let nt_offset = dos.e_lfanew as usize;
+        let nt: IMAGE_NT_HEADERS64 = read_struct(binary, nt_offset).ok_or(malformed)?;
+
+        if dos.e_magic != IMAGE_DOS_SIGNATURE || nt.Signature != IMAGE_NT_SIGNATURE {
+            return Err(malformed.into());
+        }
+
+        // The runtime debugs the program through 64-bit thread contexts, and runs it in a 64-bit
+        // process.
+        if nt.OptionalHeader.Magic != IMAGE_NT_OPTIONAL_HDR64_MAGIC {
+            return Err("only 64-bit programs can be run on Windows".into());
+        }
+
+        let first_section = nt_offset
+            + mem::size_of::<DWORD>()
+            + mem::size_of::<IMAGE_FILE_HEADER>()
+            + nt.FileHeader.SizeOfOptionalHeader as usize;
+        let sections = (0..nt.FileHeader.NumberOfSections as usize)
+            .map(|i| {
+                read_struct(
+                    binary,
+                    first_section + i * mem::size_of::<IMAGE_SECTION_HEADER>(),
+                )
+            })
+            .collect::<Option<Vec<IMAGE_SECTION_HEADER>>>()
+            .ok_or(malformed)?;
+
+        Ok(Headers {
+            nt_offset,
+            nt,
+            sections,
+        })
+    }
 
-    // Flush the changes.
-    CloseHandle(h_file);
+    /// Whether the image can be loaded at another address than its preferred base.
+    fn relocatable(&self) -> bool {
+        self.nt.FileHeader.Characteristics & IMAGE_FILE_RELOCS_STRIPPED == 0
+            && self.relocations().Size != 0
+    }
 
-    // Create the new process, and debug it.
-    let mut startup_info = mem::zeroed::<STARTUPINFOA>();
+    fn relocations(&self) -> IMAGE_DATA_DIRECTORY {
+        self.nt.OptionalHeader.DataDirectory[IMAGE_DIRECTORY_ENTRY_BASERELOC as usize]
+    }
+}
+
+/// Reads a `T` from `data` at `offset`, if it's all there.
+fn read_struct<T>(data: &[u8], offset: usize) -> Option<T> {
+    let bytes = data.get(offset..offset.checked_add(mem::size_of::<T>())?)?;
+    Some(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Starts the decrypted binary under the debugger, and returns the process.
+///
+/// The binary is never written to a file. A suspended copy of the runtime is started instead, its
+/// image is unmapped, and the binary is mapped in its place, from a section backed by memory. The
+/// process then runs the binary, with the loader resolving its imports, as if it had been started
+/// from it.
+unsafe fn run_binary(binary: &[u8]) -> Result<PROCESS_INFORMATION, Box<dyn error::Error>> {
+    let headers = Headers::read(binary)?;
+
+    let host: Vec<u16> = env::current_exe()?
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect();
+
+    // Create the process that'll run the binary, and debug it.
+    let mut startup_info = mem::zeroed::<STARTUPINFOW>();
     let mut process_info = mem::zeroed::<PROCESS_INFORMATION>();
 
-    CreateProcessA(
-        temp_file_name_buf.as_ptr() as *const _,
-        GetCommandLineA(),
+    let created = CreateProcessW(
+        host.as_ptr(),
+        GetCommandLineW(),
         null_mut(),
         null_mut(),
         TRUE,
-        DEBUG_PROCESS,
+        DEBUG_PROCESS | CREATE_SUSPENDED,
         null_mut(),
         null_mut(),
         &mut startup_info,
         &mut process_info,
     );
 
-    Ok((
-        process_info,
-        std::str::from_utf8(&temp_file_name_buf)
-            .unwrap()
-            .to_string(),
-    ))
+    if created == 0 {
+        return Err(format!(
+            "couldn't start the protected process: {}",
+            io::Error::last_os_error()
+        )
+        .into());
+    }
+
+    if let Err(e) = map_binary(&process_info, binary, &headers) {
+        TerminateProcess(process_info.hProcess, 1);
+        CloseHandle(process_info.hProcess);
+        CloseHandle(process_info.hThread);
+        return Err(format!("couldn't load the protected program: {}", e).into());
+    }
+
+    ResumeThread(process_info.hThread);
+
+    Ok(process_info)
+}
+
+/// Replaces the image of a suspended process with the binary, and points its first thread at the
+/// binary's entry point.
+unsafe fn map_binary(
+    process_info: &PROCESS_INFORMATION,
+    binary: &[u8],
+    headers: &Headers,
+) -> Result<(), Box<dyn error::Error>> {
+    let process = process_info.hProcess;
+    let optional = &headers.nt.OptionalHeader;
+
+    // Make room for the binary at its preferred base. Nothing of the runtime has run in the
+    // process yet, and nothing will.
+    let peb = peb_address(process);
+    let host_base = read_remote_peb(process).ImageBaseAddress;
+    nt_result(
+        "unmap the runtime",
+        NtUnmapViewOfSection(process, host_base),
+    )?;
+
+    let mut section = null_mut();
+    let mut size = mem::zeroed::<LARGE_INTEGER>();
+    *size.QuadPart_mut() = optional.SizeOfImage as i64;
+
+    nt_result(
+        "create a section",
+        NtCreateSection(
+            &mut section,
+            SECTION_ALL_ACCESS,
+            null_mut(),
+            &mut size,
+            PAGE_EXECUTE_READWRITE,
+            SEC_COMMIT,
+            null_mut(),
+        ),
+    )?;
+
+    let mapped = map_section(section, process, binary, headers);
+
+    // The views keep the section alive.
+    CloseHandle(section);
+    let base = mapped?;
+
+    // The loader finds the image through the PEB.
+    let field = peb + mem::offset_of!(PEB, ImageBaseAddress) as u64;
+    let ret = WriteProcessMemory(
+        process,
+        field as *mut _,
+        &base as *const u64 as *const _,
+        mem::size_of::<u64>(),
+        null_mut(),
+    );
+
+    if ret == 0 {
+        return Err(format!("couldn't update the PEB: {}", io::Error::last_os_error()).into());
+    }
+
+    // The first thread starts in RtlUserThreadStart, which calls the entry point passed in RCX.
+    let mut context = mem::zeroed::<CONTEXT>();
+    context.ContextFlags = CONTEXT_INTEGER;
+
+    if GetThreadContext(process_info.hThread, &mut context) == 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    context.Rcx = base + optional.AddressOfEntryPoint as u64;
+
+    if SetThreadContext(process_info.hThread, &context) == 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+/// Maps the section into the process, at the binary's preferred base if it's free, and fills it
+/// with the binary, laid out and relocated like the loader would. Returns where it was mapped.
+unsafe fn map_section(
+    section: HANDLE,
+    process: HANDLE,
+    binary: &[u8],
+    headers: &Headers,
+) -> Result<u64, Box<dyn error::Error>> {
+    let optional = &headers.nt.OptionalHeader;
+
+    let mut base = optional.ImageBase as PVOID;
+    let mut view_size = 0;
+    let mut status = NtMapViewOfSection(
+        section,
+        process,
+        &mut base,
+        0,
+        0,
+        null_mut(),
+        &mut view_size,
+        ViewUnmap,
+        0,
+        PAGE_EXECUTE_READWRITE,
+    );
+
+    // Something else is there, map it anywhere and relocate it.
+    if !NT_SUCCESS(status) && headers.relocatable() {
+        base = null_mut();
+        view_size = 0;
+        status = NtMapViewOfSection(
+            section,
+            process,
+            &mut base,
+            0,
+            0,
+            null_mut(),
+            &mut view_size,
+            ViewUnmap,
+            0,
+            PAGE_EXECUTE_READWRITE,
+        );
+    }
+
+    nt_result("map the protected program", status)?;
+    let base = base as u64;
+
+    // Lay the image out through a view of our own.
+    let mut local = null_mut();
+    let mut local_size = 0;
+    nt_result(
+        "map the protected program locally",
+        NtMapViewOfSection(
+            section,
+            NtCurrentProcess,
+            &mut local,
+            0,
+            0,
+            null_mut(),
+            &mut local_size,
+            ViewUnmap,
+            0,
+            PAGE_READWRITE,
+        ),
+    )?;
+
+    let image = slice::from_raw_parts_mut(local as *mut u8, optional.SizeOfImage as usize);
+    let laid_out = lay_out(image, binary, headers, base);
+    NtUnmapViewOfSection(NtCurrentProcess, local);
+    laid_out?;
+
+    // Protect every section like the loader would. The headers are read only.
+    let mut old = 0;
+    VirtualProtectEx(
+        process,
+        base as *mut _,
+        optional.SizeOfHeaders as usize,
+        PAGE_READONLY,
+        &mut old,
+    );
+
+    for header in &headers.sections {
+        let size = section_size(header);
+
+        if size != 0 {
+            VirtualProtectEx(
+                process,
+                (base + header.VirtualAddress as u64) as *mut _,
+                size,
+                protection(header.Characteristics),
+                &mut old,
+            );
+        }
+    }
+
+    Ok(base)
+}
+
+/// Copies the headers and sections of the binary to where they're loaded in `image`, and
+/// relocates it to `base`.
+fn lay_out(
+    image: &mut [u8],
+    binary: &[u8],
+    headers: &Headers,
+    base: u64,
+) -> Result<(), Box<dyn error::Error>> {
+    let malformed = "the protected program isn't a valid PE image";
+    let optional = &headers.nt.OptionalHeader;
+
+    let size = optional.SizeOfHeaders as usize;
+    let data = binary.get(..size).ok_or(malformed)?;
+    image
+        .get_mut(..size)
+        .ok_or(malformed)?
+        .copy_from_slice(data);
+
+    for header in &headers.sections {
+        let start = header.PointerToRawData as usize;
+        let address = header.VirtualAddress as usize;
+        // The raw data is padded to the file alignment, and the rest is zeroed.
+        let size = (header.SizeOfRawData as usize).min(section_size(header));
+
+        let data = binary.get(start..start + size).ok_or(malformed)?;
+        image
+            .get_mut(address..address + size)
+            .ok_or(malformed)?
+            .copy_from_slice(data);
+    }
+
+    let delta = base.wrapping_sub(optional.ImageBase);
+
+    if delta != 0 {
+        relocate(image, headers.relocations(), delta)?;
+
+        // The loader relocates the image again if the base in its headers is off.
+        let field = headers.nt_offset
+            + mem::offset_of!(IMAGE_NT_HEADERS64, OptionalHeader)
+            + mem::offset_of!(IMAGE_OPTIONAL_HEADER64, ImageBase);
+        image[field..field + 8].copy_from_slice(&base.to_le_bytes());
+    }
+
+    Ok(())
+}
+
+/// Applies the base relocations of a laid out image, moving it by `delta`.
+fn relocate(
+    image: &mut [u8],
+    directory: IMAGE_DATA_DIRECTORY,
+    delta: u64,
+) -> Result<(), Box<dyn error::Error>> {
+    let malformed = "the base relocations of the protected program are malformed";
+    let read_u32 = |image: &[u8], at: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(
+            image.get(at..at + 4)?.try_into().unwrap(),
+        ))
+    };
+
+    let mut block = directory.VirtualAddress as usize;
+    let end = block + directory.Size as usize;
+
+    while block + 8 <= end {
+        let page = read_u32(image, block).ok_or(malformed)? as usize;
+        let size = read_u32(image, block + 4).ok_or(malformed)? as usize;
+
+        if size < 8 || block + size > end {
+            return Err(malformed.into());
+        }
+
+        for entry in (block + 8..block + size).step_by(2) {
+            let entry = image.get(entry..entry + 2).ok_or(malformed)?;
+            let entry = u16::from_le_bytes(entry.try_into().unwrap());
+            let address = page + (entry & 0xFFF) as usize;
+
+            match entry >> 12 {
+                IMAGE_REL_BASED_ABSOLUTE => {}
+                IMAGE_REL_BASED_DIR64 => {
+                    let field = image.get_mut(address..address + 8).ok_or(malformed)?;
+                    let value = u64::from_le_bytes((&*field).try_into().unwrap());
+                    field.copy_from_slice(&value.wrapping_add(delta).to_le_bytes());
+                }
+                kind => return Err(format!("unsupported base relocation type {}", kind).into()),
+            }
+        }
+
+        block += size;
+    }
+
+    Ok(())
+}
+
+/// Returns the size of an image section once it's loaded. Some linkers leave the virtual size out.
+fn section_size(header: &IMAGE_SECTION_HEADER) -> usize {
+    match unsafe { *header.Misc.VirtualSize() } {
+        0 => header.SizeOfRawData as usize,
+        size => size as usize,
+    }
+}
+
+/// Returns the page protection of an image section.
+fn protection(characteristics: DWORD) -> DWORD {
+    let execute = characteristics & IMAGE_SCN_MEM_EXECUTE != 0;
+    let write = characteristics & IMAGE_SCN_MEM_WRITE != 0;
+
+    match (execute, write) {
+        (true, true) => PAGE_EXECUTE_READWRITE,
+        (true, false) => PAGE_EXECUTE_READ,
+        (false, true) => PAGE_READWRITE,
+        (false, false) => PAGE_READONLY,
+    }
+}
+
+fn nt_result(what: &str, status: NTSTATUS) -> Result<(), String> {
+    if NT_SUCCESS(status) {
+        Ok(())
+    } else {
+        Err(format!("couldn't {}: NTSTATUS 0x{:X}", what, status))
+    }
 }
@@ -0,0 +1,28 @@
+//! Every kind of branch the infector can replace must be emulated correctly.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+#[test]
+fn all_branch_kinds() {
+    let program = common::protect(
+        "test/test.c",
+        "all_branch_kinds",
+        &["-O0"],
+        &["--branches", "jcc,jmp,call,ret"],
+    );
+
+    for args in [["1", "2"], ["2", "1"], ["7", "7"]].iter() {
+        program.assert_same_behaviour(args);
+    }
+}
+
+#[test]
+fn counter_branches() {
+    let program = common::protect("test/counter.c", "counter_branches", &["-O0"], &[]);
+
+    for args in [["5"], ["1"], ["0"]].iter() {
+        program.assert_same_behaviour(args);
+    }
+}
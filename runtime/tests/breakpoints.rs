@@ -0,0 +1,84 @@
+//! Breakpoints of the program's own are delivered to it, not emulated.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::path::Path;
+use std::process::Command;
+
+const INFECT_ARGS: [&str; 2] = ["--branches", "jcc,jmp,call,ret"];
+
+#[test]
+fn delivers_own_breakpoints() {
+    let program = common::protect(
+        "test/breakpoints.c",
+        "delivers_own_breakpoints",
+        &["-O0"],
+        &INFECT_ARGS,
+    );
+
+    for args in [
+        &["0"][..],
+        &["1"],
+        &["4"],
+        &["1", "unhandled"],
+        &["4", "blocked"],
+    ]
+    .iter()
+    {
+        program.assert_same_behaviour(args);
+    }
+
+    // The int3 that starts a function right after padding is a breakpoint too.
+    assert_eq!(passthrough_count(&program.original), 2);
+}
+
+#[test]
+fn delivers_own_breakpoints_recursive() {
+    let args = [&INFECT_ARGS[..], &["--disassembly", "recursive"]].concat();
+    let program = common::protect(
+        "test/breakpoints.c",
+        "delivers_own_breakpoints_recursive",
+        &["-O0"],
+        &args,
+    );
+
+    for args in [&["4"][..], &["4", "blocked"]].iter() {
+        program.assert_same_behaviour(args);
+    }
+}
+
+/// Returns the number of passthrough entries a dry run of the infector records.
+fn passthrough_count(original: &Path) -> usize {
+    let dry_run = Command::new(common::infector())
+        .args(["infect", "--dry-run"])
+        .arg(original)
+        .args(INFECT_ARGS)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&dry_run.stdout);
+
+    stdout
+        .lines()
+        .filter_map(|x| x.strip_prefix("breakpoints: "))
+        .map(|x| x.parse::<usize>().unwrap())
+        .sum()
+}
+
+#[test]
+fn ignores_cc_immediates() {
+    let program = common::protect(
+        "test/cc_bytes.c",
+        "ignores_cc_immediates",
+        &["-O0"],
+        &INFECT_ARGS,
+    );
+
+    for args in [&["0"][..], &["5"], &["300"]].iter() {
+        program.assert_same_behaviour(args);
+    }
+
+    // Only the int3 is a breakpoint, even though it follows a 0xCC immediate.
+    assert_eq!(passthrough_count(&program.original), 1);
+}
@@ -0,0 +1,27 @@
+//! Infections encrypted with every cipher backend run the same as the original program.
+
+mod common;
+
+fn assert_cipher(cipher: &str) {
+    let name = format!("cipher_{}", cipher.replace('-', "_"));
+
+    let program = common::protect(
+        "test/test.c",
+        &name,
+        &["-O0"],
+        &["--cipher", cipher, "--seed", "1"],
+    );
+
+    program.assert_same_behaviour(&["1", "2"]);
+    program.assert_same_behaviour(&["3", "2"]);
+}
+
+#[test]
+fn aes_soft() {
+    assert_cipher("aes-soft");
+}
+
+#[test]
+fn chacha20() {
+    assert_cipher("chacha20");
+}
@@ -0,0 +1,160 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::Once;
+
+static BUILD_INFECTOR: Once = Once::new();
+static BUILD_PROFILING_STUB: Once = Once::new();
+
+/// A C program, compiled and protected with nanomites.
+pub struct TestProgram {
+    pub original: PathBuf,
+    pub protected: PathBuf,
+}
+
+impl TestProgram {
+    /// Runs both the original and protected program, and asserts they behave the same.
+    #[allow(dead_code)] // Not every test crate uses it.
+    pub fn assert_same_behaviour(&self, args: &[&str]) {
+        let original = run(&self.original, args);
+        let protected = run(&self.protected, args);
+
+        assert_eq!(
+            String::from_utf8_lossy(&original.stdout),
+            String::from_utf8_lossy(&protected.stdout),
+            "stdout differs for {:?}",
+            args
+        );
+        assert_eq!(
+            original.status, protected.status,
+            "status differs for {:?}",
+            args
+        );
+    }
+}
+
+fn run(path: &Path, args: &[&str]) -> Output {
+    Command::new(path).args(args).output().unwrap()
+}
+
+/// The directory the runtime stub, and the infector, are built to.
+fn target_dir() -> &'static Path {
+    Path::new(env!("CARGO_BIN_EXE_runtime")).parent().unwrap()
+}
+
+/// Returns the path to the infector, building it the first time.
+pub fn infector() -> PathBuf {
+    BUILD_INFECTOR.call_once(|| {
+        let mut cargo = Command::new(env!("CARGO"));
+        cargo.args(["build", "--package", "infector"]);
+
+        if target_dir().ends_with("release") {
+            cargo.arg("--release");
+        }
+
+        assert!(cargo.status().unwrap().success(), "couldn't build infector");
+    });
+
+    target_dir().join("infector")
+}
+
+/// Returns the path to a runtime stub built with the `profile` feature, building it the first
+/// time. It has a target directory of its own, so the stub other tests use is never replaced.
+#[allow(dead_code)] // Not every test crate uses it.
+pub fn profiling_stub() -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("profiling");
+
+    BUILD_PROFILING_STUB.call_once(|| {
+        let mut cargo = Command::new(env!("CARGO"));
+        cargo
+            .args(["build", "--package", "runtime", "--features", "profile"])
+            .arg("--target-dir")
+            .arg(&dir);
+
+        if target_dir().ends_with("release") {
+            cargo.arg("--release");
+        }
+
+        assert!(
+            cargo.status().unwrap().success(),
+            "couldn't build the profiling stub"
+        );
+    });
+
+    dir.join(target_dir().file_name().unwrap()).join("runtime")
+}
+
+/// Compiles `source` with the system C compiler, infects it with `infect_args` and packs it with
+/// the runtime stub. Panics if the program can't be compiled, e.g. because there's no static libc,
+/// so a missing toolchain fails the test instead of skipping it.
+#[allow(dead_code)] // Not every test crate uses it.
+pub fn protect(source: &str, name: &str, cflags: &[&str], infect_args: &[&str]) -> TestProgram {
+    protect_with_stub(
+        source,
+        name,
+        cflags,
+        infect_args,
+        Path::new(env!("CARGO_BIN_EXE_runtime")),
+    )
+}
+
+/// Like `protect`, but packs the program with the given runtime stub.
+#[allow(dead_code)] // Not every test crate uses it.
+pub fn protect_with_stub(
+    source: &str,
+    name: &str,
+    cflags: &[&str],
+    infect_args: &[&str],
+    stub: &Path,
+) -> TestProgram {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+    let work = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::create_dir_all(&work).unwrap();
+
+    let original = work.join(name);
+    let protected = work.join(format!("{}_protected", name));
+
+    let compiled = Command::new("cc")
+        .args(cflags)
+        .arg("-o")
+        .arg(&original)
+        .arg(root.join(source))
+        .status();
+
+    assert!(
+        matches!(compiled, Ok(status) if status.success()),
+        "couldn't compile {} with {:?}, is a C compiler for that layout installed?",
+        source,
+        cflags
+    );
+
+    let infected = Command::new(infector())
+        .arg("-q")
+        .arg("infect")
+        .arg(&original)
+        .args(infect_args)
+        .arg("--out-dir")
+        .arg(&work)
+        .status()
+        .unwrap();
+    assert!(infected.success(), "couldn't infect {}", name);
+
+    let packed = Command::new(infector())
+        .arg("-q")
+        .arg("pack")
+        .arg("--stub")
+        .arg(stub)
+        .arg("--binary")
+        .arg(work.join("nanomite.bin"))
+        .arg("--jdt")
+        .arg(work.join("jdt.bin"))
+        .arg("--output")
+        .arg(&protected)
+        .status()
+        .unwrap();
+    assert!(packed.success(), "couldn't pack {}", name);
+
+    TestProgram {
+        original,
+        protected,
+    }
+}
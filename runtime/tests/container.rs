@@ -0,0 +1,120 @@
+//! Mismatched, corrupt or tampered infections are rejected with a clear error.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+use ::common::container::{self, Content};
+use ::common::jump_data_table::JumpDataTable;
+
+/// Packs the binary and jump data table directly, without the checks `infector pack` makes, and
+/// returns what the runtime prints to stderr.
+fn run_packed(work: &Path, binary: &[u8], jdt: &[u8]) -> String {
+    let stub = fs::read(env!("CARGO_BIN_EXE_runtime")).unwrap();
+    let path = work.join("packed");
+
+    fs::write(&path, ::common::packed::pack(&stub, binary, jdt)).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = Command::new(&path).arg("1").arg("2").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn rejects_mismatched_files() {
+    let first = common::protect("test/test.c", "container_first", &["-O0"], &["--seed", "1"]);
+    let second = common::protect(
+        "test/test.c",
+        "container_second",
+        &["-O0"],
+        &["--seed", "2"],
+    );
+
+    first.assert_same_behaviour(&["1", "2"]);
+
+    let first_dir = first.original.parent().unwrap();
+    let second_dir = second.original.parent().unwrap();
+    let binary = fs::read(first_dir.join("nanomite.bin")).unwrap();
+    let jdt = fs::read(second_dir.join("jdt.bin")).unwrap();
+
+    let packed = Command::new(common::infector())
+        .arg("pack")
+        .arg("--stub")
+        .arg(env!("CARGO_BIN_EXE_runtime"))
+        .arg("--binary")
+        .arg(first_dir.join("nanomite.bin"))
+        .arg("--jdt")
+        .arg(second_dir.join("jdt.bin"))
+        .arg("--output")
+        .arg(first_dir.join("mismatched"))
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&packed.stderr);
+
+    assert!(!packed.status.success());
+    assert!(stderr.contains("different infections"), "{}", stderr);
+
+    let stderr = run_packed(first_dir, &binary, &jdt);
+    assert!(stderr.contains("different infections"), "{}", stderr);
+
+    let mut corrupt = fs::read(first_dir.join("jdt.bin")).unwrap();
+    *corrupt.last_mut().unwrap() ^= 1;
+
+    let stderr = run_packed(first_dir, &binary, &corrupt);
+    assert!(stderr.contains("corrupt"), "{}", stderr);
+}
+
+#[test]
+fn rejects_tampered_entries() {
+    let program = common::protect("test/test.c", "container_tampered", &["-O0"], &[]);
+
+    let dir = program.original.parent().unwrap();
+    let binary = fs::read(dir.join("nanomite.bin")).unwrap();
+    let jdt = fs::read(dir.join("jdt.bin")).unwrap();
+
+    // Rotate the entries, so each one is stored under the key of the next. Every entry is still
+    // intact, and the container is rewritten with a valid CRC, so only authentication catches it.
+    let (header, payload) = container::unwrap(&jdt, Content::JumpDataTable).unwrap();
+    let payload = snap::raw::Decoder::new().decompress_vec(payload).unwrap();
+    let mut table: JumpDataTable = bincode::deserialize(&payload).unwrap();
+
+    let keys: Vec<u64> = table.table.keys().copied().collect();
+    let mut entries: Vec<_> = keys.iter().map(|x| table.table[x].clone()).collect();
+    entries.rotate_left(1);
+    table.table = keys.into_iter().zip(entries).collect();
+
+    let payload = bincode::serialize(&table).unwrap();
+    let payload = snap::raw::Encoder::new().compress_vec(&payload).unwrap();
+    let tampered = container::wrap(&header, &payload);
+
+    let stderr = run_packed(dir, &binary, &tampered);
+    assert!(stderr.contains("failed authentication"), "{}", stderr);
+}
+
+#[test]
+fn rejects_tampered_binary() {
+    let program = common::protect("test/test.c", "container_tampered_bin", &["-O0"], &[]);
+
+    let dir = program.original.parent().unwrap();
+    let binary = fs::read(dir.join("nanomite.bin")).unwrap();
+    let jdt = fs::read(dir.join("jdt.bin")).unwrap();
+
+    // The binary is encrypted, so it doesn't decompress on its own.
+    let (header, payload) = container::unwrap(&binary, Content::Binary).unwrap();
+    assert!(snap::raw::Decoder::new().decompress_vec(payload).is_err());
+
+    // Flip a bit of the ciphertext, and rewrite the container with a valid CRC.
+    let mut payload = payload.to_vec();
+    *payload.last_mut().unwrap() ^= 1;
+    let tampered = container::wrap(&header, &payload);
+
+    let stderr = run_packed(dir, &tampered, &jdt);
+    assert!(stderr.contains("failed authentication"), "{}", stderr);
+}
@@ -0,0 +1,42 @@
+//! Only a seeded random subset of the branches is protected.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::process::Command;
+
+const INFECT_ARGS: [&str; 8] = [
+    "--branches",
+    "jcc,jmp,call,ret",
+    "--density",
+    "0.5",
+    "--max-nanomites",
+    "10",
+    "--seed",
+    "1",
+];
+
+#[test]
+fn seeded_density() {
+    let program = common::protect("test/test.c", "seeded_density", &["-O0"], &INFECT_ARGS);
+
+    for args in [["1", "2"], ["2", "1"], ["7", "7"]].iter() {
+        program.assert_same_behaviour(args);
+    }
+
+    let dry_run = || {
+        let output = Command::new(common::infector())
+            .args(["infect", "--dry-run", "--decoys", "0"])
+            .arg(&program.original)
+            .args(INFECT_ARGS)
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let first = dry_run();
+    assert!(first.contains("branches skipped by density"), "{}", first);
+    assert!(first.contains("dry run: 10 jdt entries"), "{}", first);
+    assert_eq!(first, dry_run());
+}
@@ -0,0 +1,43 @@
+//! Recursive disassembly must leave data embedded in the code untouched.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+#[test]
+fn recursive_skips_inline_data() {
+    let program = common::protect(
+        "test/inline_data.c",
+        "recursive_skips_inline_data",
+        &["-O2"],
+        &[
+            "--branches",
+            "jcc,jmp,call,ret",
+            "--disassembly",
+            "recursive",
+        ],
+    );
+
+    for args in [["-3"], ["0"], ["5"], ["500"]].iter() {
+        program.assert_same_behaviour(args);
+    }
+}
+
+#[test]
+fn recursive_static_binary() {
+    let program = common::protect(
+        "test/test.c",
+        "recursive_static_binary",
+        &["-O2", "-static"],
+        &[
+            "--branches",
+            "jcc,jmp,call,ret",
+            "--disassembly",
+            "recursive",
+        ],
+    );
+
+    for args in [["1", "2"], ["2", "1"], ["7", "7"]].iter() {
+        program.assert_same_behaviour(args);
+    }
+}
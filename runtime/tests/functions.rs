@@ -0,0 +1,56 @@
+//! Nanomites can be limited to some functions.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::process::Command;
+
+const INFECT_ARGS: [&str; 6] = [
+    "--branches",
+    "jcc,jmp,call,ret",
+    "--functions",
+    "comp*,main",
+    "--exclude-functions",
+    "main",
+];
+
+#[test]
+fn selected_functions() {
+    let program = common::protect("test/test.c", "selected_functions", &["-O0"], &INFECT_ARGS);
+
+    for args in [["1", "2"], ["2", "1"], ["7", "7"]].iter() {
+        program.assert_same_behaviour(args);
+    }
+
+    let dry_run = Command::new(common::infector())
+        .args(["infect", "--dry-run"])
+        .arg(&program.original)
+        .args(INFECT_ARGS)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&dry_run.stdout);
+
+    let per_function = stdout.split("nanomites per function:\n").nth(1).unwrap();
+    let counts: Vec<(&str, usize)> = per_function
+        .lines()
+        .take_while(|x| x.starts_with("  "))
+        .map(|x| {
+            let mut fields = x.split_whitespace();
+            let count = fields.next().unwrap().parse().unwrap();
+            (fields.next().unwrap(), count)
+        })
+        .collect();
+
+    // Excluding a function wins over selecting it.
+    assert!(counts.contains(&("main", 0)), "{}", stdout);
+    assert!(counts.iter().any(|x| x.0.starts_with("comp")), "{}", stdout);
+    assert!(
+        counts
+            .iter()
+            .filter(|x| x.0.starts_with("comp"))
+            .all(|x| x.1 > 0),
+        "{}",
+        stdout
+    );
+}
@@ -0,0 +1,158 @@
+//! `infector inspect` decrypts every entry, and finds an `int3` at each one, decoys included.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use ::common::container::{self, Content};
+use ::common::jump_data_table::JumpDataTable;
+use ::common::{payload, JumpType};
+
+/// Runs `infector inspect` on the files in `dir`, with a JSON report.
+fn inspect(dir: &Path) -> Output {
+    Command::new(common::infector())
+        .arg("inspect")
+        .arg("--binary")
+        .arg(dir.join("nanomite.bin"))
+        .arg("--jdt")
+        .arg(dir.join("jdt.bin"))
+        .arg("--format")
+        .arg("json")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn inspects_infection() {
+    let program = common::protect("test/test.c", "inspect", &["-O0"], &["--seed", "1"]);
+
+    program.assert_same_behaviour(&["1", "2"]);
+
+    let output = inspect(program.original.parent().unwrap());
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let entries = report["entries"].as_array().unwrap();
+
+    assert!(!entries.is_empty());
+    assert!(report["problems"].as_array().unwrap().is_empty());
+    assert_eq!(report["binary_hash"].as_str().unwrap().len(), 64);
+
+    for entry in entries {
+        let address = entry["address"].as_u64().unwrap();
+        assert_eq!(
+            address,
+            report["base"].as_u64().unwrap() + entry["offset"].as_u64().unwrap()
+        );
+
+        // Decoys are only placed at 0xCC bytes, so they can't be told apart by the byte at them.
+        assert!(entry["int3"].as_bool().unwrap(), "{}", entry);
+        assert!(entry["length"].as_u64().unwrap() >= 1);
+        assert!(entry["next"].as_u64().unwrap() > address);
+    }
+}
+
+#[test]
+fn reports_patched_nanomite() {
+    let program = common::protect("test/test.c", "inspect_patched", &["-O0"], &[]);
+
+    let dir = program.original.parent().unwrap();
+    let binary_file = fs::read(dir.join("nanomite.bin")).unwrap();
+    let jdt_file = fs::read(dir.join("jdt.bin")).unwrap();
+    let (header, binary, jdt) = container::unwrap_pair(&binary_file, &jdt_file).unwrap();
+    let jdt_header = container::unwrap(&jdt_file, Content::JumpDataTable)
+        .unwrap()
+        .0;
+    let cipher = header.cipher.encryptor().unwrap();
+
+    let jdt = snap::raw::Decoder::new().decompress_vec(jdt).unwrap();
+    let mut jdt: JumpDataTable = bincode::deserialize(&jdt).unwrap();
+    let binary = payload::decrypt(cipher.as_ref(), &jdt.salt, &header, binary).unwrap();
+    let mut binary = snap::raw::Decoder::new().decompress_vec(&binary).unwrap();
+
+    // The code each entry key is bound to.
+    let code_hash = |binary: &[u8], key: u64| {
+        let region = jdt.region(key).unwrap();
+        region
+            .window_hash(&binary[region.file_offset as usize..], key)
+            .unwrap()
+    };
+
+    let master = jdt.master_key(&binary);
+    let entries: Vec<_> = jdt
+        .table
+        .keys()
+        .map(|&key| {
+            let hash = code_hash(&binary, key);
+            (
+                key,
+                jdt.get_jump_data(key, &hash, &master, cipher.as_ref())
+                    .unwrap(),
+            )
+        })
+        .collect();
+
+    // Replace the int3 of a nanomite with a nop, as an infector that forgot to place it would.
+    let patched = entries
+        .iter()
+        .find(|x| x.1.jump_type() != JumpType::Passthrough)
+        .unwrap()
+        .0;
+    let region = jdt.region(patched).unwrap().clone();
+    let index = (region.file_offset + patched - region.start) as usize;
+    assert_eq!(binary[index], 0xCC);
+    binary[index] = 0x90;
+
+    // Encrypt the infection again, so the entries and the binary are all authentic.
+    let master = jdt.master_key(&binary);
+    let table = entries
+        .iter()
+        .map(|(key, jump_data)| {
+            let hash = code_hash(&binary, *key);
+            let entry_key = master.entry_key(*key, &hash);
+            (
+                *key,
+                jump_data.encrypt(cipher.as_ref(), &entry_key, [1; 12], *key),
+            )
+        })
+        .collect();
+    jdt.table = table;
+
+    let compressed = snap::raw::Encoder::new().compress_vec(&binary).unwrap();
+    let binary = payload::encrypt(cipher.as_ref(), &jdt.salt, &header, &compressed, [2; 12]);
+    let jdt = bincode::serialize(&jdt).unwrap();
+    let jdt = snap::raw::Encoder::new().compress_vec(&jdt).unwrap();
+
+    let patched_dir = dir.join("patched");
+    fs::create_dir_all(&patched_dir).unwrap();
+    fs::write(
+        patched_dir.join("nanomite.bin"),
+        container::wrap(&header, &binary),
+    )
+    .unwrap();
+    fs::write(
+        patched_dir.join("jdt.bin"),
+        container::wrap(&jdt_header, &jdt),
+    )
+    .unwrap();
+
+    let output = inspect(&patched_dir);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success());
+    assert!(stderr.contains("1 entries failed the checks"), "{}", stderr);
+
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let problems = report["problems"].as_array().unwrap();
+
+    assert_eq!(problems.len(), 1);
+    assert_eq!(problems[0]["offset"].as_u64().unwrap(), patched);
+    assert!(problems[0]["message"].as_str().unwrap().contains("no int3"));
+}
@@ -0,0 +1,27 @@
+//! Entry keys are bound to the code around their nanomite, as it is in memory when the nanomite is
+//! hit.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::process::Command;
+
+#[test]
+fn rejects_patched_code() {
+    let program = common::protect("test/selfpatch.c", "rejects_patched_code", &["-O0"], &[]);
+
+    program.assert_same_behaviour(&[]);
+
+    // The branch is emulated before the patch, and fails authentication after it.
+    let output = Command::new(&program.protected)
+        .arg("patch")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert_eq!(stdout, "1\n");
+    assert!(stderr.contains("failed authentication"), "{}", stderr);
+}
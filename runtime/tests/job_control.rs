@@ -0,0 +1,141 @@
+//! Every task of the protected program stays stopped until SIGCONT, not just the leader.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::fs::{self, File};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::unistd::Pid;
+
+/// Returns what the program's thread and child process wrote so far.
+fn ticks(path: &Path) -> (usize, usize) {
+    let output = fs::read(path).unwrap();
+    let count = |c| output.iter().filter(|x| **x == c).count();
+
+    (count(b't'), count(b'c'))
+}
+
+/// Returns the protected process, the runtime's only child.
+fn tracee(runtime: Pid) -> Pid {
+    let children = fs::read_to_string(format!("/proc/{0}/task/{0}/children", runtime)).unwrap();
+    Pid::from_raw(children.trim().parse().unwrap())
+}
+
+/// Returns the state of a process, like `ps` shows it.
+fn state(pid: Pid) -> char {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+    let state = stat[stat.rfind(')').unwrap() + 1..].trim_start();
+    state.chars().next().unwrap()
+}
+
+#[test]
+fn stops_every_task() {
+    let program = common::protect(
+        "test/jobcontrol.c",
+        "stops_every_task",
+        &["-O0", "-pthread"],
+        &[],
+    );
+
+    let output = program.protected.with_file_name("ticks");
+    let child_pid = program.protected.with_file_name("child_pid");
+    let mut protected = Command::new(&program.protected)
+        .stdout(File::create(&output).unwrap())
+        .stderr(File::create(&child_pid).unwrap())
+        .process_group(0)
+        .spawn()
+        .unwrap();
+    let group = Pid::from_raw(protected.id() as i32);
+
+    thread::sleep(Duration::from_millis(300));
+    let running = ticks(&output);
+    assert!(running.0 > 0 && running.1 > 0, "{:?}", running);
+
+    // A child process stops on its own, while the rest of the program keeps running.
+    let child: i32 = fs::read_to_string(&child_pid)
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    kill(Pid::from_raw(child), Signal::SIGTSTP).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let stopped = ticks(&output);
+    thread::sleep(Duration::from_millis(300));
+    let (threads, children) = ticks(&output);
+    assert!(threads > stopped.0, "{:?}", stopped);
+    assert_eq!(children, stopped.1);
+
+    kill(Pid::from_raw(child), Signal::SIGCONT).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert!(ticks(&output).1 > stopped.1);
+
+    // The runtime stops along with the tracees, once they have.
+    killpg(group, Signal::SIGTSTP).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let stopped = ticks(&output);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(ticks(&output), stopped);
+    assert_eq!(state(group), 'T');
+
+    killpg(group, Signal::SIGCONT).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let continued = ticks(&output);
+
+    killpg(group, Signal::SIGKILL).unwrap();
+    protected.wait().unwrap();
+
+    assert!(
+        continued.0 > stopped.0 && continued.1 > stopped.1,
+        "{:?} {:?}",
+        stopped,
+        continued
+    );
+}
+
+#[test]
+fn continues_stopped_tracee() {
+    let program = common::protect(
+        "test/jobcontrol.c",
+        "continues_stopped_tracee",
+        &["-O0", "-pthread"],
+        &[],
+    );
+
+    let output = program.protected.with_file_name("ticks");
+    let mut protected = Command::new(&program.protected)
+        .stdout(File::create(&output).unwrap())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()
+        .unwrap();
+    let runtime = Pid::from_raw(protected.id() as i32);
+
+    thread::sleep(Duration::from_millis(300));
+    let tracee = tracee(runtime);
+
+    // Stopping and continuing the protected process alone leaves the runtime running, so it can
+    // let the SIGCONT through, and the thread carries on past its next nanomite.
+    kill(tracee, Signal::SIGSTOP).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let stopped = ticks(&output);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(ticks(&output).0, stopped.0);
+    let runtime_state = state(runtime);
+
+    kill(tracee, Signal::SIGCONT).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let continued = ticks(&output);
+
+    killpg(runtime, Signal::SIGKILL).unwrap();
+    protected.wait().unwrap();
+
+    assert_ne!(runtime_state, 'T');
+    assert!(continued.0 > stopped.0, "{:?} {:?}", stopped, continued);
+}
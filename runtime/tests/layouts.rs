@@ -0,0 +1,40 @@
+//! Nanomites must be found no matter where, and how, the image is loaded.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+const ARGS: [[&str; 2]; 4] = [["1", "2"], ["2", "1"], ["7", "7"], ["204", "0"]];
+
+fn assert_layout(name: &str, cflags: &[&str]) {
+    let program = common::protect("test/test.c", name, cflags, &[]);
+
+    for args in ARGS.iter() {
+        program.assert_same_behaviour(args);
+    }
+}
+
+#[test]
+fn pie() {
+    assert_layout("pie", &["-O0", "-fPIE", "-pie"]);
+}
+
+#[test]
+fn non_pie() {
+    assert_layout("non_pie", &["-O0", "-fno-PIE", "-no-pie"]);
+}
+
+#[test]
+fn separate_code() {
+    assert_layout("separate_code", &["-O0", "-pie", "-Wl,-z,separate-code"]);
+}
+
+#[test]
+fn static_binary() {
+    assert_layout("static", &["-O0", "-static"]);
+}
+
+#[test]
+fn static_pie() {
+    assert_layout("static_pie", &["-O0", "-static-pie"]);
+}
@@ -0,0 +1,39 @@
+//! Functions marked with `rekk.h` are the only ones protected.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::process::Command;
+
+#[test]
+fn marked_functions() {
+    let program = common::protect(
+        "test/markers.c",
+        "marked_functions",
+        &["-O2"],
+        &["--branches", "jcc,jmp,call,ret", "--strip-markers"],
+    );
+
+    for args in [&["ABCDEFGH"][..], &["ABCDEFGI"], &["abcdefgh"], &[]].iter() {
+        program.assert_same_behaviour(args);
+    }
+
+    let dry_run = Command::new(common::infector())
+        .args(["infect", "--dry-run"])
+        .arg(&program.original)
+        .args(["--branches", "jcc,jmp,call,ret"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&dry_run.stdout);
+
+    assert!(stdout.contains("found 1 protection markers"), "{}", stdout);
+
+    let per_function = stdout.split("nanomites per function:\n").nth(1).unwrap();
+    let functions: Vec<&str> = per_function
+        .lines()
+        .take_while(|x| x.starts_with("  "))
+        .map(|x| x.split_whitespace().nth(1).unwrap())
+        .collect();
+    assert_eq!(functions, ["check_license"]);
+}
@@ -0,0 +1,78 @@
+//! A recorded profile keeps nanomites out of the hot branches.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Runs a protected program, recording a profile to `path`.
+fn record(protected: &Path, path: &Path, args: &[&str]) -> String {
+    fs::remove_file(path).ok();
+
+    let status = Command::new(protected)
+        .args(args)
+        .env("REKK_PROFILE", path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    fs::read_to_string(path).unwrap()
+}
+
+/// Returns the largest number of hits in a profile.
+fn max_hits(profile: &str) -> u64 {
+    profile
+        .lines()
+        .map(|x| x.split_whitespace().nth(1).unwrap().parse().unwrap())
+        .max()
+        .unwrap_or(0)
+}
+
+#[test]
+fn skips_hot_branches() {
+    let stub = common::profiling_stub();
+    let program =
+        common::protect_with_stub("test/bench.c", "profiled", &["-O0"], &["-d", "0"], &stub);
+
+    let path = program.protected.with_file_name("profile.txt");
+    let profile = record(&program.protected, &path, &["1000"]);
+    assert!(max_hits(&profile) >= 1000, "{}", profile);
+
+    let path = path.to_str().unwrap();
+    let program = common::protect_with_stub(
+        "test/bench.c",
+        "profile_guided",
+        &["-O0"],
+        &["-d", "0", "--profile", path, "--max-hits", "100"],
+        &stub,
+    );
+
+    for args in [["1000"], ["7"]].iter() {
+        program.assert_same_behaviour(args);
+    }
+
+    let guided_path = program.protected.with_file_name("profile.txt");
+    let guided = record(&program.protected, &guided_path, &["1000"]);
+    assert!(max_hits(&guided) <= 100, "{}", guided);
+    assert!(!guided.is_empty());
+}
+
+#[test]
+fn release_stub_ignores_profile() {
+    let program = common::protect("test/bench.c", "unprofiled", &["-O0"], &["-d", "0"]);
+
+    let path = program.protected.with_file_name("profile.txt");
+    fs::remove_file(&path).ok();
+
+    let status = Command::new(&program.protected)
+        .arg("10")
+        .env("REKK_PROFILE", &path)
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    assert!(!path.exists());
+}
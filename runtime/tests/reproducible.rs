@@ -0,0 +1,47 @@
+//! Infecting with the same seed writes identical files.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Infects `binary` into `out_dir`, seeding the infector from `REKK_SEED`.
+fn infect(binary: &Path, out_dir: &Path, seed: &str) {
+    let status = Command::new(common::infector())
+        .args(["-q", "infect"])
+        .arg(binary)
+        .args(["--branches", "jcc,jmp,call,ret"])
+        .arg("--out-dir")
+        .arg(out_dir)
+        .env("REKK_SEED", seed)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn same_seed_same_output() {
+    let args = ["--branches", "jcc,jmp,call,ret", "--seed", "42"];
+    let program = common::protect("test/test.c", "reproducible", &["-O0"], &args);
+
+    program.assert_same_behaviour(&["1", "2"]);
+
+    let work = program.original.parent().unwrap();
+    let read = |dir: &Path| {
+        (
+            fs::read(dir.join("jdt.bin")).unwrap(),
+            fs::read(dir.join("nanomite.bin")).unwrap(),
+        )
+    };
+
+    let again = work.join("again");
+    infect(&program.original, &again, "42");
+    assert!(read(work) == read(&again), "same seed, different output");
+
+    let other = work.join("other");
+    infect(&program.original, &other, "43");
+    assert!(read(work) != read(&other), "different seeds, same output");
+}
@@ -0,0 +1,46 @@
+//! Signals sent to the runtime reach the protected program's handlers.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, Stdio};
+
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+#[test]
+fn forwards_signals() {
+    let program = common::protect("test/signals.c", "forwards_signals", &["-O0"], &[]);
+
+    for signal in [
+        Signal::SIGTERM,
+        Signal::SIGHUP,
+        Signal::SIGUSR1,
+        Signal::SIGINT,
+    ]
+    .iter()
+    {
+        let mut protected = Command::new(&program.protected)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut stdout = BufReader::new(protected.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        assert_eq!(line, "ready\n");
+
+        // Sent to the runtime alone, not its process group.
+        kill(Pid::from_raw(protected.id() as i32), *signal).unwrap();
+
+        let mut rest = String::new();
+        stdout.read_to_string(&mut rest).unwrap();
+        let status = protected.wait().unwrap();
+
+        assert_eq!(rest, format!("handled {:02}\n", *signal as i32));
+        assert_eq!(status.code(), Some(*signal as i32), "{:?}", status.signal());
+    }
+}
@@ -0,0 +1,29 @@
+//! The exit status of the protected program is passed on, even when its threads are killed while
+//! stopped at a nanomite.
+
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::process::Command;
+
+#[test]
+fn exit_group_mid_nanomite() {
+    let program = common::protect(
+        "test/exit_group.c",
+        "exit_group_mid_nanomite",
+        &["-O0", "-pthread"],
+        &[],
+    );
+
+    for _ in 0..20 {
+        let output = Command::new(&program.protected).arg("3").output().unwrap();
+
+        assert_eq!(
+            output.status.code(),
+            Some(3),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+}
@@ -0,0 +1,80 @@
+//! Infections written by the first infector, without a header, can be upgraded and run.
+
+#![cfg(all(target_os = "linux", target_arch = "x86_64"))]
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn upgrades_headerless_infection() {
+    // `test/baseline` holds the files the first infector wrote for `test/test.c`, built with
+    // `cc -O0`. The table has two fake entries, at instructions with a 0xCC byte.
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test/baseline");
+    let work = Path::new(env!("CARGO_TARGET_TMPDIR")).join("upgrade_headerless");
+    let protected = work.join("protected");
+
+    let upgraded = Command::new(common::infector())
+        .arg("upgrade")
+        .arg("--binary")
+        .arg(fixture.join("nanomite.bin"))
+        .arg("--jdt")
+        .arg(fixture.join("jdt.bin"))
+        .arg("--out-dir")
+        .arg(&work)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&upgraded.stdout);
+
+    assert!(upgraded.status.success(), "{}", stdout);
+    assert!(
+        stdout.contains("upgraded 10 entries from the headerless format"),
+        "{}",
+        stdout
+    );
+
+    let packed = Command::new(common::infector())
+        .arg("-q")
+        .arg("pack")
+        .arg("--stub")
+        .arg(env!("CARGO_BIN_EXE_runtime"))
+        .arg("--binary")
+        .arg(work.join("nanomite.bin"))
+        .arg("--jdt")
+        .arg(work.join("jdt.bin"))
+        .arg("--output")
+        .arg(&protected)
+        .status()
+        .unwrap();
+    assert!(packed.success());
+    fs::set_permissions(&protected, fs::Permissions::from_mode(0o755)).unwrap();
+
+    for (args, expected) in [
+        (["1", "2"], "cmp:         -1\ncmp to 0xCC: -1\n"),
+        (["204", "3"], "cmp:         1\ncmp to 0xCC: 0\n"),
+        (["300", "300"], "cmp:         0\ncmp to 0xCC: 1\n"),
+    ] {
+        let output = Command::new(&protected).args(args).output().unwrap();
+
+        assert!(output.status.success(), "{:?}", args);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    }
+
+    // Every entry left is a nanomite, and is bound to the code around it.
+    let inspected = Command::new(common::infector())
+        .arg("inspect")
+        .arg("--binary")
+        .arg(work.join("nanomite.bin"))
+        .arg("--jdt")
+        .arg(work.join("jdt.bin"))
+        .output()
+        .unwrap();
+    assert!(
+        inspected.status.success(),
+        "{}",
+        String::from_utf8_lossy(&inspected.stdout)
+    );
+}
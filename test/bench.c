@@ -0,0 +1,35 @@
+//
+// Hot loop workload used by bench.sh to measure the cost of a nanomite.
+//
+
+#include <stdlib.h>
+#include <stdio.h>
+
+int compare(int a, int b) {
+    if (a == b) {
+        return 0;
+    }
+
+    if (a > b) {
+        return 1;
+    } else {
+        return -1;
+    }
+}
+
+int main(int argc, char **argv) {
+    if (argc < 2) {
+        printf("usage: %s [iterations]\n", argv[0]);
+        return 1;
+    }
+
+    int iterations = atoi(argv[1]);
+    long sum = 0;
+
+    for (int i = 0; i < iterations; i++) {
+        sum += compare(i % 7, 3);
+    }
+
+    printf("sum: %ld\n", sum);
+    return 0;
+}
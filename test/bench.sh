@@ -0,0 +1,45 @@
+#!/bin/sh
+#
+# Measures the overhead of nanomites on the hot loop in bench.c. Results are
+# written to bench_output.txt in the repository root.
+#
+# usage: test/bench.sh [iterations]
+
+set -e
+
+ITERATIONS=${1:-100000}
+ROOT=$(cd "$(dirname "$0")/.." && pwd)
+WORK=$(mktemp -d)
+OUTPUT="$ROOT/bench_output.txt"
+
+trap 'rm -rf "$WORK"' EXIT
+
+cd "$ROOT"
+cargo build --quiet --release --bin infector --bin runtime
+
+${CC:-cc} -O0 -o "$WORK/bench" test/bench.c
+
+target/release/infector -q infect "$WORK/bench" -o "$WORK"
+target/release/infector -q pack --stub target/release/runtime \
+    --binary "$WORK/nanomite.bin" --jdt "$WORK/jdt.bin" -o "$WORK/bench_protected"
+
+# Prints the wall clock time of a command in seconds.
+time_it() {
+    start=$(date +%s.%N)
+    "$@" > /dev/null
+    end=$(date +%s.%N)
+    awk "BEGIN { printf \"%.3f\", $end - $start }"
+}
+
+unprotected=$(time_it "$WORK/bench" "$ITERATIONS")
+protected=$(time_it "$WORK/bench_protected" "$ITERATIONS")
+
+# Every iteration executes the loop condition plus the two branches in compare.
+nanomites=$((ITERATIONS * 3))
+
+{
+    echo "iterations:      $ITERATIONS"
+    echo "unprotected (s): $unprotected"
+    echo "protected (s):   $protected"
+    echo "nanomites/s:     $(awk "BEGIN { printf \"%d\", $nanomites / $protected }")"
+} | tee "$OUTPUT"
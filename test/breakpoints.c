@@ -0,0 +1,105 @@
+//
+// Breakpoints of the program's own, which must reach its SIGTRAP handler rather than being
+// swallowed by the runtime. The handler, and code run with SIGTRAP blocked, hit nanomites of
+// their own, which must leave both the handler and the mask as they were.
+//
+
+#include <signal.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#if defined(__has_builtin)
+#if __has_builtin(__builtin_debugtrap)
+#define DEBUGTRAP() __builtin_debugtrap()
+#endif
+#endif
+
+// gcc doesn't have __builtin_debugtrap.
+#ifndef DEBUGTRAP
+#define DEBUGTRAP() __asm__ volatile("int3")
+#endif
+
+// A breakpoint that starts a function, right after the padding of the code before it.
+void trap_after_padding(void);
+
+__asm__(".text\n"
+        "    .p2align 4\n"
+        "    ret\n"
+        "    int3\n"
+        "    int3\n"
+        "    int3\n"
+        "    .type trap_after_padding, @function\n"
+        "trap_after_padding:\n"
+        "    int3\n"
+        "    ret\n"
+        "    .size trap_after_padding, . - trap_after_padding\n");
+
+static volatile sig_atomic_t traps = 0;
+
+static void on_trap(int signal) {
+    (void)signal;
+    traps++;
+}
+
+static int collatz(int n) {
+    int steps = 0;
+
+    while (n != 1) {
+        n = n % 2 == 0 ? n / 2 : 3 * n + 1;
+        steps++;
+    }
+
+    return steps;
+}
+
+// Runs branches with SIGTRAP blocked, and checks they cost neither the handler nor the mask.
+static int blocked(void) {
+    sigset_t trap, mask;
+    sigemptyset(&trap);
+    sigaddset(&trap, SIGTRAP);
+    sigprocmask(SIG_BLOCK, &trap, NULL);
+
+    int steps = collatz(27);
+
+    struct sigaction action;
+    sigaction(SIGTRAP, NULL, &action);
+    sigprocmask(SIG_UNBLOCK, &trap, &mask);
+
+    int kept = action.sa_handler == on_trap && sigismember(&mask, SIGTRAP);
+    printf("steps: %d, kept: %d\n", steps, kept);
+
+    return kept;
+}
+
+int main(int argc, char **argv) {
+    if (argc < 2) {
+        printf("usage: %s [traps] [unhandled|blocked]\n", argv[0]);
+        return 1;
+    }
+
+    int count = atoi(argv[1]);
+
+    if (argc < 3 || strcmp(argv[2], "unhandled") != 0) {
+        signal(SIGTRAP, on_trap);
+    }
+
+    if (argc >= 3 && strcmp(argv[2], "blocked") == 0 && !blocked()) {
+        return 2;
+    }
+
+    // Alternate between int3 and SIGTRAPs sent by the program itself.
+    for (int i = 0; i < count; i++) {
+        if (i % 3 == 0) {
+            DEBUGTRAP();
+        } else if (i % 3 == 1) {
+            raise(SIGTRAP);
+        } else {
+            trap_after_padding();
+        }
+    }
+
+    printf("traps: %d\n", traps);
+
+    return traps == count ? 0 : 1;
+}
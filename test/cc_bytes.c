@@ -0,0 +1,60 @@
+//
+// Immediates that contain 0xCC, which look like an int3, next to nanomites and to a breakpoint
+// of the program's own.
+//
+
+#include <signal.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static volatile sig_atomic_t traps = 0;
+
+static void on_trap(int signal) {
+    (void)signal;
+    traps++;
+}
+
+// Returns 1 if the low byte of `value` is 0xCC, and 0xCC otherwise.
+static int classify(int value) {
+    int result;
+
+    __asm__ volatile("cmp $0xcc, %b1\n\t"
+                     "je 1f\n\t"
+                     "mov $0xcc, %0\n\t"
+                     "jmp 2f\n"
+                     "1:\n\t"
+                     "mov $1, %0\n"
+                     "2:"
+                     : "=r"(result)
+                     : "q"(value)
+                     : "cc");
+
+    return result;
+}
+
+int main(int argc, char **argv) {
+    if (argc < 2) {
+        printf("usage: %s [count]\n", argv[0]);
+        return 1;
+    }
+
+    int count = atoi(argv[1]);
+    int sum = 0;
+
+    signal(SIGTRAP, on_trap);
+
+    for (int i = 0; i < count; i++) {
+        sum += classify(i * 0x33);
+    }
+
+    // The int3 follows the 0xCC immediate of the mov.
+    __asm__ volatile("mov $0xcc, %%al\n\t"
+                     "int3"
+                     :
+                     :
+                     : "al");
+
+    printf("sum: %d, traps: %d\n", sum, traps);
+
+    return traps == 1 ? 0 : 1;
+}
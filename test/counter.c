@@ -0,0 +1,85 @@
+#include <stdio.h>
+#include <stdlib.h>
+
+/* loop, loope, loopne and jcxz, which use rcx or ecx as their counter. */
+
+static long loop_rcx(long n) {
+    long count = 0;
+    if (n == 0)
+        return 0;
+    __asm__ volatile("1: inc %0\n\t"
+                     "loop 1b"
+                     : "+r"(count), "+c"(n));
+    return count;
+}
+
+static long loop_ecx(long n) {
+    long count = 0;
+    if ((unsigned int)n == 0)
+        return 0;
+    __asm__ volatile("1: inc %0\n\t"
+                     "addr32 loop 1b"
+                     : "+r"(count), "+c"(n));
+    return count;
+}
+
+static long loope_rcx(long n, long stop) {
+    long count = 0;
+    if (n == 0)
+        return 0;
+    __asm__ volatile("1: inc %0\n\t"
+                     "cmp %0, %2\n\t"
+                     "loopne 1b"
+                     : "+r"(count), "+c"(n)
+                     : "r"(stop)
+                     : "cc");
+    return count;
+}
+
+static long loopz_rcx(long n) {
+    long count = 0;
+    if (n == 0)
+        return 0;
+    __asm__ volatile("1: inc %0\n\t"
+                     "xor %%eax, %%eax\n\t"
+                     "loope 1b"
+                     : "+r"(count), "+c"(n)
+                     :
+                     : "rax", "cc");
+    return count;
+}
+
+static int jrcxz(long n) {
+    int zero = 0;
+    __asm__ volatile("jrcxz 1f\n\t"
+                     "jmp 2f\n\t"
+                     "1: movl $1, %0\n\t"
+                     "2:"
+                     : "+r"(zero)
+                     : "c"(n));
+    return zero;
+}
+
+static int jecxz(long n) {
+    int zero = 0;
+    __asm__ volatile("jecxz 1f\n\t"
+                     "jmp 2f\n\t"
+                     "1: movl $1, %0\n\t"
+                     "2:"
+                     : "+r"(zero)
+                     : "c"(n));
+    return zero;
+}
+
+int main(int argc, char **argv) {
+    long n = argc > 1 ? strtol(argv[1], NULL, 0) : 5;
+
+    printf("loop rcx: %ld\n", loop_rcx(n));
+    printf("loop ecx: %ld\n", loop_ecx(n | (1L << 32)));
+    printf("loopne rcx: %ld\n", loope_rcx(n, 3));
+    printf("loope rcx: %ld\n", loopz_rcx(n));
+    printf("jrcxz: %d %d\n", jrcxz(n), jrcxz(n << 32));
+    printf("jecxz: %d %d\n", jecxz(n), jecxz(n << 32));
+
+    return 0;
+}
@@ -0,0 +1,39 @@
+//
+// Threads that keep hitting nanomites while the main thread exits the whole process. Some of them
+// are killed while they're stopped at a nanomite.
+//
+
+#include <pthread.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+#define THREADS 8
+
+static volatile unsigned long counter;
+
+static void *spin(void *arg) {
+    (void)arg;
+
+    for (;;) {
+        if (counter % 3 == 0) {
+            counter += 2;
+        } else {
+            counter++;
+        }
+    }
+
+    return NULL;
+}
+
+int main(int argc, char **argv) {
+    pthread_t handle;
+
+    for (int i = 0; i < THREADS; i++) {
+        if (pthread_create(&handle, NULL, spin, NULL) != 0) {
+            return 1;
+        }
+    }
+
+    usleep(20000);
+    exit(argc > 1 ? atoi(argv[1]) : 0);
+}
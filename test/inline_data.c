@@ -0,0 +1,25 @@
+#include <stdio.h>
+#include <stdlib.h>
+
+/* Data embedded in .text between two functions, containing what decodes as jcc's. */
+__asm__(".text\n"
+        ".globl table\n"
+        "table:\n"
+        ".byte 0x0f, 0x84, 0x01, 0x02, 0x03, 0x04, 0x74, 0x05, 0x75, 0x10, 0x0f, 0x85, 0xaa, 0xbb, 0xcc, 0xdd\n"
+        ".byte 0x7e, 0x02, 0x0f, 0x8f, 0x00, 0x00, 0x00, 0x00\n");
+extern const unsigned char table[];
+
+int classify(int x) {
+    if (x < 0) return -1;
+    if (x == 0) return 0;
+    if (x > 100) return 2;
+    return 1;
+}
+
+int main(int argc, char **argv) {
+    int x = argc > 1 ? atoi(argv[1]) : 0;
+    unsigned sum = 0;
+    for (int i = 0; i < 24; i++) sum = sum * 31 + table[i];
+    printf("%d %u\n", classify(x), sum);
+    return 0;
+}
@@ -0,0 +1,42 @@
+//
+// A thread and a child process that keep writing to stdout, so job control can be checked on
+// tasks other than the thread group leader. The child process prints its pid to stderr.
+//
+
+#include <pthread.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+static void tick(char c) {
+    for (;;) {
+        if (write(STDOUT_FILENO, &c, 1) != 1) {
+            exit(1);
+        }
+
+        usleep(5000);
+    }
+}
+
+static void *thread(void *arg) {
+    (void)arg;
+    tick('t');
+    return NULL;
+}
+
+int main(void) {
+    pthread_t handle;
+
+    if (fork() == 0) {
+        fprintf(stderr, "%d\n", getpid());
+        tick('c');
+    }
+
+    if (pthread_create(&handle, NULL, thread, NULL) != 0) {
+        return 1;
+    }
+
+    for (;;) {
+        pause();
+    }
+}
@@ -0,0 +1,38 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "../marker/include/rekk.h"
+
+/* Only check_license is marked, so it's the only function that gets nanomites. */
+
+REKK_PROTECTED int check_license(const char *key) {
+    if (strlen(key) != 8)
+        return 0;
+
+    int sum = 0;
+    for (int i = 0; i < 8; i++) {
+        if (key[i] < 'A' || key[i] > 'Z')
+            return 0;
+        sum += key[i];
+    }
+
+    return sum % 7 == 3;
+}
+
+REKK_PROTECT(check_license);
+
+int decode(int x) {
+    if (x > 10)
+        return x - 10;
+    return x + 10;
+}
+
+int main(int argc, char **argv) {
+    const char *key = argc > 1 ? argv[1] : "";
+
+    printf("license: %d\n", check_license(key));
+    printf("decode: %d\n", decode((int)strlen(key)));
+
+    return 0;
+}
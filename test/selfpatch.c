@@ -0,0 +1,60 @@
+//
+// Patches its own code in memory, after the runtime has checked the nanomite'd binary. The
+// nanomites around the patch must stop decrypting.
+//
+
+#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/mman.h>
+#include <unistd.h>
+
+#define SCAN_SIZE 64
+
+__attribute__((noinline)) static int is_positive(int x) {
+    if (x > 0) {
+        return 1;
+    }
+
+    return 0;
+}
+
+// Flips the byte after the first int3 of is_positive. Once it's protected, that's a junk byte of
+// the nanomite that replaced the branch, which is never executed, so the patch doesn't change what
+// the code does.
+static int patch(void) {
+    uint8_t *code = (uint8_t *)is_positive;
+    uintptr_t page = (uintptr_t)sysconf(_SC_PAGESIZE);
+    uintptr_t start = (uintptr_t)code & ~(page - 1);
+    uintptr_t end = ((uintptr_t)code + SCAN_SIZE + page) & ~(page - 1);
+
+    if (mprotect((void *)start, end - start, PROT_READ | PROT_WRITE | PROT_EXEC) != 0) {
+        perror("mprotect");
+        return 1;
+    }
+
+    for (int i = 0; i < SCAN_SIZE; i++) {
+        if (code[i] == 0xCC) {
+            code[i + 1] ^= 0xFF;
+            return 0;
+        }
+    }
+
+    // The original program has no nanomites to patch.
+    return 0;
+}
+
+int main(int argc, char **argv) {
+    printf("%d\n", is_positive(argc));
+    fflush(stdout);
+
+    if (argc > 1 && strcmp(argv[1], "patch") == 0) {
+        if (patch() != 0) {
+            return 2;
+        }
+
+        printf("%d\n", is_positive(argc));
+    }
+
+    return 0;
+}
@@ -0,0 +1,36 @@
+//
+// Exits with the number of the first signal it handles, after printing it. Prints "ready" once
+// its handlers are installed.
+//
+
+#include <signal.h>
+#include <stdio.h>
+#include <string.h>
+#include <unistd.h>
+
+static void handler(int signal) {
+    char message[] = "handled 00\n";
+
+    message[8] += signal / 10;
+    message[9] += signal % 10;
+
+    write(STDOUT_FILENO, message, strlen(message));
+    _exit(signal);
+}
+
+int main(void) {
+    int signals[] = {SIGTERM, SIGHUP, SIGUSR1, SIGINT};
+
+    for (unsigned i = 0; i < sizeof(signals) / sizeof(signals[0]); i++) {
+        if (signal(signals[i], handler) == SIG_ERR) {
+            return 1;
+        }
+    }
+
+    printf("ready\n");
+    fflush(stdout);
+
+    for (;;) {
+        pause();
+    }
+}